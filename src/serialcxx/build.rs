@@ -6,6 +6,7 @@ fn main() {
     let out_dir = std::path::Path::new(out_str.as_str());
 
     //Build cxx bridge normally
+    #[allow(clippy::let_unit_value)]
     let _build = cxx_build::bridge("src/lib.rs")
         .flag_if_supported("std=c++11")
         .compile("serialcxx");
@@ -0,0 +1,822 @@
+//! Reliable, in-order delivery over a noisy link, created with [crate::Serial::create_reliable_link].
+//!
+//! Messages are sent in HDLC-like frames checked with CRC-16, each starting with a frame type, a
+//! session id and a sequence number. The receiver only accepts the next message in sequence, and
+//! acknowledges every frame with the sequence number it expects next. The sender keeps up to a window
+//! of messages in flight, and sends all of them again when the oldest is not acknowledged in time
+//! (Go-Back-N).
+//!
+//! Each end numbers its messages within a session, picked at random when the link starts. The first
+//! message of a session is marked, so the receiver can tell a restarted sender from one it lost track
+//! of. When a message arrives from a session the receiver does not know, it asks for a reset, and the
+//! sender moves its unacknowledged messages into a new session. ACKs name the session they belong to,
+//! so a peer that restarted never mistakes them for its own.
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use parking_lot::Condvar;
+use serialport::{Error, SerialPort};
+
+use crate::ffi::{ArqConfig, ArqStats, HdlcFcs, SerialError};
+use crate::framing::{Deframer, DEFAULT_MAX_FRAME_LEN};
+use crate::hdlc::{self, HdlcDeframer};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{CVoidSend, SerialPortReader};
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+/// Frame carrying a message: type, session, sequence number, payload.
+const FRAME_DATA: u8 = 0x01;
+/// Frame acknowledging every message before a sequence number: type, session of the messages, next
+/// expected sequence number.
+const FRAME_ACK: u8 = 0x02;
+/// Frame carrying the first message of a session, laid out like [FRAME_DATA].
+const FRAME_DATA_START: u8 = 0x03;
+/// Frame asking the sender of a session to start a new one: type, session, unused sequence number.
+const FRAME_RESET: u8 = 0x04;
+
+/// Bytes before the payload: frame type, session id, sequence number.
+const HEADER_LEN: usize = 6;
+/// Sessions remembered after they are replaced or refused, so late frames from them are not mistaken
+/// for a new session.
+const RETIRED_SESSIONS: usize = 8;
+
+/// Longest retransmit timeout accepted, in seconds. Keeps retransmit deadlines representable.
+const MAX_RETRANSMIT_TIMEOUT: f32 = 3600.0;
+
+/// Largest window that keeps new and old sequence numbers apart.
+const MAX_WINDOW: u8 = 127;
+
+/// The largest message [ReliableLink::send_reliable] accepts.
+pub const MAX_MESSAGE_LEN: usize = DEFAULT_MAX_FRAME_LEN;
+
+/// Callback for a delivered message. The payload is only valid until the callback returns.
+pub type DeliveryCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize);
+
+struct Outgoing {
+    seq: u8,
+    /// Set on the first message of a session.
+    start: bool,
+    data: Vec<u8>,
+}
+
+/// Carries the encoded frames of a link to the other end.
+pub trait Transport: Send + Sync {
+    /// Writes one encoded frame.
+    fn write(&self, frame: &[u8]) -> SerialError;
+}
+
+impl Transport for Mutex<Box<dyn SerialPort>> {
+    fn write(&self, frame: &[u8]) -> SerialError {
+        let (error, _) = write_counted(&mut **self.lock(), frame);
+        error
+    }
+}
+
+struct State {
+    /// Session of the messages we send.
+    session: u32,
+    /// Set until the first message of the session is sent.
+    session_fresh: bool,
+    /// Sequence number of the next message sent.
+    next_seq: u8,
+    /// Messages sent but not acknowledged yet, oldest first.
+    unacked: VecDeque<Outgoing>,
+    /// When the oldest message is sent again, if it is still not acknowledged.
+    retransmit_at: Option<Instant>,
+    /// Number of times the oldest message has been sent again.
+    retries: u32,
+    /// Session of the messages we receive, once the first one arrives.
+    peer_session: Option<u32>,
+    /// Peer sessions replaced or refused, newest last.
+    retired: VecDeque<u32>,
+    /// Sequence number of the next message to deliver.
+    expected: u8,
+    /// Set once a message runs out of retries. The link sends nothing after this.
+    failed: bool,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when messages are acknowledged, the link fails, or the link is stopped.
+    changed: Condvar,
+    delivery_callback: Mutex<Option<(CVoidSend, DeliveryCallback)>>,
+    transport: Arc<dyn Transport>,
+    /// Decodes received bytes. Locked for as long as a frame is handled, so messages are delivered
+    /// in order.
+    deframer: Mutex<HdlcDeframer>,
+    window: usize,
+    retransmit_timeout: Duration,
+    max_retries: u32,
+    sent: AtomicU64,
+    retransmits: AtomicU64,
+    delivered: AtomicU64,
+    duplicates: AtomicU64,
+    out_of_order: AtomicU64,
+    bad_frames: AtomicU64,
+    resets: AtomicU64,
+}
+
+impl Shared {
+    fn write_frame(&self, frame_type: u8, session: u32, seq: u8, payload: &[u8]) -> SerialError {
+        let mut frame = Vec::with_capacity(payload.len() + HEADER_LEN);
+        frame.push(frame_type);
+        frame.extend_from_slice(&session.to_be_bytes());
+        frame.push(seq);
+        frame.extend_from_slice(payload);
+
+        self.transport.write(&hdlc::encode(&frame, HdlcFcs::Crc16))
+    }
+
+    fn write_message(&self, session: u32, message: &Outgoing) -> SerialError {
+        let frame_type = if message.start {
+            FRAME_DATA_START
+        } else {
+            FRAME_DATA
+        };
+        self.write_frame(frame_type, session, message.seq, &message.data)
+    }
+
+    /// Decodes data received from the other end, handling every complete frame in it.
+    fn receive(&self, data: &[u8]) {
+        self.deframer.lock().push(data, &mut |frame| match frame {
+            Ok(frame) => self.handle_frame(frame),
+            Err(error) => {
+                self.bad_frames.fetch_add(1, Ordering::Relaxed);
+                log::debug!("Dropping frame: {:?}", error.repr);
+            }
+        });
+    }
+
+    fn handle_frame(&self, frame: &[u8]) {
+        let (frame_type, session, seq, payload) = match frame {
+            [frame_type, s0, s1, s2, s3, seq, payload @ ..] => (
+                *frame_type,
+                u32::from_be_bytes([*s0, *s1, *s2, *s3]),
+                *seq,
+                payload,
+            ),
+            _ => {
+                self.bad_frames.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        match frame_type {
+            FRAME_DATA => self.handle_data(session, false, seq, payload),
+            FRAME_DATA_START => self.handle_data(session, true, seq, payload),
+            FRAME_ACK => self.handle_ack(session, seq),
+            FRAME_RESET => self.handle_reset(session),
+            _ => {
+                self.bad_frames.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn handle_data(&self, session: u32, start: bool, seq: u8, payload: &[u8]) {
+        let (accepted, expected) = {
+            let mut state = self.state.lock();
+
+            if state.peer_session != Some(session) {
+                //Only the first message of a session we have not seen before starts it. Anything else
+                //is from a sender whose state we lost, or a late frame from an old session
+                if !start || seq != 0 || state.retired.contains(&session) {
+                    retire(&mut state.retired, session);
+                    drop(state);
+                    if self.write_frame(FRAME_RESET, session, 0, &[]) != SerialError::NoErr {
+                        log::warn!("Reliable link failed to write reset");
+                    }
+                    return;
+                }
+
+                log::debug!("Reliable link peer started session {:08X}", session);
+                if let Some(old) = state.peer_session.replace(session) {
+                    retire(&mut state.retired, old);
+                }
+                state.expected = 0;
+            }
+
+            let accepted = seq == state.expected;
+
+            if accepted {
+                state.expected = state.expected.wrapping_add(1);
+            } else if state.expected.wrapping_sub(seq) <= MAX_WINDOW {
+                //Sent again because our ACK was lost
+                self.duplicates.fetch_add(1, Ordering::Relaxed);
+            } else {
+                //A message before this one was lost, the sender will go back to it
+                self.out_of_order.fetch_add(1, Ordering::Relaxed);
+            }
+
+            (accepted, state.expected)
+        };
+
+        //Always ACK, so the sender learns where we are even if an earlier ACK was lost
+        if self.write_frame(FRAME_ACK, session, expected, &[]) != SerialError::NoErr {
+            log::warn!("Reliable link failed to write ACK");
+        }
+
+        //Only this thread delivers, so messages stay in order without holding the state lock
+        if accepted {
+            self.delivered.fetch_add(1, Ordering::Relaxed);
+
+            //Copied out, so the callback can replace itself without deadlocking
+            let callback = *self.delivery_callback.lock();
+            if let Some((user_data, callback)) = callback {
+                unsafe {
+                    //Safe only if callback does not store a reference to the data, which it does not own.
+                    callback(user_data.0, payload.as_ptr(), payload.len());
+                }
+            }
+        }
+    }
+
+    fn handle_ack(&self, session: u32, next: u8) {
+        let mut state = self.state.lock();
+        //ACKs for an old session, likely from before we restarted, say nothing about this one
+        if session != state.session {
+            return;
+        }
+        let base = match state.unacked.front() {
+            Some(oldest) => oldest.seq,
+            None => return,
+        };
+
+        //Stale or bogus ACKs acknowledge nothing we are waiting on
+        let acked = next.wrapping_sub(base) as usize;
+        if acked == 0 || acked > state.unacked.len() {
+            return;
+        }
+
+        state.unacked.drain(..acked);
+        state.retries = 0;
+        state.retransmit_at = if state.unacked.is_empty() {
+            None
+        } else {
+            Some(Instant::now() + self.retransmit_timeout)
+        };
+        self.changed.notify_all();
+    }
+
+    /// Moves the unacknowledged messages into a new session, since the receiver lost track of ours.
+    fn handle_reset(&self, session: u32) {
+        let mut state = self.state.lock();
+        //Late resets for a session we already replaced
+        if session != state.session || state.stopped {
+            return;
+        }
+
+        state.session = new_session_id();
+        log::debug!(
+            "Reliable link reset, moving to session {:08X}",
+            state.session
+        );
+        self.resets.fetch_add(1, Ordering::Relaxed);
+
+        for (idx, message) in state.unacked.iter_mut().enumerate() {
+            message.seq = idx as u8;
+            message.start = idx == 0;
+        }
+        state.next_seq = state.unacked.len() as u8;
+        state.session_fresh = state.unacked.is_empty();
+        state.retries = 0;
+        //Send them all now, rather than waiting for the timer
+        if !state.unacked.is_empty() && !state.failed {
+            state.retransmit_at = Some(Instant::now());
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Remembers a session that is no longer accepted, forgetting the oldest once there are too many.
+fn retire(retired: &mut VecDeque<u32>, session: u32) {
+    if retired.contains(&session) {
+        return;
+    }
+    if retired.len() == RETIRED_SESSIONS {
+        retired.pop_front();
+    }
+    retired.push_back(session);
+}
+
+/// Picks a session id, different on every call and in every process.
+fn new_session_id() -> u32 {
+    //RandomState is seeded from the OS, the time covers a platform where it is not
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish() as u32
+}
+
+/// A reliable link over a port, with its reader and retransmit threads.
+pub struct ReliableLink {
+    shared: Arc<Shared>,
+    /// Token used to kill the reader thread.
+    cts: CancellationTokenSource,
+}
+
+impl ReliableLink {
+    /// Creates a link on a port and starts its threads, or throws if config is invalid.
+    pub fn start(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+        config: ArqConfig,
+    ) -> serialport::Result<ReliableLink> {
+        let link = ReliableLink::new(write_handle, config)?;
+
+        let token = link.cts.token().clone();
+        let thread_shared = link.shared.clone();
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = reader.lock();
+
+        let thread_reader = reader.clone();
+        std::thread::spawn(move || {
+            log::debug!("Spawned reliable link reader");
+            read_loop(&thread_shared, &thread_reader, &token);
+            log::debug!("exiting reliable link reader thread")
+        });
+        //Thread detaches here
+
+        Ok(link)
+    }
+
+    /// Creates a link writing to transport and starts its retransmit thread, or throws if config is
+    /// invalid. Data from the other end is passed to [Shared::receive].
+    fn new(transport: Arc<dyn Transport>, config: ArqConfig) -> serialport::Result<ReliableLink> {
+        if config.window == 0 || config.window > MAX_WINDOW {
+            return Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Window must be between 1 and 127 messages.",
+            ));
+        }
+        //Written so NaN fails too
+        let timeout = config.retransmit_timeout;
+        if !(timeout > 0.0 && timeout <= MAX_RETRANSMIT_TIMEOUT) {
+            return Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Retransmit timeout must be positive and at most an hour.",
+            ));
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                session: new_session_id(),
+                session_fresh: true,
+                next_seq: 0,
+                unacked: VecDeque::new(),
+                retransmit_at: None,
+                retries: 0,
+                peer_session: None,
+                retired: VecDeque::new(),
+                expected: 0,
+                failed: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+            delivery_callback: Mutex::new(None),
+            transport,
+            //Leave room for the header
+            deframer: Mutex::new(HdlcDeframer::new(
+                HdlcFcs::Crc16,
+                MAX_MESSAGE_LEN + HEADER_LEN,
+            )),
+            window: config.window as usize,
+            retransmit_timeout: timeout_from_secs(config.retransmit_timeout),
+            max_retries: config.max_retries,
+            sent: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            out_of_order: AtomicU64::new(0),
+            bad_frames: AtomicU64::new(0),
+            resets: AtomicU64::new(0),
+        });
+
+        let thread_shared = shared.clone();
+        std::thread::spawn(move || {
+            retransmit_loop(&thread_shared);
+            log::debug!("exiting reliable link retransmit thread")
+        });
+        //Thread detaches here
+
+        Ok(ReliableLink {
+            shared,
+            cts: CancellationTokenSource::new(),
+        })
+    }
+
+    /// Queues a message for reliable delivery, waiting up to timeout seconds for room in the window.
+    pub fn send_reliable(&self, data: &[u8], timeout: f32) -> SerialError {
+        if data.len() > MAX_MESSAGE_LEN {
+            return SerialError::Other;
+        }
+
+        let deadline = Instant::now().checked_add(timeout_from_secs(timeout));
+        let mut state = self.shared.state.lock();
+
+        while !state.failed && !state.stopped && state.unacked.len() >= self.shared.window {
+            if wait(&self.shared.changed, &mut state, deadline) {
+                return SerialError::Timeout;
+            }
+        }
+        if state.failed || state.stopped {
+            return SerialError::Other;
+        }
+
+        let message = Outgoing {
+            seq: state.next_seq,
+            start: state.session_fresh,
+            data: data.to_vec(),
+        };
+        state.next_seq = state.next_seq.wrapping_add(1);
+        state.session_fresh = false;
+        if state.retransmit_at.is_none() {
+            state.retransmit_at = Some(Instant::now() + self.shared.retransmit_timeout);
+            self.shared.changed.notify_all();
+        }
+        self.shared.sent.fetch_add(1, Ordering::Relaxed);
+
+        //Written under the lock, so a reset cannot renumber the message before it is sent
+        //A failed write is recovered by the retransmit timer, like a lost frame
+        let error = self.shared.write_message(state.session, &message);
+        if error != SerialError::NoErr {
+            log::warn!(
+                "Reliable link failed to write message {}: {:?}",
+                message.seq,
+                error.repr
+            );
+        }
+        state.unacked.push_back(message);
+
+        SerialError::NoErr
+    }
+
+    /// Waits up to timeout seconds for every queued message to be acknowledged.
+    pub fn flush(&self, timeout: f32) -> SerialError {
+        let deadline = Instant::now().checked_add(timeout_from_secs(timeout));
+        let mut state = self.shared.state.lock();
+
+        while !state.failed && !state.stopped && !state.unacked.is_empty() {
+            if wait(&self.shared.changed, &mut state, deadline) {
+                return SerialError::Timeout;
+            }
+        }
+
+        if state.failed || !state.unacked.is_empty() {
+            SerialError::Other
+        } else {
+            SerialError::NoErr
+        }
+    }
+
+    /// Checks if a message ran out of retries.
+    pub fn has_failed(&self) -> bool {
+        self.shared.state.lock().failed
+    }
+
+    pub fn stats(&self) -> ArqStats {
+        let shared = &self.shared;
+        ArqStats {
+            sent: shared.sent.load(Ordering::Relaxed),
+            retransmits: shared.retransmits.load(Ordering::Relaxed),
+            delivered: shared.delivered.load(Ordering::Relaxed),
+            duplicates: shared.duplicates.load(Ordering::Relaxed),
+            out_of_order: shared.out_of_order.load(Ordering::Relaxed),
+            bad_frames: shared.bad_frames.load(Ordering::Relaxed),
+            resets: shared.resets.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets the callback that receives delivered messages, replacing any previous one.
+    pub fn set_delivery_callback(&self, callback: Option<(CVoidSend, DeliveryCallback)>) {
+        *self.shared.delivery_callback.lock() = callback;
+    }
+
+    /// Stops both threads, releasing the read handle once its current read times out.
+    pub fn stop(&self) {
+        self.cts.cancel();
+        self.shared.state.lock().stopped = true;
+        self.shared.changed.notify_all();
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this link
+    /// to the callback setter functions.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut ReliableLink {
+        self as *mut ReliableLink
+    }
+}
+
+impl Drop for ReliableLink {
+    fn drop(&mut self) {
+        self.stop() //Shared state is kept alive by the threads, so this doesn't create a dangling pointer.
+    }
+}
+
+/// Waits on changed until deadline, returning true if it passed.
+fn wait(
+    changed: &Condvar,
+    state: &mut parking_lot::MutexGuard<State>,
+    deadline: Option<Instant>,
+) -> bool {
+    match deadline {
+        Some(deadline) => changed.wait_until(state, deadline).timed_out(),
+        None => {
+            changed.wait(state);
+            false
+        }
+    }
+}
+
+/// Body of the retransmit thread.
+fn retransmit_loop(shared: &Shared) {
+    let mut state = shared.state.lock();
+
+    while !state.stopped {
+        let retransmit_at = match state.retransmit_at {
+            Some(retransmit_at) if !state.failed => retransmit_at,
+            _ => {
+                shared.changed.wait(&mut state);
+                continue;
+            }
+        };
+
+        if Instant::now() < retransmit_at {
+            shared.changed.wait_until(&mut state, retransmit_at);
+            continue;
+        }
+
+        if shared.max_retries != 0 && state.retries >= shared.max_retries {
+            log::warn!("Reliable link gave up after {} retries", state.retries);
+            state.failed = true;
+            state.retransmit_at = None;
+            shared.changed.notify_all();
+            continue;
+        }
+
+        //Go back to the oldest message, since the receiver dropped everything after it
+        state.retries += 1;
+        for message in &state.unacked {
+            shared.retransmits.fetch_add(1, Ordering::Relaxed);
+            if shared.write_message(state.session, message) != SerialError::NoErr {
+                log::warn!("Reliable link failed to retransmit message {}", message.seq);
+            }
+        }
+        state.retransmit_at = Some(Instant::now() + shared.retransmit_timeout);
+    }
+}
+
+/// Body of the reader thread.
+fn read_loop(
+    shared: &Shared,
+    reader: &Mutex<BufReader<SerialPortReader>>,
+    token: &CancellationToken,
+) {
+    //Lock the reader while this link is alive
+    let mut reader = reader.lock();
+
+    while !token.is_canceled() {
+        match reader.fill_buf() {
+            Ok([]) => {
+                log::warn!("Reliable link reader hit end of file");
+                break;
+            }
+            Ok(buf) => {
+                shared.receive(buf);
+                let len = buf.len();
+                reader.consume(len);
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("Reliable link reader failed: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+
+    use super::*;
+
+    const MESSAGES: u32 = 300;
+
+    fn config() -> ArqConfig {
+        ArqConfig {
+            window: 8,
+            retransmit_timeout: 0.02,
+            max_retries: 0,
+        }
+    }
+
+    /// One direction of a loopback, carrying frames to a pump thread that mangles them.
+    struct Wire(Mutex<Sender<Vec<u8>>>);
+
+    impl Transport for Wire {
+        fn write(&self, frame: &[u8]) -> SerialError {
+            let _ = self.0.lock().send(frame.to_vec());
+            SerialError::NoErr
+        }
+    }
+
+    /// The link at one end of the loopback, replaced to simulate a restart.
+    type Slot = Arc<Mutex<Option<Arc<ReliableLink>>>>;
+
+    /// Messages delivered to one link.
+    type Delivered = Mutex<Vec<u32>>;
+
+    unsafe extern "C" fn on_delivery(user_data: *mut c_void, data: *const u8, len: usize) {
+        let delivered = &*(user_data as *const Delivered);
+        let data = std::slice::from_raw_parts(data, len);
+        delivered
+            .lock()
+            .push(u32::from_be_bytes(data.try_into().unwrap()));
+    }
+
+    /// Passes frames from rx to the link in slot, dropping, duplicating, reordering and corrupting some.
+    fn pump(rx: Receiver<Vec<u8>>, slot: Slot, seed: u64) {
+        let mut rng = seed;
+        let mut next = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng % 100
+        };
+        let deliver = |frame: &[u8]| {
+            let link = slot.lock().clone();
+            if let Some(link) = link {
+                link.shared.receive(frame);
+            }
+        };
+        let mut held: Option<Vec<u8>> = None;
+
+        loop {
+            let mut frame = match rx.recv_timeout(Duration::from_millis(5)) {
+                Ok(frame) => frame,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(held) = held.take() {
+                        deliver(&held);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            match next() {
+                0..=9 => continue,
+                10..=19 => deliver(&frame),
+                20..=29 if held.is_none() => {
+                    held = Some(frame);
+                    continue;
+                }
+                30..=34 => {
+                    let idx = next() as usize % frame.len();
+                    frame[idx] ^= 0x55;
+                }
+                _ => {}
+            }
+            deliver(&frame);
+            if let Some(held) = held.take() {
+                deliver(&held);
+            }
+        }
+    }
+
+    /// Creates a link at one end of the loopback and puts it in slot.
+    fn spawn_link(wire: &Arc<Wire>, slot: &Slot, delivered: &Arc<Delivered>) -> Arc<ReliableLink> {
+        let link = Arc::new(ReliableLink::new(wire.clone(), config()).unwrap());
+        let user_data = Arc::as_ptr(delivered) as *mut c_void;
+        link.set_delivery_callback(Some((CVoidSend(user_data), on_delivery)));
+        *slot.lock() = Some(link.clone());
+        link
+    }
+
+    struct Loopback {
+        a_wire: Arc<Wire>,
+        b_wire: Arc<Wire>,
+        a_slot: Slot,
+        b_slot: Slot,
+    }
+
+    fn loopback() -> Loopback {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+        let a_slot = Slot::default();
+        let b_slot = Slot::default();
+
+        //A writes to the wire read by B, and the other way round
+        let slot = b_slot.clone();
+        std::thread::spawn(move || pump(a_rx, slot, 0x9E37_79B9_7F4A_7C15));
+        let slot = a_slot.clone();
+        std::thread::spawn(move || pump(b_rx, slot, 0xD1B5_4A32_D192_ED03));
+
+        Loopback {
+            a_wire: Arc::new(Wire(Mutex::new(a_tx))),
+            b_wire: Arc::new(Wire(Mutex::new(b_tx))),
+            a_slot,
+            b_slot,
+        }
+    }
+
+    fn send_all(link: &ReliableLink, messages: std::ops::Range<u32>) {
+        for message in messages {
+            assert!(link.send_reliable(&message.to_be_bytes(), 10.0) == SerialError::NoErr);
+        }
+    }
+
+    /// Waits for the last message to be delivered, since it is acknowledged before the callback runs.
+    fn wait_for_last(delivered: &Delivered) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while delivered.lock().last() != Some(&(MESSAGES - 1)) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn delivers_in_order_over_lossy_loopback() {
+        let wires = loopback();
+        let a_delivered = Arc::new(Delivered::default());
+        let b_delivered = Arc::new(Delivered::default());
+        let a = spawn_link(&wires.a_wire, &wires.a_slot, &a_delivered);
+        let b = spawn_link(&wires.b_wire, &wires.b_slot, &b_delivered);
+
+        let sender = std::thread::spawn({
+            let b = b.clone();
+            move || {
+                send_all(&b, 0..MESSAGES);
+                assert!(b.flush(10.0) == SerialError::NoErr);
+            }
+        });
+        send_all(&a, 0..MESSAGES);
+        assert!(a.flush(10.0) == SerialError::NoErr);
+        sender.join().unwrap();
+        wait_for_last(&a_delivered);
+        wait_for_last(&b_delivered);
+
+        let expected: Vec<u32> = (0..MESSAGES).collect();
+        assert_eq!(*a_delivered.lock(), expected);
+        assert_eq!(*b_delivered.lock(), expected);
+        let stats = a.stats();
+        assert!(stats.retransmits > 0 && stats.bad_frames > 0 && stats.duplicates > 0);
+    }
+
+    #[test]
+    fn survives_receiver_restart() {
+        let wires = loopback();
+        let before = Arc::new(Delivered::default());
+        let after = Arc::new(Delivered::default());
+        let a = spawn_link(&wires.a_wire, &wires.a_slot, &Arc::default());
+        spawn_link(&wires.b_wire, &wires.b_slot, &before);
+
+        send_all(&a, 0..MESSAGES / 2);
+        //Drops the only other reference, stopping the old link
+        spawn_link(&wires.b_wire, &wires.b_slot, &after);
+        send_all(&a, MESSAGES / 2..MESSAGES);
+        assert!(a.flush(10.0) == SerialError::NoErr);
+        wait_for_last(&after);
+
+        //Messages in flight at the restart may reach both, but none are lost or reordered
+        let before = before.lock().clone();
+        let after = after.lock().clone();
+        assert_eq!(before, (0..before.len() as u32).collect::<Vec<_>>());
+        let first = after[0];
+        assert!(first <= before.len() as u32);
+        assert_eq!(after, (first..MESSAGES).collect::<Vec<_>>());
+        assert!(a.stats().resets > 0);
+    }
+
+    #[test]
+    fn survives_sender_restart() {
+        let wires = loopback();
+        let delivered = Arc::new(Delivered::default());
+        let a = spawn_link(&wires.a_wire, &wires.a_slot, &Arc::default());
+        spawn_link(&wires.b_wire, &wires.b_slot, &delivered);
+
+        send_all(&a, 0..MESSAGES / 2);
+        assert!(a.flush(10.0) == SerialError::NoErr);
+        drop(a);
+
+        //B still expects the old session to continue, and ACKs for it must not drain the new one
+        let a = spawn_link(&wires.a_wire, &wires.a_slot, &Arc::default());
+        send_all(&a, MESSAGES / 2..MESSAGES);
+        assert!(a.flush(10.0) == SerialError::NoErr);
+        wait_for_last(&delivered);
+
+        assert_eq!(*delivered.lock(), (0..MESSAGES).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,341 @@
+//! Hayes AT command client, created with [crate::Serial::create_at_client].
+
+use std::ffi::{c_void, CString};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::raw::c_char;
+use std::sync::Arc;
+use std::time::Instant;
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use parking_lot::Condvar;
+use serialport::SerialPort;
+
+use crate::ffi::{AtResponse, AtStatus, SerialError};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{CVoidSend, SerialPortReader};
+use crate::transact::trim_line;
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+/// Callback for unsolicited result codes. Called from the reader thread with the line, minus its
+/// terminator, and its length.
+pub type UrcCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, line: *const c_char, line_size: usize);
+
+/// URC prefixes recognised by every client. More can be added with [AtClient::add_urc_prefix].
+const DEFAULT_URC_PREFIXES: &[&str] = &[
+    "RING",
+    "NO CARRIER",
+    "+CREG:",
+    "+CGREG:",
+    "+CEREG:",
+    "+CMTI:",
+    "+CMT:",
+    "+CDS:",
+    "+CLIP:",
+    "+CRING:",
+    "+CUSD:",
+];
+
+/// The command currently waiting for its final result code.
+struct Pending {
+    /// The command as sent, used to drop its echo.
+    command: String,
+    /// Information responses to this command start with this, e.g. `+CREG:` for `AT+CREG?`. Lines
+    /// with this prefix belong to the command even if they are also registered as URCs.
+    info_prefix: Option<String>,
+    lines: Vec<String>,
+    /// Set by the reader once the final result code arrives.
+    result: Option<(AtStatus, i32)>,
+}
+
+impl Pending {
+    fn new(command: &str) -> Self {
+        let info_prefix = command
+            .strip_prefix("AT")
+            .or_else(|| command.strip_prefix("at"))
+            .filter(|rest| rest.starts_with('+'))
+            .map(|rest| {
+                let end = rest.find(['=', '?']).unwrap_or(rest.len());
+                format!("{}:", &rest[..end])
+            });
+
+        Pending {
+            command: command.to_string(),
+            info_prefix,
+            lines: Vec::new(),
+            result: None,
+        }
+    }
+}
+
+struct Shared {
+    pending: Mutex<Option<Pending>>,
+    /// Signalled when the pending command gets its final result code.
+    completed: Condvar,
+    urc_prefixes: Mutex<Vec<String>>,
+    urc_callback: Mutex<Option<(CVoidSend, UrcCallback)>>,
+}
+
+impl Shared {
+    fn is_urc(&self, line: &str) -> bool {
+        self.urc_prefixes
+            .lock()
+            .iter()
+            .any(|prefix| line.starts_with(prefix.as_str()))
+    }
+
+    /// Sorts a line read from the port into the pending command, or the URC callback.
+    fn route_line(&self, mut line: String) {
+        //Echo is terminated by the \r that ended the command, on top of the \r\n of the response
+        line.truncate(line.trim_end_matches('\r').len());
+        if line.is_empty() {
+            return;
+        }
+
+        {
+            let mut pending = self.pending.lock();
+            if let Some(pending) = pending.as_mut().filter(|p| p.result.is_none()) {
+                let is_info = pending
+                    .info_prefix
+                    .as_deref()
+                    .is_some_and(|prefix| line.starts_with(prefix));
+
+                if line == pending.command {
+                    return; //Echo
+                }
+
+                //Final result codes win over URC prefixes, as NO CARRIER is both
+                if let Some(result) = parse_final_result(&line) {
+                    pending.result = Some(result);
+                    self.completed.notify_all();
+                    return;
+                }
+                if is_info || !self.is_urc(&line) {
+                    pending.lines.push(line);
+                    return;
+                }
+            }
+        }
+
+        if self.is_urc(&line) {
+            self.dispatch_urc(&line);
+        } else {
+            //Such as the late result of a command that timed out
+            log::debug!("Dropping line with no command waiting: {}", line);
+        }
+    }
+
+    fn dispatch_urc(&self, line: &str) {
+        let callback = *self.urc_callback.lock();
+
+        match callback {
+            Some((user_data, callback)) => {
+                let c_str = match CString::new(line) {
+                    Ok(c_str) => c_str,
+                    Err(_) => {
+                        log::warn!("Dropping URC with internal null bytes");
+                        return;
+                    }
+                };
+
+                unsafe {
+                    //Safe only if callback does not store a reference to the string, which it does not own.
+                    callback(user_data.0, c_str.as_ptr(), line.len());
+                }
+            }
+            None => log::debug!("Dropping URC with no callback set: {}", line),
+        }
+    }
+}
+
+/// Parses a final result code, returning its status and CME/CMS error number or CONNECT rate.
+fn parse_final_result(line: &str) -> Option<(AtStatus, i32)> {
+    match line {
+        "OK" => return Some((AtStatus::Ok, 0)),
+        "ERROR" => return Some((AtStatus::Error, 0)),
+        "NO CARRIER" => return Some((AtStatus::NoCarrier, 0)),
+        "BUSY" => return Some((AtStatus::Busy, 0)),
+        "NO ANSWER" => return Some((AtStatus::NoAnswer, 0)),
+        "NO DIALTONE" | "NO DIAL TONE" => return Some((AtStatus::NoDialtone, 0)),
+        "CONNECT" => return Some((AtStatus::Connect, 0)),
+        _ => {}
+    }
+
+    if let Some(rate) = line.strip_prefix("CONNECT ") {
+        //The rate may be followed by text such as /V42BIS
+        let digits = rate.trim().split(|c: char| !c.is_ascii_digit()).next();
+        Some((
+            AtStatus::Connect,
+            digits.and_then(|digits| digits.parse().ok()).unwrap_or(0),
+        ))
+    } else if let Some(code) = line.strip_prefix("+CME ERROR:") {
+        Some((AtStatus::CmeError, code.trim().parse().unwrap_or(-1)))
+    } else {
+        line.strip_prefix("+CMS ERROR:")
+            .map(|code| (AtStatus::CmsError, code.trim().parse().unwrap_or(-1)))
+    }
+}
+
+/// A client for modems that speak Hayes AT commands.
+///
+/// The client owns a reader thread that holds the read handle of its port for as long as the client
+/// lives, exactly like a [crate::SerialListener]. Lines read while a command is waiting are collected
+/// as its response, apart from unsolicited result codes, which are always passed to the URC callback.
+pub struct AtClient {
+    shared: Arc<Shared>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    /// Ensures only one command is waiting at a time.
+    command_lock: Mutex<()>,
+    /// Token used to kill the reader thread.
+    cts: CancellationTokenSource,
+}
+
+impl AtClient {
+    /// Creates a client and starts its reader thread.
+    pub fn start(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    ) -> AtClient {
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(None),
+            completed: Condvar::new(),
+            urc_prefixes: Mutex::new(DEFAULT_URC_PREFIXES.iter().map(|p| p.to_string()).collect()),
+            urc_callback: Mutex::new(None),
+        });
+        let cts = CancellationTokenSource::new();
+
+        let token = cts.token().clone();
+        let thread_shared = shared.clone();
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = reader.lock();
+
+        let thread_reader = reader.clone();
+        std::thread::spawn(move || {
+            log::debug!("Spawned AT reader");
+            read_loop(&thread_shared, &thread_reader, &token);
+            log::debug!("exiting AT reader thread")
+        });
+        //Thread detaches here
+
+        AtClient {
+            shared,
+            write_handle,
+            command_lock: Mutex::new(()),
+            cts,
+        }
+    }
+
+    /// Sends command, followed by a carriage return, and waits up to timeout seconds for its final
+    /// result code.
+    pub fn send(&self, command: &str, timeout: f32) -> AtResponse {
+        let _command = self.command_lock.lock();
+        let deadline = Instant::now().checked_add(timeout_from_secs(timeout));
+
+        *self.shared.pending.lock() = Some(Pending::new(command));
+
+        let (error, _) = write_counted(
+            &mut **self.write_handle.lock(),
+            format!("{}\r", command).as_bytes(),
+        );
+
+        let mut pending = self.shared.pending.lock();
+        if error == SerialError::NoErr {
+            while pending.as_ref().is_some_and(|p| p.result.is_none()) {
+                match deadline {
+                    Some(deadline) => {
+                        if self
+                            .shared
+                            .completed
+                            .wait_until(&mut pending, deadline)
+                            .timed_out()
+                        {
+                            break;
+                        }
+                    }
+                    None => self.shared.completed.wait(&mut pending),
+                }
+            }
+        }
+
+        let pending = pending.take().unwrap();
+        match pending.result {
+            Some((status, code)) => AtResponse {
+                error: SerialError::NoErr,
+                status,
+                code,
+                lines: pending.lines,
+            },
+            None => AtResponse {
+                error: if error == SerialError::NoErr {
+                    SerialError::Timeout
+                } else {
+                    error
+                },
+                status: AtStatus::NoResult,
+                code: 0,
+                lines: pending.lines,
+            },
+        }
+    }
+
+    /// Routes lines starting with prefix to the URC callback, unless they answer the pending command.
+    pub fn add_urc_prefix(&self, prefix: &str) {
+        self.shared.urc_prefixes.lock().push(prefix.to_string());
+    }
+
+    /// Sets the callback that receives unsolicited result codes, replacing any previous one.
+    pub fn set_urc_callback(&self, callback: Option<(CVoidSend, UrcCallback)>) {
+        *self.shared.urc_callback.lock() = callback;
+    }
+
+    /// Stops the reader thread, releasing the read handle once its current read times out.
+    pub fn stop(&self) {
+        self.cts.cancel();
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this client
+    /// to the callback setter function.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut AtClient {
+        self as *mut AtClient
+    }
+}
+
+impl Drop for AtClient {
+    fn drop(&mut self) {
+        self.cts.cancel() //The token will be kept alive by the thread, so this doesn't create a dangling pointer.
+    }
+}
+
+/// Body of the reader thread.
+fn read_loop(
+    shared: &Shared,
+    reader: &Mutex<BufReader<SerialPortReader>>,
+    token: &CancellationToken,
+) {
+    //Lock the reader while this client is alive
+    let mut reader = reader.lock();
+    let mut line = Vec::new();
+
+    while !token.is_canceled() {
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => {
+                log::warn!("AT reader hit end of file");
+                break;
+            }
+            Ok(_) if line.ends_with(b"\n") => {
+                shared.route_line(trim_line(&line));
+                line.clear();
+            }
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("AT reader failed: {}", err);
+                break;
+            }
+        }
+    }
+}
@@ -1,5 +1,13 @@
-use crate::SerialListenerBuilder;
-use std::ffi::c_void;
+use crate::ffi::{
+    FrameError, ModbusTable, NmeaGga, NmeaGll, NmeaGsa, NmeaGsv, NmeaRmc, NmeaVtg, RpcResult,
+    SerialError, Stm32Progress, TelemetryRecord, TransferProgress, UbxNavPvt, WritePriority,
+};
+use crate::serial_ext::CVoidSend;
+use crate::{
+    AtClient, Channel, FileTransfer, ModbusServer, ReliableLink, RpcEndpoint, RpcReply, Serial,
+    SerialListenerBuilder, Stm32Bootloader, UbxClient,
+};
+use std::ffi::{c_void, CStr};
 use std::os::raw::c_char;
 
 /// Adds the callback function to the serial listener.
@@ -33,3 +41,601 @@ pub unsafe extern "C" fn add_read_callback(
         true
     }
 }
+
+/// Queues data to be written to the port by a background thread at Normal priority, returning immediately.
+///
+/// The data is copied, so the buffer may be reused as soon as this returns. Once the write completes,
+/// callback is called from the writer thread with user_data, the outcome of the write, and the number
+/// of bytes that were written. Messages are never split, and never interleave with blocking writes.
+///
+/// Returns NoErr if the data was queued, or QueueFull if the queue is at capacity, in which case the
+/// callback will never be called. Any writes still queued when the port is destroyed complete with
+/// Interrupted.
+/// # Null policy
+/// Serial must not be null. Data may only be null if len is 0. user_data and callback may be null,
+/// in which case no completion is reported.
+#[no_mangle]
+pub unsafe extern "C" fn write_async(
+    serial: *mut Serial,
+    data: *const u8,
+    len: usize,
+    user_data: *mut c_void,
+    callback: Option<
+        unsafe extern "C" fn(user_data: *mut c_void, error: SerialError, bytes_written: usize),
+    >,
+) -> SerialError {
+    write_async_with_priority(
+        serial,
+        data,
+        len,
+        WritePriority::Normal,
+        user_data,
+        callback,
+    )
+}
+
+/// Same as [write_async], but queues the data at the given priority.
+///
+/// Once the message currently being written completes, the oldest message of the highest waiting
+/// priority is written next. Each priority has its own queue capacity, so a full Low queue never
+/// prevents High messages from being queued.
+/// # Null policy
+/// Serial must not be null. Data may only be null if len is 0. user_data and callback may be null,
+/// in which case no completion is reported.
+#[no_mangle]
+pub unsafe extern "C" fn write_async_with_priority(
+    serial: *mut Serial,
+    data: *const u8,
+    len: usize,
+    priority: WritePriority,
+    user_data: *mut c_void,
+    callback: Option<
+        unsafe extern "C" fn(user_data: *mut c_void, error: SerialError, bytes_written: usize),
+    >,
+) -> SerialError {
+    if serial.is_null() || (data.is_null() && len > 0) {
+        return SerialError::Other;
+    }
+
+    let data = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len)
+    };
+
+    (*serial).write_async(
+        data,
+        priority,
+        callback.map(|call| (CVoidSend(user_data), call)),
+    )
+}
+
+/// Sets the callback that receives unsolicited result codes from an AT client, replacing any
+/// previous one. Codes that arrive with no callback set are dropped. Only lines matching a URC prefix
+/// are passed on, see [AtClient::add_urc_prefix].
+///
+/// The callback is called from the clients reader thread, with user_data, the line without its
+/// terminator, and its size.
+///
+/// You *Do not* have ownership over this string. After the callback returns, the string will be freed,
+/// leaving a dangling pointer if you stored this pointer somewhere.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Client must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_urc_callback(
+    client: *mut AtClient,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(user_data: *mut c_void, line: *const c_char, line_size: usize),
+    >,
+) -> bool {
+    if client.is_null() {
+        false
+    } else {
+        (*client).set_urc_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback called after a master writes to a Modbus servers map, replacing any previous one.
+///
+/// The callback is called from the server thread once the response has been sent, with user_data,
+/// the table that was written, the first address written, and the number of values written. Use
+/// the servers getters to read the new values.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Server must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_modbus_write_callback(
+    server: *mut ModbusServer,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(user_data: *mut c_void, table: ModbusTable, address: u16, count: u16),
+    >,
+) -> bool {
+    if server.is_null() {
+        false
+    } else {
+        (*server).set_write_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback for GGA (fix data) sentences on a listener builder, switching it to NMEA mode.
+/// See [SerialListenerBuilder::set_nmea_mode].
+///
+/// The callback is called from the listener thread with user_data and the parsed sentence. You *Do not*
+/// have ownership over the sentence, it is freed after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Listener must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn add_nmea_gga_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, sentence: *const NmeaGga)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).nmea_callbacks().gga = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for RMC (recommended minimum) sentences. Same as [add_nmea_gga_callback].
+#[no_mangle]
+pub unsafe extern "C" fn add_nmea_rmc_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, sentence: *const NmeaRmc)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).nmea_callbacks().rmc = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for VTG (course and speed) sentences. Same as [add_nmea_gga_callback].
+#[no_mangle]
+pub unsafe extern "C" fn add_nmea_vtg_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, sentence: *const NmeaVtg)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).nmea_callbacks().vtg = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for GSA (active satellites) sentences. Same as [add_nmea_gga_callback].
+#[no_mangle]
+pub unsafe extern "C" fn add_nmea_gsa_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, sentence: *const NmeaGsa)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).nmea_callbacks().gsa = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for GSV (satellites in view) sentences. Same as [add_nmea_gga_callback].
+#[no_mangle]
+pub unsafe extern "C" fn add_nmea_gsv_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, sentence: *const NmeaGsv)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).nmea_callbacks().gsv = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for GLL (position) sentences. Same as [add_nmea_gga_callback].
+#[no_mangle]
+pub unsafe extern "C" fn add_nmea_gll_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, sentence: *const NmeaGll)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).nmea_callbacks().gll = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback that receives every valid UBX frame from a UBX client, replacing any previous one.
+///
+/// The callback is called from the clients reader thread, with user_data, the class and id of the
+/// message, and its payload and payload size. ACK, NAK and NAV-PVT messages are passed here too.
+///
+/// You *Do not* have ownership over the payload. After the callback returns, it will be freed.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Client must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_ubx_message_callback(
+    client: *mut UbxClient,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(
+            user_data: *mut c_void,
+            msg_class: u8,
+            msg_id: u8,
+            payload: *const u8,
+            payload_size: usize,
+        ),
+    >,
+) -> bool {
+    if client.is_null() {
+        false
+    } else {
+        (*client).set_message_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback that receives decoded NAV-PVT messages from a UBX client, replacing any
+/// previous one. Same as [set_ubx_message_callback].
+#[no_mangle]
+pub unsafe extern "C" fn set_ubx_nav_pvt_callback(
+    client: *mut UbxClient,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, pvt: *const UbxNavPvt)>,
+) -> bool {
+    if client.is_null() {
+        false
+    } else {
+        (*client).set_nav_pvt_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback that receives lines of text between UBX frames, such as NMEA sentences,
+/// replacing any previous one. Same as [set_ubx_message_callback].
+///
+/// Lines are passed without their terminator. You *Do not* have ownership over this string.
+#[no_mangle]
+pub unsafe extern "C" fn set_ubx_text_callback(
+    client: *mut UbxClient,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(user_data: *mut c_void, line: *const c_char, line_size: usize),
+    >,
+) -> bool {
+    if client.is_null() {
+        false
+    } else {
+        (*client).set_text_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback for lines that are not valid JSON on a listener builder, switching it to JSON
+/// lines mode. See [SerialListenerBuilder::set_json_mode].
+///
+/// The callback is called from the listener thread with user_data, the line without its terminator
+/// and its size, and the parse error message and its size. You *Do not* have ownership over either
+/// string, they are freed after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Listener must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn add_json_error_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(
+            user_data: *mut c_void,
+            line: *const c_char,
+            line_size: usize,
+            message: *const c_char,
+            message_size: usize,
+        ),
+    >,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        *(*listener).json_error_callback() = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for parsed records on a listener builder in telemetry mode. See
+/// [SerialListenerBuilder::set_telemetry_mode].
+///
+/// The callback is called from the listener thread with user_data and the record, which holds one value
+/// per schema field. You *Do not* have ownership over the record, it is freed after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Listener must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn add_telemetry_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, record: *const TelemetryRecord)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).telemetry_callback = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for decoded frames on a listener builder in a frame mode, such as
+/// [SerialListenerBuilder::set_cobs_mode].
+///
+/// The callback is called from the listener thread with user_data, the decoded payload and its size.
+/// Payloads may contain any byte, including 0x00. You *Do not* have ownership over the payload,
+/// it is freed after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Listener must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn add_frame_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).frame_callbacks.frame = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for dropped frames on a listener builder in a frame mode. This is optional,
+/// dropped frames are always counted in the listeners frame stats.
+///
+/// The callback is called from the listener thread with user_data and the reason the frame was dropped.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Listener must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn add_frame_error_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, error: FrameError)>,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).frame_callbacks.error = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback for KISS frames on a listener builder, switching it to KISS mode. See
+/// [SerialListenerBuilder::set_kiss_mode].
+///
+/// The callback is called from the listener thread with user_data, the TNC port and command of the
+/// frame, and its payload and payload size. You *Do not* have ownership over the payload, it is freed
+/// after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Listener must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn add_kiss_callback(
+    listener: *mut SerialListenerBuilder,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(
+            user_data: *mut c_void,
+            port: u8,
+            command: u8,
+            data: *const u8,
+            len: usize,
+        ),
+    >,
+) -> bool {
+    if listener.is_null() {
+        false
+    } else {
+        (*listener).set_kiss_mode();
+        (*listener).frame_callbacks.kiss = call.map(|call| (CVoidSend(user_data), call));
+        true
+    }
+}
+
+/// Sets the callback that receives messages from a reliable link, replacing any previous one.
+///
+/// The callback is called from the links reader thread with user_data, and the message and its size.
+/// Messages are delivered once each, in the order they were sent, unless the sender restarts with
+/// them in flight. You *Do not* have ownership over the message, it is freed after the callback
+/// returns.
+///
+/// Messages that arrive while no callback is set are acknowledged and dropped.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Link must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_reliable_delivery_callback(
+    link: *mut ReliableLink,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize)>,
+) -> bool {
+    if link.is_null() {
+        false
+    } else {
+        (*link).set_delivery_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback that receives data on a mux channel, replacing any previous one.
+///
+/// The callback is called from the muxs reader thread with user_data, and the data of each frame and
+/// its size. While a callback is set, received data is passed to it instead of being kept for
+/// [Channel::read]. You *Do not* have ownership over the data, it is freed after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Channel must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_channel_callback(
+    channel: *mut Channel,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize)>,
+) -> bool {
+    if channel.is_null() {
+        false
+    } else {
+        (*channel).set_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Calls method on the device without waiting, passing the result to call once the response arrives
+/// or timeout seconds pass. A negative or infinite timeout waits forever. See [RpcEndpoint::call].
+///
+/// The callback is called once, from one of the endpoints threads, with user_data and the result. It
+/// may also be called before this function returns, if the request could not be sent. You *Do not*
+/// have ownership over the result, it is freed after the callback returns.
+///
+/// The function will return false if the call was not made due to null pointers being passed, or
+/// method not being UTF-8.
+/// # Null policy
+/// Endpoint, method and call must not be null. Payload may only be null if payload_size is 0.
+/// user_data may be null.
+#[no_mangle]
+pub unsafe extern "C" fn rpc_call_async(
+    endpoint: *mut RpcEndpoint,
+    method: *const c_char,
+    payload: *const u8,
+    payload_size: usize,
+    timeout: f32,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, result: *const RpcResult)>,
+) -> bool {
+    if endpoint.is_null() || method.is_null() || (payload.is_null() && payload_size != 0) {
+        return false;
+    }
+    let (method, call) = match (CStr::from_ptr(method).to_str(), call) {
+        (Ok(method), Some(call)) => (method, call),
+        _ => return false,
+    };
+    let payload = if payload_size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(payload, payload_size)
+    };
+
+    (*endpoint).call_async(method, payload, timeout, (CVoidSend(user_data), call));
+    true
+}
+
+/// Sets the handler for requests to method from the device, replacing any previous one.
+///
+/// The handler is called from the endpoints reader thread with user_data, the request payload and its
+/// size, and a reply to fill in with [RpcReply::set_result] or [RpcReply::set_error]. Leaving the
+/// reply untouched sends an empty result. You *Do not* have ownership over the payload or the reply,
+/// they are freed after the handler returns.
+///
+/// Requests for methods without a handler are answered with an error.
+///
+/// The function will return false if the handler was not set due to null pointers being passed, or
+/// method not being UTF-8.
+/// # Null policy
+/// Endpoint and method must not be null, user_data may be null. Passing a null call removes the
+/// handler.
+#[no_mangle]
+pub unsafe extern "C" fn set_rpc_handler(
+    endpoint: *mut RpcEndpoint,
+    method: *const c_char,
+    user_data: *mut c_void,
+    call: Option<
+        unsafe extern "C" fn(
+            user_data: *mut c_void,
+            payload: *const u8,
+            payload_size: usize,
+            reply: *mut RpcReply,
+        ),
+    >,
+) -> bool {
+    if endpoint.is_null() || method.is_null() {
+        return false;
+    }
+    match CStr::from_ptr(method).to_str() {
+        Ok(method) => {
+            (*endpoint).set_handler(method, call.map(|call| (CVoidSend(user_data), call)));
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Sets the callback that receives the progress of a file transfer, replacing any previous one.
+///
+/// The callback is called from the transfer thread with user_data and the progress, after each block
+/// is acknowledged. You *Do not* have ownership over the progress, it is freed after the callback returns.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Transfer must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_transfer_progress_callback(
+    transfer: *mut FileTransfer,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, progress: *const TransferProgress)>,
+) -> bool {
+    if transfer.is_null() {
+        false
+    } else {
+        (*transfer).set_progress_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
+
+/// Sets the callback that receives the progress of STM32 bootloader erases, writes, verifies and
+/// reads, replacing any previous one.
+///
+/// The callback is called from the thread running the command with user_data and the progress, after
+/// each block. You *Do not* have ownership over the progress, it is freed after the callback returns.
+///
+/// The command still holds the port while the callback runs, so the callback must not call any other
+/// method of the client, or it will deadlock. Replacing the callback from inside it is fine.
+///
+/// The function will return false if the callback was not set due to null pointers being passed.
+/// # Null policy
+/// Client must not be null, user_data may be null. Passing a null call removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn set_stm32_progress_callback(
+    client: *mut Stm32Bootloader,
+    user_data: *mut c_void,
+    call: Option<unsafe extern "C" fn(user_data: *mut c_void, progress: *const Stm32Progress)>,
+) -> bool {
+    if client.is_null() {
+        false
+    } else {
+        (*client).set_progress_callback(call.map(|call| (CVoidSend(user_data), call)));
+        true
+    }
+}
@@ -0,0 +1,116 @@
+//! Consistent Overhead Byte Stuffing, with frames delimited by 0x00.
+
+use crate::ffi::FrameError;
+use crate::framing::{Deframer, FrameOut};
+
+/// Ends every encoded frame. Encoded data never contains it.
+pub const DELIMITER: u8 = 0x00;
+
+/// Encodes data, appending the delimiter.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+
+    //Each block is a code byte giving the distance to the next zero, followed by the bytes before it
+    let mut code_idx = 0;
+    out.push(0);
+    let mut code = 1u8;
+
+    for &byte in data {
+        //Start a new block once the current one is full, but only if more data follows
+        if code == 0xFF {
+            out[code_idx] = code;
+            code_idx = out.len();
+            out.push(0);
+            code = 1;
+        }
+
+        if byte == 0 {
+            out[code_idx] = code;
+            code_idx = out.len();
+            out.push(0);
+            code = 1;
+        } else {
+            out.push(byte);
+            code += 1;
+        }
+    }
+
+    out[code_idx] = code;
+    out.push(DELIMITER);
+    out
+}
+
+/// Decodes one frame, without its delimiter.
+///
+/// Returns None if a code byte is zero or points past the end of the frame.
+pub fn decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut idx = 0;
+
+    while idx < frame.len() {
+        let code = frame[idx] as usize;
+        if code == 0 || idx + code > frame.len() {
+            return None;
+        }
+
+        out.extend_from_slice(&frame[idx + 1..idx + code]);
+        idx += code;
+
+        //A block shorter than 254 bytes stands for a zero, unless it ends the frame
+        if code < 0xFF && idx < frame.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+/// Splits a stream on delimiters and decodes each frame.
+pub struct CobsDeframer {
+    buf: Vec<u8>,
+    max_len: usize,
+    /// Set when the current frame overflowed, so the rest of it is dropped up to the next delimiter.
+    discarding: bool,
+}
+
+impl CobsDeframer {
+    pub fn new(max_len: usize) -> Self {
+        CobsDeframer {
+            buf: Vec::new(),
+            max_len,
+            discarding: false,
+        }
+    }
+}
+
+impl Deframer for CobsDeframer {
+    fn push(&mut self, data: &[u8], out: &mut FrameOut) {
+        for &byte in data {
+            if byte != DELIMITER {
+                if self.discarding {
+                    continue;
+                }
+                //Encoding adds one byte, plus one for every 254
+                if self.buf.len() > self.max_len.saturating_add(self.max_len / 254) {
+                    self.buf.clear();
+                    self.discarding = true;
+                    out(Err(FrameError::TooLong));
+                    continue;
+                }
+                self.buf.push(byte);
+                continue;
+            }
+
+            //Back to back delimiters are padding, not empty frames
+            if !self.discarding && !self.buf.is_empty() {
+                match decode(&self.buf) {
+                    Some(frame) => out(Ok(&frame)),
+                    None => out(Err(FrameError::Malformed)),
+                }
+            }
+
+            self.buf.clear();
+            self.discarding = false;
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Checksums shared by the protocol modules, and exposed to C++ through [Checksum].
+//!
+//! Each algorithm has an `_update` function that continues a running value over more data, so a
+//! checksum can be computed over data that arrives in pieces. The plain functions compute the
+//! checksum of a whole buffer.
+
+use serialport::{Error, ErrorKind};
+
+use crate::ffi::ChecksumKind;
+
+/// Continues a reflected (LSB first) CRC-16 with the reflected polynomial poly.
+fn crc16_reflected_update(poly: u16, mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Continues a CRC-16/MODBUS from crc, which starts at 0xFFFF.
+pub fn crc16_modbus_update(crc: u16, data: &[u8]) -> u16 {
+    crc16_reflected_update(0xA001, crc, data)
+}
+
+/// CRC-16/MODBUS: polynomial 0xA001 (reflected 0x8005), initial value 0xFFFF.
+///
+/// The result is transmitted low byte first.
+pub fn crc16_modbus(data: &[u8]) -> u16 {
+    crc16_modbus_update(0xFFFF, data)
+}
+
+/// Continues a CRC-8/SMBUS from crc, which starts at 0.
+pub fn crc8_update(mut crc: u8, data: &[u8]) -> u8 {
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Continues the MSB first CRC-16 with polynomial 0x1021 shared by CRC-16/CCITT-FALSE and
+/// CRC-16/XMODEM, which differ only in the initial value.
+pub fn crc16_1021_update(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// The 8-bit Fletcher checksum used by u-blox UBX frames, computed over class, id, length and payload.
+///
+/// Returns CK_A and CK_B, which are transmitted in that order.
+pub fn ubx_checksum(data: &[u8]) -> (u8, u8) {
+    data.iter().fold((0u8, 0u8), |(ck_a, ck_b), &byte| {
+        let ck_a = ck_a.wrapping_add(byte);
+        (ck_a, ck_b.wrapping_add(ck_a))
+    })
+}
+
+/// Continues a Fletcher-16 from its two running sums, which start at 0.
+pub fn fletcher16_update((mut sum1, mut sum2): (u8, u8), data: &[u8]) -> (u8, u8) {
+    for &byte in data {
+        sum1 = ((sum1 as u16 + byte as u16) % 255) as u8;
+        sum2 = ((sum2 as u16 + sum1 as u16) % 255) as u8;
+    }
+
+    (sum1, sum2)
+}
+
+/// XOR of all bytes.
+pub fn xor8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |acc, &byte| acc ^ byte)
+}
+
+/// Sum of all bytes, modulo 256.
+pub fn sum8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, &byte| acc.wrapping_add(byte))
+}
+
+/// CRC-16/X-25, the FCS-16 of PPP and HDLC: polynomial 0x8408 (reflected 0x1021), initial value
+/// 0xFFFF, inverted at the end.
+///
+/// The result is transmitted low byte first.
+pub fn crc16_x25(data: &[u8]) -> u16 {
+    !crc16_reflected_update(0x8408, 0xFFFF, data)
+}
+
+/// Continues a CRC-32 from its uninverted running value crc, which starts at 0xFFFFFFFF. The
+/// checksum is the inverse of the final value.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// CRC-32 as used by Ethernet, zlib and PPP's FCS-32: polynomial 0xEDB88320 (reflected 0x04C11DB7),
+/// initial value 0xFFFFFFFF, inverted at the end.
+///
+/// The result is transmitted low byte first.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFF_FFFF, data)
+}
+
+/// Where a streaming NMEA checksum is in its sentence.
+#[derive(Copy, Clone, PartialEq)]
+enum NmeaPhase {
+    /// Nothing seen yet, so a leading start character is skipped.
+    Start,
+    Body,
+    /// The `*` was seen, so the rest is ignored.
+    Done,
+}
+
+/// The running value of each algorithm.
+#[derive(Copy, Clone)]
+enum State {
+    Crc8(u8),
+    Crc16Modbus(u16),
+    Crc16Ccitt(u16),
+    Crc16Xmodem(u16),
+    Crc16X25(u16),
+    Crc32(u32),
+    Fletcher16(u8, u8),
+    Xor(u8),
+    Sum8(u8),
+    Nmea(u8, NmeaPhase),
+}
+
+impl State {
+    /// The initial state of kind, or None if kind is not known.
+    fn new(kind: ChecksumKind) -> Option<Self> {
+        Some(match kind {
+            ChecksumKind::Crc8 => State::Crc8(0),
+            ChecksumKind::Crc16Modbus => State::Crc16Modbus(0xFFFF),
+            ChecksumKind::Crc16Ccitt => State::Crc16Ccitt(0xFFFF),
+            ChecksumKind::Crc16Xmodem => State::Crc16Xmodem(0),
+            ChecksumKind::Crc16X25 => State::Crc16X25(0xFFFF),
+            ChecksumKind::Crc32 => State::Crc32(0xFFFF_FFFF),
+            ChecksumKind::Fletcher16 => State::Fletcher16(0, 0),
+            ChecksumKind::Xor => State::Xor(0),
+            ChecksumKind::Sum8 => State::Sum8(0),
+            ChecksumKind::Nmea => State::Nmea(0, NmeaPhase::Start),
+            _ => return None,
+        })
+    }
+}
+
+/// A checksum computed over data fed to it in any number of pieces.
+pub struct Checksum {
+    kind: ChecksumKind,
+    state: State,
+}
+
+impl Checksum {
+    /// Starts a checksum of kind, or returns None if kind is not known.
+    pub fn of(kind: ChecksumKind) -> Option<Self> {
+        State::new(kind).map(|state| Checksum { kind, state })
+    }
+
+    pub fn kind(&self) -> ChecksumKind {
+        self.kind
+    }
+
+    /// Continues the checksum over data.
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = match self.state {
+            State::Crc8(crc) => State::Crc8(crc8_update(crc, data)),
+            State::Crc16Modbus(crc) => State::Crc16Modbus(crc16_modbus_update(crc, data)),
+            State::Crc16Ccitt(crc) => State::Crc16Ccitt(crc16_1021_update(crc, data)),
+            State::Crc16Xmodem(crc) => State::Crc16Xmodem(crc16_1021_update(crc, data)),
+            State::Crc16X25(crc) => State::Crc16X25(crc16_reflected_update(0x8408, crc, data)),
+            State::Crc32(crc) => State::Crc32(crc32_update(crc, data)),
+            State::Fletcher16(sum1, sum2) => {
+                let (sum1, sum2) = fletcher16_update((sum1, sum2), data);
+                State::Fletcher16(sum1, sum2)
+            }
+            State::Xor(sum) => State::Xor(sum ^ xor8(data)),
+            State::Sum8(sum) => State::Sum8(sum.wrapping_add(sum8(data))),
+            State::Nmea(mut sum, mut phase) => {
+                for &byte in data {
+                    match (phase, byte) {
+                        (NmeaPhase::Start, b'$' | b'!') => phase = NmeaPhase::Body,
+                        (NmeaPhase::Done, _) => break,
+                        (_, b'*') => phase = NmeaPhase::Done,
+                        _ => {
+                            sum ^= byte;
+                            phase = NmeaPhase::Body;
+                        }
+                    }
+                }
+                State::Nmea(sum, phase)
+            }
+        };
+    }
+
+    /// Gets the checksum of all data so far. This does not end the checksum, so more data may follow.
+    pub fn value(&self) -> u32 {
+        match self.state {
+            State::Crc8(crc) | State::Xor(crc) | State::Sum8(crc) | State::Nmea(crc, _) => {
+                crc as u32
+            }
+            State::Crc16Modbus(crc) | State::Crc16Ccitt(crc) | State::Crc16Xmodem(crc) => {
+                crc as u32
+            }
+            State::Crc16X25(crc) => !crc as u32,
+            State::Crc32(crc) => !crc,
+            State::Fletcher16(sum1, sum2) => u16::from_be_bytes([sum2, sum1]) as u32,
+        }
+    }
+
+    /// Restarts the checksum, as if no data had been fed to it.
+    pub fn reset(&mut self) {
+        self.state = State::new(self.kind).unwrap();
+    }
+}
+
+impl ChecksumKind {
+    /// The number of bytes in a checksum of this kind, or 0 if the kind is not known.
+    pub fn size(&self) -> usize {
+        match *self {
+            ChecksumKind::Crc8 | ChecksumKind::Xor | ChecksumKind::Sum8 | ChecksumKind::Nmea => 1,
+            ChecksumKind::Crc16Modbus
+            | ChecksumKind::Crc16Ccitt
+            | ChecksumKind::Crc16Xmodem
+            | ChecksumKind::Crc16X25
+            | ChecksumKind::Fletcher16 => 2,
+            ChecksumKind::Crc32 => 4,
+            _ => 0,
+        }
+    }
+
+    /// Computes the checksum of data, or 0 if the kind is not known.
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        Checksum::of(*self).map_or(0, |mut checksum| {
+            checksum.update(data);
+            checksum.value()
+        })
+    }
+}
+
+fn unknown_kind() -> Error {
+    Error::new(ErrorKind::InvalidInput, "Unknown checksum kind.")
+}
+
+/// Starts a streaming checksum of kind, or throws if kind is not known.
+pub fn new_checksum(kind: ChecksumKind) -> serialport::Result<Box<Checksum>> {
+    Checksum::of(kind).map(Box::new).ok_or_else(unknown_kind)
+}
+
+/// Computes the checksum of data, or throws if kind is not known.
+pub fn compute_checksum(kind: ChecksumKind, data: &[u8]) -> serialport::Result<u32> {
+    Checksum::of(kind).ok_or_else(unknown_kind)?;
+    Ok(kind.compute(data))
+}
+
+/// The number of bytes in a checksum of kind, or 0 if kind is not known.
+pub fn checksum_size(kind: ChecksumKind) -> usize {
+    kind.size()
+}
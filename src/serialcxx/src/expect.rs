@@ -0,0 +1,87 @@
+//! Expect-style pattern waiting, see [crate::Serial::expect].
+
+use std::io::{BufRead, BufReader, ErrorKind};
+
+use regex::bytes::Regex;
+use serialport::{Error, Result};
+
+use crate::ffi::{ExpectResult, SerialError};
+use crate::serial_ext::{io_error_to_serial, DeadlineGuard, SerialPortReader};
+
+/// Compiles every pattern, erroring on the first that is not a valid regex.
+pub fn compile_patterns<S: AsRef<str>>(patterns: impl Iterator<Item = S>) -> Result<Vec<Regex>> {
+    patterns
+        .map(|pattern| {
+            Regex::new(pattern.as_ref())
+                .map_err(|err| Error::new(serialport::ErrorKind::InvalidInput, err.to_string()))
+        })
+        .collect()
+}
+
+/// Finds the pattern whose match ends first in text, preferring earlier patterns on ties.
+///
+/// Returns the pattern index along with the start and end of its match.
+fn earliest_match(patterns: &[Regex], text: &[u8]) -> Option<(usize, usize, usize)> {
+    patterns
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, pattern)| pattern.find(text).map(|m| (idx, m.start(), m.end())))
+        .min_by_key(|&(idx, _, end)| (end, idx))
+}
+
+/// Reads from the port until one of patterns matches, or the deadline passes.
+///
+/// Data is consumed from the reader only up to the end of the match, so anything received after it
+/// is left for the next read. Matching works on raw bytes rather than lines, which allows waiting
+/// for prompts that are not followed by a newline.
+pub fn expect(
+    reader: &mut BufReader<SerialPortReader>,
+    deadline: &DeadlineGuard,
+    patterns: &[Regex],
+) -> ExpectResult {
+    //Bytes consumed from the reader that have not matched yet
+    let mut pending = Vec::new();
+
+    let error = loop {
+        if !deadline.arm() {
+            break SerialError::Timeout;
+        }
+
+        let chunk = match reader.fill_buf() {
+            Ok([]) => break SerialError::Other, //End of file, the device is gone
+            Ok(chunk) => chunk,
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                continue
+            }
+            Err(err) => break io_error_to_serial(&err),
+        };
+
+        let searched = pending.len();
+        pending.extend_from_slice(chunk);
+
+        match earliest_match(patterns, &pending) {
+            Some((idx, start, end)) => {
+                //Only take what we need from this chunk, leaving the rest buffered
+                reader.consume(end.saturating_sub(searched));
+
+                return ExpectResult {
+                    error: SerialError::NoErr,
+                    index: idx as i32,
+                    before: String::from_utf8_lossy(&pending[..start]).into_owned(),
+                    matched: String::from_utf8_lossy(&pending[start..end]).into_owned(),
+                };
+            }
+            None => {
+                let len = chunk.len();
+                reader.consume(len);
+            }
+        }
+    };
+
+    ExpectResult {
+        error,
+        index: -1,
+        before: String::from_utf8_lossy(&pending).into_owned(),
+        matched: String::new(),
+    }
+}
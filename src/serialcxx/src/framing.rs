@@ -0,0 +1,251 @@
+//! Frame listeners, which split the byte stream into packets using one of the framing codecs.
+
+use std::ffi::c_void;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use cancellation::CancellationToken;
+
+use crate::cobs::CobsDeframer;
+use crate::ffi::{FrameError, FrameReadResult, FrameStats, HdlcFcs, LengthFraming, SerialError};
+use crate::hdlc::HdlcDeframer;
+use crate::length_frame::LengthDeframer;
+use crate::serial_ext::{io_error_to_serial, CVoidSend, DeadlineGuard, SerialPortReader};
+use crate::slip::{kiss_split, SlipDeframer};
+
+/// Callback for a decoded frame. The payload is only valid until the callback returns.
+pub type FrameCallback = unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize);
+/// Callback for a frame that was dropped.
+pub type FrameErrorCallback = unsafe extern "C" fn(user_data: *mut c_void, error: FrameError);
+/// Callback for a KISS frame, split into its TNC port, command and payload.
+pub type KissCallback = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    port: u8,
+    command: u8,
+    data: *const u8,
+    len: usize,
+);
+
+/// Frames longer than this are dropped unless the builder sets another limit.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 4096;
+
+/// Receives each frame or error found by a [Deframer].
+pub type FrameOut<'a> = dyn FnMut(Result<&[u8], FrameError>) + 'a;
+
+/// Splits a byte stream into frames.
+///
+/// Implementations keep partial frames between calls, and resynchronize on their own after an error.
+pub trait Deframer: Send {
+    /// Feeds data read from the port, calling out with each frame or error found, in order.
+    fn push(&mut self, data: &[u8], out: &mut FrameOut);
+}
+
+/// The framing a frame listener decodes.
+#[derive(Clone)]
+pub enum Framing {
+    Cobs,
+    Slip,
+    /// SLIP framing, where the first byte of each frame holds a TNC port and command.
+    Kiss,
+    Hdlc(HdlcFcs),
+    Length(LengthFraming),
+}
+
+impl Framing {
+    /// Creates a fresh decoder for this framing.
+    pub fn deframer(&self, max_len: usize) -> Box<dyn Deframer> {
+        match self {
+            Framing::Cobs => Box::new(CobsDeframer::new(max_len)),
+            Framing::Slip | Framing::Kiss => Box::new(SlipDeframer::new(max_len)),
+            Framing::Hdlc(fcs) => Box::new(HdlcDeframer::new(*fcs, max_len)),
+            Framing::Length(config) => Box::new(LengthDeframer::new(config.clone(), max_len)),
+        }
+    }
+}
+
+/// The callbacks of a frame listener.
+#[derive(Default, Copy, Clone)]
+pub struct FrameCallbacks {
+    pub frame: Option<(CVoidSend, FrameCallback)>,
+    pub error: Option<(CVoidSend, FrameErrorCallback)>,
+    pub kiss: Option<(CVoidSend, KissCallback)>,
+}
+
+impl FrameCallbacks {
+    /// Checks that the callback frames of this framing are delivered to is set.
+    pub fn can_deliver(&self, framing: &Framing) -> bool {
+        match framing {
+            Framing::Kiss => self.kiss.is_some(),
+            _ => self.frame.is_some(),
+        }
+    }
+}
+
+/// Passes frames to the callbacks, and counts them.
+pub struct FrameSink {
+    callbacks: FrameCallbacks,
+    /// Set in KISS mode, where frames are split before they are delivered.
+    kiss: bool,
+    frames: AtomicU64,
+    malformed: AtomicU64,
+    too_long: AtomicU64,
+    bad_checksums: AtomicU64,
+}
+
+impl FrameSink {
+    pub fn new(callbacks: FrameCallbacks, framing: &Framing) -> Self {
+        FrameSink {
+            callbacks,
+            kiss: matches!(framing, Framing::Kiss),
+            frames: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            too_long: AtomicU64::new(0),
+            bad_checksums: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            too_long: self.too_long.load(Ordering::Relaxed),
+            bad_checksums: self.bad_checksums.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn deliver(&self, frame: Result<&[u8], FrameError>) {
+        match frame {
+            Ok(data) if self.kiss => match kiss_split(data) {
+                Some((port, command, data)) => {
+                    self.frames.fetch_add(1, Ordering::Relaxed);
+
+                    if let Some((user_data, callback)) = self.callbacks.kiss {
+                        unsafe {
+                            //Safe only if callback does not store a reference to the data, which it does not own.
+                            callback(user_data.0, port, command, data.as_ptr(), data.len());
+                        }
+                    }
+                }
+                None => self.deliver(Err(FrameError::Malformed)),
+            },
+            Ok(data) => {
+                self.frames.fetch_add(1, Ordering::Relaxed);
+
+                if let Some((user_data, callback)) = self.callbacks.frame {
+                    unsafe {
+                        //Safe only if callback does not store a reference to the data, which it does not own.
+                        callback(user_data.0, data.as_ptr(), data.len());
+                    }
+                }
+            }
+            Err(error) => {
+                let counter = match error {
+                    FrameError::TooLong => &self.too_long,
+                    FrameError::BadChecksum => &self.bad_checksums,
+                    _ => &self.malformed,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                log::debug!("Dropping frame: {:?}", error.repr);
+
+                if let Some((user_data, callback)) = self.callbacks.error {
+                    unsafe {
+                        callback(user_data.0, error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Body of a listener thread in a frame mode.
+pub fn read_loop(
+    reader: &mut BufReader<SerialPortReader>,
+    deframer: &mut dyn Deframer,
+    sink: &FrameSink,
+    token: &CancellationToken,
+) {
+    while !token.is_canceled() {
+        match reader.fill_buf() {
+            Ok([]) => {
+                log::warn!("Frame listener hit end of file");
+                break;
+            }
+            Ok(buf) => {
+                deframer.push(buf, &mut |frame| sink.deliver(frame));
+                let len = buf.len();
+                reader.consume(len);
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("Frame listener failed: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Reads until deframer produces a frame, or the deadline guarded by deadline passes.
+///
+/// Input is only consumed up to the end of the frame, so data after it is left for the next read.
+/// Bad frames are skipped and counted. A frame cut off by the deadline is lost.
+pub fn read_frame(
+    reader: &mut BufReader<SerialPortReader>,
+    deadline: &DeadlineGuard,
+    deframer: &mut dyn Deframer,
+) -> FrameReadResult {
+    let mut frame = None;
+    let mut dropped = 0;
+
+    loop {
+        if !deadline.arm() {
+            return FrameReadResult {
+                error: SerialError::Timeout,
+                data: Vec::new(),
+                dropped,
+            };
+        }
+
+        let buf = match reader.fill_buf() {
+            Ok([]) => {
+                return FrameReadResult {
+                    error: SerialError::Other,
+                    data: Vec::new(),
+                    dropped,
+                }
+            }
+            Ok(buf) => buf,
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                continue
+            }
+            Err(err) => {
+                return FrameReadResult {
+                    error: io_error_to_serial(&err),
+                    data: Vec::new(),
+                    dropped,
+                }
+            }
+        };
+
+        //Feed a byte at a time, so nothing past the end of the frame is consumed
+        let mut used = 0;
+        for byte in buf.chunks(1) {
+            used += 1;
+            deframer.push(byte, &mut |result| match result {
+                Ok(data) => frame = Some(data.to_vec()),
+                Err(_) => dropped += 1,
+            });
+            if frame.is_some() {
+                break;
+            }
+        }
+        reader.consume(used);
+
+        if let Some(data) = frame {
+            return FrameReadResult {
+                error: SerialError::NoErr,
+                data,
+                dropped,
+            };
+        }
+    }
+}
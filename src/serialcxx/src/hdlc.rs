@@ -0,0 +1,128 @@
+//! HDLC-like framing, as PPP uses on asynchronous links (RFC 1662).
+
+use crate::crc::{crc16_x25, crc32};
+use crate::ffi::{FrameError, HdlcFcs};
+use crate::framing::{Deframer, FrameOut};
+
+/// Starts and ends every frame.
+pub const FLAG: u8 = 0x7E;
+/// Starts an escape sequence. The byte after it is XORed with [ESC_XOR].
+pub const ESC: u8 = 0x7D;
+/// Flips bit 5 of escaped bytes.
+pub const ESC_XOR: u8 = 0x20;
+
+impl HdlcFcs {
+    /// The number of FCS bytes at the end of each frame.
+    pub fn size(&self) -> usize {
+        match *self {
+            HdlcFcs::Crc32 => 4,
+            _ => 2,
+        }
+    }
+
+    /// Computes the FCS of data, low byte first.
+    pub fn compute(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            HdlcFcs::Crc32 => crc32(data).to_le_bytes().to_vec(),
+            _ => crc16_x25(data).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+fn escape_into(out: &mut Vec<u8>, data: &[u8]) {
+    for &byte in data {
+        //Control characters are escaped too, so links that eat XON/XOFF do not corrupt frames
+        if byte == FLAG || byte == ESC || byte < 0x20 {
+            out.extend_from_slice(&[ESC, byte ^ ESC_XOR]);
+        } else {
+            out.push(byte);
+        }
+    }
+}
+
+/// Encodes data as a frame, with its FCS appended and a flag before and after it.
+pub fn encode(data: &[u8], fcs: HdlcFcs) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 8 + fcs.size() + 2);
+    out.push(FLAG);
+    escape_into(&mut out, data);
+    escape_into(&mut out, &fcs.compute(data));
+    out.push(FLAG);
+    out
+}
+
+/// Splits a stream on flags, removes escapes and checks the FCS of each frame.
+pub struct HdlcDeframer {
+    buf: Vec<u8>,
+    fcs: HdlcFcs,
+    max_len: usize,
+    escaped: bool,
+    /// Set when the current frame is bad, so the rest of it is dropped up to the next flag.
+    discarding: bool,
+}
+
+impl HdlcDeframer {
+    pub fn new(fcs: HdlcFcs, max_len: usize) -> Self {
+        HdlcDeframer {
+            buf: Vec::new(),
+            fcs,
+            max_len,
+            escaped: false,
+            discarding: false,
+        }
+    }
+
+    fn end_frame(&mut self, out: &mut FrameOut) {
+        //Back to back flags are padding, not empty frames
+        if self.escaped {
+            //RFC 1662 aborts frames ending in an escape
+            out(Err(FrameError::Malformed));
+        } else if !self.discarding && !self.buf.is_empty() {
+            if self.buf.len() <= self.fcs.size() {
+                out(Err(FrameError::Malformed));
+            } else {
+                let (data, fcs) = self.buf.split_at(self.buf.len() - self.fcs.size());
+                if self.fcs.compute(data) == fcs {
+                    out(Ok(data));
+                } else {
+                    out(Err(FrameError::BadChecksum));
+                }
+            }
+        }
+
+        self.buf.clear();
+        self.escaped = false;
+        self.discarding = false;
+    }
+}
+
+impl Deframer for HdlcDeframer {
+    fn push(&mut self, data: &[u8], out: &mut FrameOut) {
+        for &byte in data {
+            if byte == FLAG {
+                self.end_frame(out);
+                continue;
+            }
+            if self.discarding {
+                continue;
+            }
+
+            let byte = if self.escaped {
+                self.escaped = false;
+                byte ^ ESC_XOR
+            } else if byte == ESC {
+                self.escaped = true;
+                continue;
+            } else {
+                byte
+            };
+
+            if self.buf.len() >= self.max_len.saturating_add(self.fcs.size()) {
+                self.buf.clear();
+                self.discarding = true;
+                out(Err(FrameError::TooLong));
+                continue;
+            }
+            self.buf.push(byte);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Intel HEX images.
+
+use serialport::{Error, ErrorKind};
+
+use crate::ffi::FirmwareImage;
+use crate::image::{
+    check_segments, decode_hex, encode_hex, line_error, sorted_segments, ImageBuilder,
+};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Data bytes per record written.
+const RECORD_LEN: usize = 16;
+
+/// Parses an Intel HEX file into segments, in file order. Records continuing the previous one are
+/// merged into its segment. Throws on the first bad line, or if there is no end of file record.
+pub fn parse_intel_hex(text: &str) -> serialport::Result<FirmwareImage> {
+    let mut image = ImageBuilder::new();
+    let mut base = 0u32;
+    let mut finished = false;
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fail = |desc: &str| line_error(idx + 1, desc);
+
+        let record = line
+            .strip_prefix(':')
+            .and_then(decode_hex)
+            .ok_or_else(|| fail("not a hex record"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(fail("wrong record length"));
+        }
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(fail("bad checksum"));
+        }
+
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+
+        match record[3] {
+            DATA => {
+                if !image.push(base.wrapping_add(offset), data) {
+                    return Err(fail("data runs past the end of the address space"));
+                }
+            }
+            END_OF_FILE => {
+                finished = true;
+                break;
+            }
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16
+            }
+            START_SEGMENT_ADDRESS if data.len() == 4 => {
+                let segment = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let offset = u16::from_be_bytes([data[2], data[3]]) as u32;
+                image.set_entry_point((segment << 4) + offset)
+            }
+            START_LINEAR_ADDRESS if data.len() == 4 => {
+                image.set_entry_point(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+            }
+            _ => return Err(fail("unknown or malformed record type")),
+        }
+    }
+
+    //Without it, a truncated file would pass as a shorter image
+    if !finished {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Missing end of file record.",
+        ));
+    }
+
+    Ok(image.finish())
+}
+
+/// Appends one record to out.
+fn write_record(out: &mut String, offset: u16, record_type: u8, data: &[u8]) {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&offset.to_be_bytes());
+    record.push(record_type);
+    record.extend_from_slice(data);
+    record.push(record.iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte)));
+
+    out.push(':');
+    encode_hex(out, &record);
+    out.push('\n');
+}
+
+/// Writes image as an Intel HEX file, in address order with extended linear address records.
+/// Throws if a segment runs past the end of the address space.
+pub fn write_intel_hex(image: &FirmwareImage) -> serialport::Result<String> {
+    check_segments(image)?;
+
+    let mut out = String::new();
+    let mut upper = 0u16;
+
+    for segment in sorted_segments(image) {
+        let mut address = segment.address;
+        let mut data = segment.data.as_slice();
+
+        while !data.is_empty() {
+            //Records cannot cross a 64 KiB boundary, as their offset would wrap
+            let len = data
+                .len()
+                .min(RECORD_LEN)
+                .min(0x1_0000 - (address & 0xFFFF) as usize);
+            if (address >> 16) as u16 != upper {
+                upper = (address >> 16) as u16;
+                write_record(&mut out, 0, EXTENDED_LINEAR_ADDRESS, &upper.to_be_bytes());
+            }
+
+            write_record(&mut out, address as u16, DATA, &data[..len]);
+            address = address.wrapping_add(len as u32);
+            data = &data[len..];
+        }
+    }
+
+    if image.has_entry_point {
+        write_record(
+            &mut out,
+            0,
+            START_LINEAR_ADDRESS,
+            &image.entry_point.to_be_bytes(),
+        );
+    }
+    write_record(&mut out, 0, END_OF_FILE, &[]);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = ":0400000001020304F2\n:04000500AABBCCDDE9\n:00000001FF\n";
+        let image = parse_intel_hex(text).unwrap();
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].address, 0);
+        assert_eq!(image.segments[0].data, [1, 2, 3, 4]);
+        assert_eq!(image.segments[1].address, 5);
+        assert_eq!(
+            parse_intel_hex(&write_intel_hex(&image).unwrap())
+                .unwrap()
+                .segments
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for text in [
+            ":0é0AB\n:00000001FF\n",
+            "é\n:00000001FF\n",
+            ":\n:00000001FF\n",
+            ":00000001F\n",
+            ":0400000001020304F3\n:00000001FF\n",
+            ":0500000001020304F2\n:00000001FF\n",
+            ":00000007F9\n:00000001FF\n",
+            "0400000001020304F2\n:00000001FF\n",
+        ] {
+            assert!(parse_intel_hex(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_missing_end_of_file() {
+        assert!(parse_intel_hex(":0400000001020304F2\n").is_err());
+        assert!(parse_intel_hex("").is_err());
+    }
+}
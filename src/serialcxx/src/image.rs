@@ -0,0 +1,175 @@
+//! Firmware images as address/data segments, shared by the Intel HEX and S-record codecs.
+
+use serialport::{Error, ErrorKind};
+
+use crate::ffi::{FirmwareImage, MemorySegment, SegmentOverlap};
+
+/// The largest image [flatten_image] builds, so a stray far away record cannot exhaust memory.
+const MAX_FLAT_LEN: u64 = 64 * 1024 * 1024;
+
+/// Collects data records into segments, merging each record that continues the previous one.
+pub struct ImageBuilder {
+    image: FirmwareImage,
+}
+
+impl ImageBuilder {
+    pub fn new() -> Self {
+        ImageBuilder {
+            image: FirmwareImage {
+                segments: Vec::new(),
+                entry_point: 0,
+                has_entry_point: false,
+            },
+        }
+    }
+
+    /// Adds data at address, returning false if it runs past the end of the address space.
+    pub fn push(&mut self, address: u32, data: &[u8]) -> bool {
+        if address as u64 + data.len() as u64 > 1 << 32 {
+            return false;
+        }
+
+        match self.image.segments.last_mut() {
+            Some(last) if end(last) == address as u64 => last.data.extend_from_slice(data),
+            _ if data.is_empty() => {}
+            _ => self.image.segments.push(MemorySegment {
+                address,
+                data: data.to_vec(),
+            }),
+        }
+        true
+    }
+
+    pub fn set_entry_point(&mut self, entry_point: u32) {
+        self.image.entry_point = entry_point;
+        self.image.has_entry_point = true;
+    }
+
+    pub fn finish(self) -> FirmwareImage {
+        self.image
+    }
+}
+
+/// The address after the last byte of segment.
+pub fn end(segment: &MemorySegment) -> u64 {
+    segment.address as u64 + segment.data.len() as u64
+}
+
+/// Checks that no segment runs past the end of the address space, as images from C++ could.
+pub fn check_segments(image: &FirmwareImage) -> serialport::Result<()> {
+    match image.segments.iter().find(|segment| end(segment) > 1 << 32) {
+        Some(segment) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Segment at {:#010X} runs past the end of the address space.",
+                segment.address
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Gets the segments of image in address order.
+pub fn sorted_segments(image: &FirmwareImage) -> Vec<&MemorySegment> {
+    let mut segments: Vec<&MemorySegment> = image
+        .segments
+        .iter()
+        .filter(|segment| !segment.data.is_empty())
+        .collect();
+    segments.sort_by_key(|segment| segment.address);
+    segments
+}
+
+/// Finds every address range covered by more than one segment of image, in address order.
+pub fn find_overlaps(image: &FirmwareImage) -> Vec<SegmentOverlap> {
+    let mut overlaps: Vec<SegmentOverlap> = Vec::new();
+    let mut covered_to = 0u64;
+
+    for segment in sorted_segments(image) {
+        let start = segment.address as u64;
+        let overlap_end = end(segment).min(covered_to);
+
+        if start < overlap_end {
+            //Ranges found after this one may extend it, when three segments overlap
+            match overlaps.last_mut() {
+                Some(last) if last.address as u64 + last.len >= start => {
+                    last.len = last.len.max(overlap_end - last.address as u64)
+                }
+                _ => overlaps.push(SegmentOverlap {
+                    address: segment.address,
+                    len: overlap_end - start,
+                }),
+            }
+        }
+        covered_to = covered_to.max(end(segment));
+    }
+
+    overlaps
+}
+
+/// Copies image into one contiguous segment, from its lowest to its highest address, with fill in
+/// the gaps. Throws if segments overlap, or the result would be too large.
+pub fn flatten_image(image: &FirmwareImage, fill: u8) -> serialport::Result<MemorySegment> {
+    check_segments(image)?;
+    if let Some(overlap) = find_overlaps(image).first() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Segments overlap for {} bytes at {:#010X}.",
+                overlap.len, overlap.address
+            ),
+        ));
+    }
+
+    let segments = sorted_segments(image);
+    let (start, stop) = match (segments.first(), segments.iter().map(|s| end(s)).max()) {
+        (Some(first), Some(stop)) => (first.address, stop),
+        _ => {
+            return Ok(MemorySegment {
+                address: 0,
+                data: Vec::new(),
+            })
+        }
+    };
+    if stop - start as u64 > MAX_FLAT_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Flattened image would be larger than 64 MiB.",
+        ));
+    }
+
+    let mut data = vec![fill; (stop - start as u64) as usize];
+    for segment in segments {
+        let offset = (segment.address - start) as usize;
+        data[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+    }
+
+    Ok(MemorySegment {
+        address: start,
+        data,
+    })
+}
+
+/// Decodes pairs of hex digits.
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+/// Appends bytes to out as upper case hex digits.
+pub fn encode_hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        out.push_str(&format!("{:02X}", byte));
+    }
+}
+
+/// Builds the error thrown for a bad line of an image file.
+pub fn line_error(line: usize, desc: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("Line {}: {}", line, desc))
+}
@@ -0,0 +1,144 @@
+//! JSON lines parsing for listeners in JSON mode, see [crate::SerialListenerBuilder::set_json_mode].
+
+use std::ffi::{c_void, CString};
+use std::io::{BufReader, ErrorKind};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use cancellation::CancellationToken;
+
+use crate::ffi::JsonStats;
+use crate::serial::ReadCallback;
+use crate::serial_ext::{read_line_bounded, CVoidSend, SerialPortReader};
+
+/// Callback for a line that is not valid JSON. Both strings are only valid until the callback returns.
+pub type JsonErrorCallback = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    line: *const c_char,
+    line_size: usize,
+    message: *const c_char,
+    message_size: usize,
+);
+
+/// Longest line kept while waiting for a terminator.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Parses lines as JSON, and passes each value to the read callback as compact JSON.
+pub struct JsonDecoder {
+    callback: (CVoidSend, ReadCallback),
+    error_callback: Option<(CVoidSend, JsonErrorCallback)>,
+    values: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+impl JsonDecoder {
+    pub fn new(
+        callback: (CVoidSend, ReadCallback),
+        error_callback: Option<(CVoidSend, JsonErrorCallback)>,
+    ) -> Self {
+        JsonDecoder {
+            callback,
+            error_callback,
+            values: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> JsonStats {
+        JsonStats {
+            values: self.values.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Handles one line read from the port.
+    pub fn handle_line(&self, line: &[u8]) {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            return;
+        }
+
+        match serde_json::from_slice::<serde_json::Value>(line) {
+            Ok(value) => {
+                self.values.fetch_add(1, Ordering::Relaxed);
+
+                //Serializing escapes any NUL in strings, so this cannot fail
+                let json = serde_json::to_string(&value).unwrap();
+                let (user_data, callback) = self.callback;
+                let c_str = CString::new(json).unwrap_or_default();
+
+                unsafe {
+                    //Safe only if callback does not store a reference to the string, which it does not own.
+                    callback(user_data.0, c_str.as_ptr(), c_str.as_bytes().len());
+                }
+            }
+            Err(err) => self.report_error(line, &err.to_string()),
+        }
+    }
+
+    fn report_error(&self, line: &[u8], message: &str) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+        log::debug!("Dropping line that is not JSON: {}", message);
+
+        if let Some((user_data, callback)) = self.error_callback {
+            //Lines with NUL bytes are passed up to the first one
+            let line_end = line
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(line.len());
+            let line = CString::new(&line[..line_end]).unwrap_or_default();
+            let message = CString::new(message).unwrap_or_default();
+
+            unsafe {
+                callback(
+                    user_data.0,
+                    line.as_ptr(),
+                    line.as_bytes().len(),
+                    message.as_ptr(),
+                    message.as_bytes().len(),
+                );
+            }
+        }
+    }
+}
+
+/// Body of a listener thread in JSON mode.
+pub fn read_loop(
+    reader: &mut BufReader<SerialPortReader>,
+    decoder: &JsonDecoder,
+    token: &CancellationToken,
+) {
+    let mut line = Vec::new();
+
+    while !token.is_canceled() {
+        match read_line_bounded(reader, &mut line, MAX_LINE_LEN) {
+            Ok(0) => {
+                log::warn!("JSON listener hit end of file");
+                break;
+            }
+            Ok(_) if line.ends_with(b"\n") => {
+                decoder.handle_line(&line);
+                line.clear();
+            }
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("JSON listener failed: {}", err);
+                break;
+            }
+        }
+
+        if line.len() > MAX_LINE_LEN {
+            decoder.report_error(&line[..64], "Line is over 64KiB");
+            line.clear();
+        }
+    }
+}
+
+/// Checks that json is a single JSON value, and returns it as one compact line with its terminator.
+pub fn to_line(json: &str) -> serde_json::Result<Vec<u8>> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let mut line = serde_json::to_vec(&value)?;
+    line.push(b'\n');
+    Ok(line)
+}
@@ -0,0 +1,178 @@
+//! Length-prefixed framing with a configurable header layout, such as `[sync][len][payload][crc]`.
+
+use serialport::{Error, ErrorKind};
+
+use crate::ffi::{ChecksumKind, FrameChecksum, FrameError, LengthFraming};
+use crate::framing::{Deframer, FrameOut};
+
+impl FrameChecksum {
+    /// The matching checksum from the checksum library, or None for [FrameChecksum::None].
+    fn kind(&self) -> Option<ChecksumKind> {
+        Some(match *self {
+            FrameChecksum::Xor => ChecksumKind::Xor,
+            FrameChecksum::Sum8 => ChecksumKind::Sum8,
+            FrameChecksum::Crc8 => ChecksumKind::Crc8,
+            FrameChecksum::Crc16Modbus => ChecksumKind::Crc16Modbus,
+            FrameChecksum::Crc16Ccitt => ChecksumKind::Crc16Ccitt,
+            FrameChecksum::Crc16Xmodem => ChecksumKind::Crc16Xmodem,
+            FrameChecksum::Crc32 => ChecksumKind::Crc32,
+            _ => return None,
+        })
+    }
+
+    /// The number of checksum bytes after the payload.
+    pub fn size(&self) -> usize {
+        self.kind().map_or(0, |kind| kind.size())
+    }
+
+    /// Computes the checksum of data.
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        self.kind().map_or(0, |kind| kind.compute(data))
+    }
+}
+
+/// Checks that a layout can be decoded.
+pub fn check_config(config: &LengthFraming) -> serialport::Result<()> {
+    let invalid = |desc: &str| Err(Error::new(ErrorKind::InvalidInput, desc));
+
+    if !(1..=4).contains(&config.length_width) {
+        return invalid("Length field width must be between 1 and 4 bytes.");
+    }
+    if (config.length_offset as usize) < config.sync.len() {
+        return invalid("Length field overlaps the sync bytes.");
+    }
+    if config.checksum_offset as usize > header_len(config) {
+        return invalid("Checksum must start before the end of the header.");
+    }
+    if config.checksum != FrameChecksum::None && config.checksum.kind().is_none() {
+        return invalid("Unknown checksum.");
+    }
+
+    Ok(())
+}
+
+/// The length of everything before the payload, ending with the length field.
+fn header_len(config: &LengthFraming) -> usize {
+    config.length_offset as usize + config.length_width as usize
+}
+
+/// Reads an unsigned integer of up to 4 bytes.
+fn read_uint(bytes: &[u8], big_endian: bool) -> u32 {
+    let fold = |acc: u32, &byte: &u8| acc << 8 | byte as u32;
+    if big_endian {
+        bytes.iter().fold(0, fold)
+    } else {
+        bytes.iter().rev().fold(0, fold)
+    }
+}
+
+/// Hunts for the sync bytes, then reads each frame by its length field and checks its checksum.
+///
+/// Frames are passed on without their sync bytes and checksum, so they start with the rest of the
+/// header. After a bad frame the hunt restarts one byte past its sync, so a frame hidden inside it is
+/// not lost.
+pub struct LengthDeframer {
+    config: LengthFraming,
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl LengthDeframer {
+    /// Creates a deframer for config, which must have passed [check_config].
+    pub fn new(config: LengthFraming, max_len: usize) -> Self {
+        LengthDeframer {
+            config,
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    /// Drops everything before the next sync, returning false if there is none yet.
+    fn hunt(&mut self) -> bool {
+        let sync = &self.config.sync;
+        if sync.is_empty() {
+            return true;
+        }
+
+        match self
+            .buf
+            .windows(sync.len())
+            .position(|window| window == &sync[..])
+        {
+            Some(start) => {
+                self.buf.drain(..start);
+                true
+            }
+            None => {
+                //Keep a tail that may be the start of a sync split across reads
+                let keep = self.buf.len().min(sync.len() - 1);
+                self.buf.drain(..self.buf.len() - keep);
+                false
+            }
+        }
+    }
+
+    /// Decodes the frame at the start of the buffer, or returns None if it is not complete yet.
+    fn next_frame(&self) -> Option<Result<usize, FrameError>> {
+        let config = &self.config;
+        let header_len = header_len(config);
+        if self.buf.len() < header_len {
+            return None;
+        }
+
+        let length = read_uint(
+            &self.buf[config.length_offset as usize..header_len],
+            config.big_endian,
+        ) as usize;
+        let payload_len = if config.length_includes_header {
+            match length.checked_sub(header_len) {
+                Some(payload_len) => payload_len,
+                None => return Some(Err(FrameError::Malformed)),
+            }
+        } else {
+            length
+        };
+        if payload_len > self.max_len {
+            return Some(Err(FrameError::TooLong));
+        }
+
+        let body_len = header_len + payload_len;
+        let checksum_len = config.checksum.size();
+        if self.buf.len() < body_len + checksum_len {
+            return None;
+        }
+
+        let expected = config
+            .checksum
+            .compute(&self.buf[config.checksum_offset as usize..body_len]);
+        let received = read_uint(
+            &self.buf[body_len..body_len + checksum_len],
+            config.big_endian,
+        );
+        if expected != received {
+            return Some(Err(FrameError::BadChecksum));
+        }
+
+        Some(Ok(body_len))
+    }
+}
+
+impl Deframer for LengthDeframer {
+    fn push(&mut self, data: &[u8], out: &mut FrameOut) {
+        self.buf.extend_from_slice(data);
+
+        while self.hunt() {
+            match self.next_frame() {
+                None => break,
+                Some(Ok(body_len)) => {
+                    out(Ok(&self.buf[self.config.sync.len()..body_len]));
+                    self.buf.drain(..body_len + self.config.checksum.size());
+                }
+                Some(Err(error)) => {
+                    out(Err(error));
+                    self.buf.drain(..1);
+                }
+            }
+        }
+    }
+}
@@ -1,10 +1,50 @@
 //! The bindings bridge.
 
+mod arq;
+mod at;
 mod bindgenffi;
+mod cobs;
+mod crc;
+mod expect;
+mod framing;
+mod hdlc;
+mod ihex;
+mod image;
+mod json_lines;
+mod length_frame;
+mod modbus;
+mod modbus_master;
+mod modbus_server;
+mod mux;
+mod nmea;
+mod periodic;
+mod rpc;
 mod serial;
 mod serial_ext;
+mod slip;
+mod srec;
+mod stm32;
+mod telemetry;
+mod transact;
+mod ubx;
+mod write_queue;
+mod xmodem;
 
+use arq::ReliableLink;
+use at::AtClient;
+use crc::{checksum_size, compute_checksum, new_checksum, Checksum};
+use ihex::{parse_intel_hex, write_intel_hex};
+use image::{find_overlaps, flatten_image};
+use modbus_master::ModbusMaster;
+use modbus_server::ModbusServer;
+use mux::{Channel, ChannelMux};
+use periodic::PeriodicWrite;
+use rpc::{RpcEndpoint, RpcReply};
 use serial::*;
+use srec::{parse_srec, write_srec};
+use stm32::Stm32Bootloader;
+use ubx::UbxClient;
+use xmodem::FileTransfer;
 
 #[cxx::bridge(namespace = "serialcxx")]
 pub mod ffi {
@@ -16,6 +56,527 @@ pub mod ffi {
         pub bytes_read: usize,
     }
 
+    pub struct TransactResult {
+        /// The error this transaction produced, if any.
+        pub error: SerialError,
+        /// The line that matched, without its terminator. Empty if nothing matched.
+        pub response: String,
+        /// Every line read before the response that did not match, in order.
+        pub unmatched: Vec<String>,
+    }
+
+    pub struct ExpectResult {
+        /// The error this expect produced, if any.
+        pub error: SerialError,
+        /// The index of the pattern that matched, or -1 if none did.
+        pub index: i32,
+        /// The text received before the match. If nothing matched, all text received.
+        pub before: String,
+        /// The text that matched the pattern.
+        pub matched: String,
+    }
+
+    pub struct AtResponse {
+        /// The error this command produced, if any. Timeout if no final result code arrived.
+        pub error: SerialError,
+        /// The final result code.
+        pub status: AtStatus,
+        /// The error number of a +CME ERROR or +CMS ERROR, or -1 if it was not numeric. The rate of a
+        /// CONNECT, or 0 if it had none.
+        pub code: i32,
+        /// The information lines received before the final result code, excluding echo and URCs.
+        pub lines: Vec<String>,
+    }
+
+    pub struct ModbusBitsResult {
+        /// The error this request produced, if any.
+        pub error: ModbusError,
+        /// The coils or discrete inputs read, one per element, each 0 or 1.
+        pub values: Vec<u8>,
+    }
+
+    pub struct ModbusRegistersResult {
+        /// The error this request produced, if any.
+        pub error: ModbusError,
+        /// The registers read.
+        pub values: Vec<u16>,
+    }
+
+    pub struct ModbusServerConfig {
+        /// The unit id the server answers to, between 1 and 247. Broadcasts to unit 0 are also handled.
+        pub unit: u8,
+        /// How requests and responses are framed on the wire.
+        pub framing: ModbusFraming,
+        /// Number of coils, starting from address 0.
+        pub coils: u16,
+        /// Number of discrete inputs, starting from address 0.
+        pub discrete_inputs: u16,
+        /// Number of holding registers, starting from address 0.
+        pub holding_registers: u16,
+        /// Number of input registers, starting from address 0.
+        pub input_registers: u16,
+    }
+
+    pub struct NmeaGga {
+        /// The talker id, such as GP or GN.
+        pub talker: String,
+        /// Seconds since midnight UTC, or NaN if absent.
+        pub time: f64,
+        /// Degrees, negative south of the equator, or NaN if absent.
+        pub latitude: f64,
+        /// Degrees, negative west of Greenwich, or NaN if absent.
+        pub longitude: f64,
+        /// 0 for no fix, 1 for GPS, 2 for DGPS, 4 for RTK fixed, 5 for RTK float.
+        pub fix_quality: u8,
+        /// Number of satellites used in the fix.
+        pub satellites: u8,
+        /// Horizontal dilution of precision, or NaN if absent.
+        pub hdop: f64,
+        /// Metres above mean sea level, or NaN if absent.
+        pub altitude: f64,
+        /// Metres from the ellipsoid to mean sea level, or NaN if absent.
+        pub geoid_separation: f64,
+        /// Seconds since the last DGPS update, or NaN if absent.
+        pub dgps_age: f64,
+        /// The DGPS reference station, or -1 if absent.
+        pub dgps_station: i32,
+    }
+
+    pub struct NmeaRmc {
+        /// The talker id, such as GP or GN.
+        pub talker: String,
+        /// Seconds since midnight UTC, or NaN if absent.
+        pub time: f64,
+        /// True if the receiver reports the fix as valid.
+        pub valid: bool,
+        /// Degrees, negative south of the equator, or NaN if absent.
+        pub latitude: f64,
+        /// Degrees, negative west of Greenwich, or NaN if absent.
+        pub longitude: f64,
+        /// Speed over ground in knots, or NaN if absent.
+        pub speed_knots: f64,
+        /// Course over ground in degrees from true north, or NaN if absent.
+        pub course: f64,
+        /// Day of the month, or 0 if the date is absent.
+        pub day: u8,
+        /// Month from 1 to 12, or 0 if the date is absent.
+        pub month: u8,
+        /// Four digit year, or 0 if the date is absent.
+        pub year: u16,
+        /// Degrees, negative to the west, or NaN if absent.
+        pub magnetic_variation: f64,
+        /// The mode indicator character, such as 'A' for autonomous, or 0 if absent.
+        pub mode: u8,
+    }
+
+    pub struct NmeaVtg {
+        /// The talker id, such as GP or GN.
+        pub talker: String,
+        /// Course over ground in degrees from true north, or NaN if absent.
+        pub course_true: f64,
+        /// Course over ground in degrees from magnetic north, or NaN if absent.
+        pub course_magnetic: f64,
+        /// Speed over ground in knots, or NaN if absent.
+        pub speed_knots: f64,
+        /// Speed over ground in km/h, or NaN if absent.
+        pub speed_kmh: f64,
+        /// The mode indicator character, such as 'A' for autonomous, or 0 if absent.
+        pub mode: u8,
+    }
+
+    pub struct NmeaGsa {
+        /// The talker id, such as GP or GN.
+        pub talker: String,
+        /// True if the receiver switches between 2D and 3D fixes on its own.
+        pub automatic: bool,
+        /// 1 for no fix, 2 for a 2D fix, 3 for a 3D fix.
+        pub fix_type: u8,
+        /// The ids of the satellites used in the fix.
+        pub satellites: Vec<u16>,
+        /// Position dilution of precision, or NaN if absent.
+        pub pdop: f64,
+        /// Horizontal dilution of precision, or NaN if absent.
+        pub hdop: f64,
+        /// Vertical dilution of precision, or NaN if absent.
+        pub vdop: f64,
+        /// The GNSS system id from NMEA 4.1, or -1 if absent.
+        pub system_id: i32,
+    }
+
+    pub struct NmeaSatellite {
+        /// The satellite id.
+        pub prn: u16,
+        /// Degrees above the horizon, or -1 if absent.
+        pub elevation: i16,
+        /// Degrees from true north, or -1 if absent.
+        pub azimuth: i16,
+        /// Signal to noise ratio in dB-Hz, or -1 if the satellite is not tracked.
+        pub snr: i16,
+    }
+
+    pub struct NmeaGsv {
+        /// The talker id, such as GP or GN.
+        pub talker: String,
+        /// The number of GSV sentences in this group.
+        pub total_messages: u8,
+        /// The number of this sentence in its group, starting at 1.
+        pub message_number: u8,
+        /// The total number of satellites in view.
+        pub satellites_in_view: u16,
+        /// Up to four of the satellites in view.
+        pub satellites: Vec<NmeaSatellite>,
+        /// The signal id from NMEA 4.1, or -1 if absent.
+        pub signal_id: i32,
+    }
+
+    pub struct NmeaGll {
+        /// The talker id, such as GP or GN.
+        pub talker: String,
+        /// Degrees, negative south of the equator, or NaN if absent.
+        pub latitude: f64,
+        /// Degrees, negative west of Greenwich, or NaN if absent.
+        pub longitude: f64,
+        /// Seconds since midnight UTC, or NaN if absent.
+        pub time: f64,
+        /// True if the receiver reports the fix as valid.
+        pub valid: bool,
+        /// The mode indicator character, such as 'A' for autonomous, or 0 if absent.
+        pub mode: u8,
+    }
+
+    pub struct NmeaStats {
+        /// Sentences that passed their checksum and parsed.
+        pub sentences: u64,
+        /// Sentences that failed or were missing their checksum.
+        pub bad_checksums: u64,
+        /// Sentences from talkers other than satellite receivers, which are dropped.
+        pub unknown_talkers: u64,
+        /// Lines that were not sentences, and sentences whose fields did not parse.
+        pub malformed: u64,
+    }
+
+    pub struct UbxNavPvt {
+        /// GPS time of week of the navigation epoch, in milliseconds.
+        pub itow: u32,
+        /// UTC year.
+        pub year: u16,
+        /// UTC month, 1 to 12.
+        pub month: u8,
+        /// UTC day of the month, 1 to 31.
+        pub day: u8,
+        /// UTC hour, 0 to 23.
+        pub hour: u8,
+        /// UTC minute, 0 to 59.
+        pub minute: u8,
+        /// UTC second, 0 to 60.
+        pub second: u8,
+        /// Fraction of the second in nanoseconds, which may be negative.
+        pub nano: i32,
+        /// True if the date is valid.
+        pub valid_date: bool,
+        /// True if the time of day is valid.
+        pub valid_time: bool,
+        /// True if the time of day is fully resolved, with no second ambiguity.
+        pub fully_resolved: bool,
+        /// Time accuracy estimate in nanoseconds.
+        pub time_accuracy: u32,
+        /// 0 for no fix, 1 dead reckoning, 2 for 2D, 3 for 3D, 4 for GNSS and dead reckoning, 5 time only.
+        pub fix_type: u8,
+        /// True if the fix is within the receivers accuracy masks.
+        pub gnss_fix_ok: bool,
+        /// Number of satellites used in the solution.
+        pub satellites: u8,
+        /// Degrees, negative west of Greenwich.
+        pub longitude: f64,
+        /// Degrees, negative south of the equator.
+        pub latitude: f64,
+        /// Metres above the ellipsoid.
+        pub height: f64,
+        /// Metres above mean sea level.
+        pub height_msl: f64,
+        /// Horizontal accuracy estimate in metres.
+        pub horizontal_accuracy: f64,
+        /// Vertical accuracy estimate in metres.
+        pub vertical_accuracy: f64,
+        /// Metres per second.
+        pub velocity_north: f64,
+        /// Metres per second.
+        pub velocity_east: f64,
+        /// Metres per second.
+        pub velocity_down: f64,
+        /// 2D ground speed in metres per second.
+        pub ground_speed: f64,
+        /// 2D heading of motion in degrees.
+        pub heading_motion: f64,
+        /// Speed accuracy estimate in metres per second.
+        pub speed_accuracy: f64,
+        /// Heading accuracy estimate in degrees.
+        pub heading_accuracy: f64,
+        /// Position dilution of precision.
+        pub pdop: f64,
+    }
+
+    pub struct UbxAckResult {
+        /// The error this message produced, if any. Timeout if no ACK or NAK arrived.
+        pub error: SerialError,
+        /// True if the receiver sent an ACK, false if it sent a NAK or nothing.
+        pub acknowledged: bool,
+    }
+
+    pub struct UbxStats {
+        /// Frames that passed their checksum.
+        pub frames: u64,
+        /// Frames that failed their checksum, which are dropped.
+        pub bad_checksums: u64,
+    }
+
+    pub struct ArqConfig {
+        /// Number of messages that may be in flight before [ReliableLink::send_reliable] waits, from 1
+        /// to 127.
+        pub window: u8,
+        /// Seconds to wait for an ACK before sending unacknowledged messages again, up to 3600.
+        pub retransmit_timeout: f32,
+        /// Number of times the oldest message is sent again before the link gives up, or 0 to retry
+        /// forever.
+        pub max_retries: u32,
+    }
+
+    pub struct ArqStats {
+        /// Messages queued with [ReliableLink::send_reliable].
+        pub sent: u64,
+        /// Messages sent again after a timeout.
+        pub retransmits: u64,
+        /// Messages passed to the delivery callback.
+        pub delivered: u64,
+        /// Messages received again after they were delivered, which are dropped.
+        pub duplicates: u64,
+        /// Messages received after a gap in the sequence, which are dropped until the gap is resent.
+        pub out_of_order: u64,
+        /// Frames that failed their CRC or could not be decoded.
+        pub bad_frames: u64,
+        /// Times the other end lost track of our messages, usually because it restarted, and they were
+        /// moved into a new session.
+        pub resets: u64,
+    }
+
+    pub struct MuxStats {
+        /// Frames received, on any channel.
+        pub frames: u64,
+        /// Frames received for channels that are not open, which are dropped.
+        pub unrouted: u64,
+        /// Frames dropped because their channel already had 64KiB waiting to be read.
+        pub overruns: u64,
+        /// Frames that could not be decoded.
+        pub bad_frames: u64,
+    }
+
+    pub struct RpcResult {
+        /// Ok if the device responded with a result.
+        pub status: RpcStatus,
+        /// The result, in the same form as the request payload. Empty unless status is Ok.
+        pub payload: Vec<u8>,
+        /// The error message from the device for RemoteError, or what failed for EncodingError.
+        pub message: String,
+    }
+
+    pub struct TransferConfig {
+        pub protocol: TransferProtocol,
+        /// Seconds to wait for each reply or block before trying again.
+        pub timeout: f32,
+        /// Times a block, or the start of the transfer, is tried again before giving up.
+        pub max_retries: u32,
+    }
+
+    #[derive(Clone)]
+    pub struct TransferFile {
+        /// The file name sent with YMODEM. XMODEM has no names, so this is empty for received files.
+        pub name: String,
+        pub data: Vec<u8>,
+    }
+
+    pub struct TransferProgress {
+        /// Index of the file being transferred, counting from 0.
+        pub file_index: u32,
+        pub name: String,
+        /// Bytes of this file acknowledged so far.
+        pub bytes_done: u64,
+        /// Size of this file, or 0 if the sender did not say.
+        pub bytes_total: u64,
+        /// Blocks sent or asked for again over the whole transfer.
+        pub retries: u32,
+    }
+
+    #[derive(Clone)]
+    pub struct TransferResult {
+        pub status: TransferStatus,
+        /// The files received, including any finished before a failure. Empty for sends.
+        pub files: Vec<TransferFile>,
+        /// What went wrong, if status is not Done.
+        pub message: String,
+    }
+
+    #[derive(Clone)]
+    pub struct MemorySegment {
+        /// Address of the first byte.
+        pub address: u32,
+        pub data: Vec<u8>,
+    }
+
+    pub struct FirmwareImage {
+        /// The data of the image, in file order. Records at consecutive addresses are merged.
+        pub segments: Vec<MemorySegment>,
+        /// The start address from the file. Only meaningful if has_entry_point is set.
+        pub entry_point: u32,
+        pub has_entry_point: bool,
+    }
+
+    pub struct SegmentOverlap {
+        /// First address covered by more than one segment.
+        pub address: u32,
+        /// Number of bytes covered more than once from there.
+        pub len: u64,
+    }
+
+    pub struct Stm32Config {
+        /// The line driving BOOT0, or None if BOOT0 is set by hand.
+        pub boot0_line: ControlLine,
+        /// The line driving NRST, or None if the chip is reset by hand.
+        pub reset_line: ControlLine,
+        /// By default an asserted line drives BOOT0 high. Set this if the wiring inverts it.
+        pub invert_boot0: bool,
+        /// By default an asserted line holds the chip in reset. Set this if the wiring inverts it.
+        pub invert_reset: bool,
+        /// Seconds to wait for each reply.
+        pub timeout: f32,
+        /// Seconds to wait for an erase to finish. Mass erases can take tens of seconds on large parts.
+        pub erase_timeout: f32,
+    }
+
+    pub struct Stm32IdResult {
+        pub error: Stm32Error,
+        /// The product ID of the chip, such as 0x0410 for STM32F10x medium density parts.
+        pub product_id: u16,
+    }
+
+    pub struct Stm32ReadResult {
+        pub error: Stm32Error,
+        /// The memory read. Empty on error.
+        pub data: Vec<u8>,
+    }
+
+    pub struct Stm32Progress {
+        pub operation: Stm32Operation,
+        /// Bytes done so far, or pages for page erases.
+        pub bytes_done: u64,
+        /// Bytes in the whole operation, or pages for page erases.
+        pub bytes_total: u64,
+    }
+
+    pub struct RpcStats {
+        /// Requests sent to the device.
+        pub requests_sent: u64,
+        /// Requests received from the device, including ones without a handler.
+        pub requests_handled: u64,
+        /// Calls that timed out.
+        pub timeouts: u64,
+        /// Responses that matched no waiting call, such as late responses to calls that timed out.
+        pub unmatched: u64,
+        /// Frames that could not be decoded.
+        pub bad_frames: u64,
+    }
+
+    pub struct JsonStats {
+        /// Lines that parsed as JSON.
+        pub values: u64,
+        /// Lines that did not parse, which are dropped.
+        pub parse_errors: u64,
+    }
+
+    #[derive(Clone)]
+    pub struct TelemetryField {
+        /// The CSV header or key of this field.
+        pub name: String,
+        pub field_type: TelemetryFieldType,
+        /// Whether lines may leave this field out. Missing values are NaN in the record.
+        pub optional: bool,
+    }
+
+    #[derive(Clone)]
+    pub struct TelemetrySchema {
+        pub format: TelemetryFormat,
+        /// The byte between columns or key=value pairs, usually ','.
+        pub separator: u8,
+        /// The fields of each record. Records hold their values in this order.
+        pub fields: Vec<TelemetryField>,
+    }
+
+    pub struct TelemetryRecord {
+        /// The value of each field, in schema order. Int fields are exact up to 2^53.
+        pub values: Vec<f64>,
+    }
+
+    pub struct TelemetryStats {
+        /// Lines parsed into records.
+        pub records: u64,
+        /// Lines that did not follow the schema, which are dropped.
+        pub malformed: u64,
+    }
+
+    pub struct FrameReadResult {
+        /// The error this read produced, if any. Timeout if no complete frame arrived in time.
+        pub error: SerialError,
+        /// The decoded frame. Empty on error.
+        pub data: Vec<u8>,
+        /// The number of bad frames skipped before this one.
+        pub dropped: u32,
+    }
+
+    pub struct KissFrameResult {
+        /// The error this read produced, if any. Timeout if no complete frame arrived in time.
+        pub error: SerialError,
+        /// The TNC port, from 0 to 15.
+        pub port: u8,
+        /// The command, from 0 to 15, or 255 for return. Data frames are command 0.
+        pub command: u8,
+        /// The payload, without its type byte. Empty on error.
+        pub data: Vec<u8>,
+        /// The number of bad frames skipped before this one.
+        pub dropped: u32,
+    }
+
+    #[derive(Clone)]
+    pub struct LengthFraming {
+        /// Bytes every frame starts with. May be empty, though then the framer cannot resynchronize
+        /// after a bad frame.
+        pub sync: Vec<u8>,
+        /// Offset of the length field from the start of the frame, counting the sync bytes. Any bytes
+        /// between the sync and the length field are header fields, and are kept in the frame.
+        pub length_offset: u32,
+        /// Width of the length field, from 1 to 4 bytes. The payload starts right after it.
+        pub length_width: u8,
+        /// Whether the length field and multi-byte checksums are big endian, rather than little endian.
+        pub big_endian: bool,
+        /// Whether the length counts the header, from the first sync byte to the end of the length field,
+        /// as well as the payload. The checksum is never counted.
+        pub length_includes_header: bool,
+        /// The checksum following the payload.
+        pub checksum: FrameChecksum,
+        /// Offset from the start of the frame where the checksum starts. It covers everything from there
+        /// to the end of the payload.
+        pub checksum_offset: u32,
+    }
+
+    pub struct FrameStats {
+        /// Frames decoded and passed to the frame callback.
+        pub frames: u64,
+        /// Frames dropped because they could not be decoded.
+        pub malformed: u64,
+        /// Frames dropped because they were longer than the maximum frame size.
+        pub too_long: u64,
+        /// Frames dropped because they failed their checksum.
+        pub bad_checksums: u64,
+    }
+
     pub enum SerialError {
         /// The operation succeeded.
         NoErr = 0,
@@ -27,6 +588,256 @@ pub mod ffi {
         PortIOErr,
         /// Uncategorized error.
         Other,
+        /// The write queue is full. Nothing was queued.
+        QueueFull,
+    }
+
+    pub enum WritePriority {
+        /// Bulk transfers, written only when nothing else is waiting.
+        Low,
+        /// The default for queued writes.
+        Normal,
+        /// Urgent messages, written as soon as the current message completes.
+        High,
+    }
+
+    pub enum MatchKind {
+        /// The line must equal the pattern.
+        Exact,
+        /// The line must start with the pattern.
+        Prefix,
+        /// The pattern is a regex that must match somewhere in the line.
+        Regex,
+    }
+
+    pub enum AtStatus {
+        /// The command returned OK.
+        Ok,
+        /// The command returned ERROR.
+        Error,
+        /// The command returned +CME ERROR, see the response code.
+        CmeError,
+        /// The command returned +CMS ERROR, see the response code.
+        CmsError,
+        /// No final result code was received.
+        NoResult,
+        /// The command returned CONNECT, and the modem is now online. See the response code for the
+        /// rate, if one was given.
+        Connect,
+        /// The command returned NO CARRIER, the connection failed or was lost.
+        NoCarrier,
+        /// The command returned BUSY.
+        Busy,
+        /// The command returned NO ANSWER.
+        NoAnswer,
+        /// The command returned NO DIALTONE.
+        NoDialtone,
+    }
+
+    pub enum ModbusError {
+        /// The request succeeded.
+        NoErr = 0,
+        /// Exception 1, the device does not support this function.
+        IllegalFunction = 1,
+        /// Exception 2, the address range is not valid on the device.
+        IllegalDataAddress = 2,
+        /// Exception 3, a value in the request is not valid on the device.
+        IllegalDataValue = 3,
+        /// Exception 4, the device failed while handling the request.
+        ServerDeviceFailure = 4,
+        /// Exception 5, the device accepted a long running request.
+        Acknowledge = 5,
+        /// Exception 6, the device is busy with a long running request.
+        ServerDeviceBusy = 6,
+        /// Exception 8, the device found a parity error in its memory.
+        MemoryParityError = 8,
+        /// Exception 10, the gateway has no path to the target.
+        GatewayPathUnavailable = 10,
+        /// Exception 11, the gateways target did not respond.
+        GatewayTargetFailedToRespond = 11,
+        /// The device returned an exception code not listed here.
+        UnknownException,
+        /// No complete response arrived in time.
+        Timeout,
+        /// The response failed its CRC check.
+        CrcMismatch,
+        /// The response was well formed but did not answer the request.
+        InvalidResponse,
+        /// The request was not sent, as its quantity or unit was out of range.
+        InvalidRequest,
+        /// The port failed while writing or reading.
+        PortErr,
+    }
+
+    pub enum ModbusFraming {
+        /// Binary frames delimited by 3.5 characters of silence, checked with CRC-16.
+        Rtu,
+        /// Hex encoded frames between `:` and CR LF, checked with an LRC.
+        Ascii,
+    }
+
+    pub enum ModbusTable {
+        Coils,
+        DiscreteInputs,
+        HoldingRegisters,
+        InputRegisters,
+    }
+
+    pub enum FrameError {
+        /// The frame could not be decoded, such as a bad escape or length.
+        Malformed,
+        /// The frame was longer than the maximum frame size. The rest of it is skipped.
+        TooLong,
+        /// The frame failed its checksum.
+        BadChecksum,
+    }
+
+    pub enum HdlcFcs {
+        /// CRC-16/X-25, PPP's default 16 bit FCS.
+        Crc16,
+        /// CRC-32, PPP's 32 bit FCS.
+        Crc32,
+    }
+
+    pub enum ChecksumKind {
+        /// CRC-8/SMBUS, polynomial 0x07, initial value 0.
+        Crc8,
+        /// CRC-16/MODBUS, polynomial 0x8005 reflected, initial value 0xFFFF. Sent low byte first.
+        Crc16Modbus,
+        /// CRC-16/CCITT-FALSE, polynomial 0x1021, initial value 0xFFFF.
+        Crc16Ccitt,
+        /// CRC-16/XMODEM, polynomial 0x1021, initial value 0. Sent high byte first.
+        Crc16Xmodem,
+        /// CRC-16/X-25, the FCS-16 of PPP and HDLC. Sent low byte first.
+        Crc16X25,
+        /// CRC-32 as used by Ethernet and zlib.
+        Crc32,
+        /// Fletcher-16, two sums modulo 255 with the second in the high byte.
+        Fletcher16,
+        /// XOR of all bytes.
+        Xor,
+        /// Sum of all bytes, modulo 256.
+        Sum8,
+        /// The XOR checksum of NMEA 0183 sentences. A leading `$` or `!` is skipped, and everything
+        /// from the `*` on is ignored, so whole sentences may be passed.
+        Nmea,
+    }
+
+    pub enum FrameChecksum {
+        None,
+        /// XOR of all bytes.
+        Xor,
+        /// Sum of all bytes, modulo 256.
+        Sum8,
+        /// CRC-8/SMBUS, polynomial 0x07.
+        Crc8,
+        /// CRC-16/MODBUS, polynomial 0x8005 reflected, initial value 0xFFFF.
+        Crc16Modbus,
+        /// CRC-16/CCITT-FALSE, polynomial 0x1021, initial value 0xFFFF.
+        Crc16Ccitt,
+        /// CRC-16/XMODEM, polynomial 0x1021, initial value 0.
+        Crc16Xmodem,
+        /// CRC-32 as used by Ethernet and zlib.
+        Crc32,
+    }
+
+    pub enum RpcEncoding {
+        /// Payloads are passed through as bytes.
+        Raw,
+        /// Payloads are JSON text, and sent as compact JSON.
+        Json,
+        /// Payloads are encoded CBOR, checked to be one well formed item and sent unchanged.
+        Cbor,
+    }
+
+    pub enum RpcStatus {
+        Ok,
+        /// No response arrived before the calls timeout.
+        Timeout,
+        /// The device responded with an error, or has no handler for the method.
+        RemoteError,
+        /// The request or response payload is not valid in the endpoints encoding.
+        EncodingError,
+        /// The request could not be written.
+        PortErr,
+        /// The endpoint was stopped before a response arrived.
+        Stopped,
+    }
+
+    pub enum TransferProtocol {
+        /// XMODEM-CRC, with 128 byte blocks.
+        Xmodem,
+        /// XMODEM-1K, with 1024 byte blocks. The end of a file may still use a 128 byte block.
+        Xmodem1k,
+        /// YMODEM batch transfers, which add names and sizes to XMODEM-1K, and send several files.
+        Ymodem,
+    }
+
+    pub enum TransferStatus {
+        /// The transfer has not been started.
+        Idle,
+        /// The transfer is still running.
+        Running,
+        /// Every file was transferred.
+        Done,
+        /// The transfer was cancelled on this side.
+        Cancelled,
+        /// The peer cancelled the transfer.
+        RemoteCancelled,
+        /// A block, or the start of the transfer, failed more than max_retries times.
+        TooManyRetries,
+        /// The peer broke the protocol, such as by skipping a block.
+        ProtocolError,
+        /// The port failed.
+        PortErr,
+    }
+
+    pub enum ControlLine {
+        None,
+        /// Data Terminal Ready.
+        Dtr,
+        /// Request To Send.
+        Rts,
+    }
+
+    pub enum Stm32Error {
+        NoErr,
+        /// The bootloader refused the command, such as for a protected or missing address.
+        Nack,
+        /// No reply arrived in time.
+        Timeout,
+        /// The reply was neither ACK nor NACK, or had the wrong length.
+        InvalidResponse,
+        /// The request cannot be sent, such as an empty erase or an address range that wraps.
+        InvalidRequest,
+        /// Memory read back after writing did not match the image.
+        VerifyFailed,
+        /// The image could not be parsed.
+        InvalidImage,
+        /// The port failed.
+        PortErr,
+    }
+
+    pub enum Stm32Operation {
+        Erase,
+        Write,
+        Verify,
+        Read,
+    }
+
+    pub enum TelemetryFormat {
+        /// One column per field, in schema order, such as `123,21.5,40`. A header line naming the
+        /// fields is skipped.
+        Csv,
+        /// key=value pairs in any order, such as `t=123,temp=21.5,hum=40`.
+        KeyValue,
+    }
+
+    pub enum TelemetryFieldType {
+        /// A decimal integer.
+        Int,
+        /// A decimal or scientific notation number, including nan and inf.
+        Float,
     }
 
     pub enum CharSize {
@@ -63,6 +874,56 @@ pub mod ffi {
         /// - Other - Any other kind of device failure, such as a disconnect.
         fn write(self: &mut Serial, data: &[u8]) -> SerialError;
 
+        /// COBS encodes data and writes it as a single frame, followed by a 0x00 delimiter.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn write_frame(self: &mut Serial, data: &[u8]) -> SerialError;
+
+        /// Writes data as a single SLIP (RFC 1055) frame, with END bytes before and after it.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn write_slip_frame(self: &mut Serial, data: &[u8]) -> SerialError;
+
+        /// Writes data as a single KISS frame to a TNC. Port must be between 0 and 15, and command
+        /// between 0 and 15, or 255 to take the TNC out of KISS mode. Use command 0 for data frames.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+        /// - Other - The port or command is out of range, or any other kind of device failure.
+        fn write_kiss_frame(self: &mut Serial, port: u8, command: u8, data: &[u8]) -> SerialError;
+
+        /// Writes data as a single HDLC-like frame, as PPP uses on async links. The FCS is appended,
+        /// flag, escape and control bytes are escaped, and the frame is wrapped in 0x7E flags.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn write_hdlc_frame(self: &mut Serial, data: &[u8], fcs: HdlcFcs) -> SerialError;
+
+        /// Writes json as a single compact line, followed by \n. Any formatting in json, including
+        /// newlines, is removed so the value stays on one line.
+        ///
+        /// This function will throw if json is not a single valid JSON value.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn write_json(self: &mut Serial, json: &str) -> Result<SerialError>;
+
         /// Attempts to write the entire string to the serial device.
         ///
         /// Errors
@@ -72,6 +933,40 @@ pub mod ffi {
         /// - Other - Any other kind of device failure, such as a disconnect.
         fn write_str(self: &mut Serial, data: &CxxString) -> SerialError;
 
+        /// Returns the number of queued writes that have not completed yet, including any being written.
+        ///
+        /// Writes are queued with [serialcxx::write_async].
+        fn queue_depth(self: &Serial) -> usize;
+
+        /// Sets the maximum number of writes waiting at each priority. Defaults to 64.
+        fn set_queue_capacity(self: &Serial, messages: usize);
+
+        /// Blocks until all queued writes have completed, waiting at most deadline seconds.
+        /// A negative or infinite deadline waits forever. Called from a write completion callback, this
+        /// returns false at once, as the callbacks own message is not complete until it returns.
+        ///
+        /// Returns true if the queue emptied before the deadline.
+        fn flush_queue(self: &Serial, deadline: f32) -> bool;
+
+        /// Starts writing data to the port every interval seconds, beginning immediately.
+        ///
+        /// Writes go through the same lock as [Serial::write], so they never interleave with other writes.
+        /// The schedule keeps a steady rate, skipping ticks rather than bursting if a write overruns.
+        /// The schedule ends when the returned handle is cancelled or destroyed.
+        ///
+        /// This function will throw if the interval is not a positive number of seconds.
+        fn schedule_periodic(
+            self: &Serial,
+            data: &[u8],
+            interval: f32,
+        ) -> Result<Box<PeriodicWrite>>;
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this port
+        /// to the free functions that take callbacks, such as [serialcxx::write_async].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut Serial) -> *mut Serial;
+
         /// Attempts to read the remaining serial device's buffer, up to the size of the passed slice.
         /// Return struct includes the number of bytes read into this buffer.
         ///
@@ -95,15 +990,198 @@ pub mod ffi {
         /// - Other - Any other kind of device failure, such as a disconnect.
         fn read_line(self: &mut Serial, read_buff: Pin<&mut CxxString>) -> ReadResult;
 
+        /// Writes command, then reads lines until one matches, waiting at most deadline seconds.
+        ///
+        /// The matcher compares each line, without its terminator, against pattern either exactly, by
+        /// prefix, or as a regex that may match anywhere in the line. Lines that do not match are collected
+        /// in the result, in the order they arrived. Transactions are serialized, so concurrent calls
+        /// will each receive their own reply. A negative or infinite deadline waits forever.
+        ///
+        /// Like other reads, this blocks while a listener is alive.
+        ///
+        /// This function will throw if pattern is not a valid regex.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - No matching line arrived before the deadline.
+        /// - Interrupted - The device transfer was interrupted. You may retry this transaction.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn transact(
+            self: &Serial,
+            command: &CxxString,
+            kind: MatchKind,
+            pattern: &str,
+            deadline: f32,
+        ) -> Result<TransactResult>;
+
+        /// Reads from the port until one of patterns matches, waiting at most deadline seconds.
+        ///
+        /// Each pattern is a regex. Unlike [Serial::read_line], this matches raw data as it arrives, so it
+        /// can wait for prompts such as `login: ` that do not end in a newline. If several patterns match,
+        /// the one whose match ends first wins. Only data up to the end of the match is consumed, so output
+        /// that follows the match is left for the next read. A negative or infinite deadline waits forever.
+        ///
+        /// The result holds the index of the matching pattern, the text received before the match, and the
+        /// matched text itself. On failure the index is -1, and the text before holds everything received.
+        ///
+        /// Like other reads, this blocks while a listener is alive.
+        ///
+        /// This function will throw if any pattern is not a valid regex.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - No pattern matched before the deadline.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn expect(
+            self: &Serial,
+            patterns: &CxxVector<CxxString>,
+            deadline: f32,
+        ) -> Result<ExpectResult>;
+
+        /// Reads the next SLIP frame, waiting at most deadline seconds. A negative or infinite deadline
+        /// waits forever.
+        ///
+        /// Only input up to the end of the frame is consumed. Malformed frames are skipped, and counted
+        /// in the result. Like other reads, this blocks while a listener is alive.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - No complete frame arrived before the deadline. A partial frame is lost.
+        /// - Other - Any other kind of device failure, such as a disconnect.
+        fn read_slip_frame(self: &Serial, deadline: f32) -> FrameReadResult;
+
+        /// Reads the next KISS frame, waiting at most deadline seconds. Same as [read_slip_frame],
+        /// but the type byte is split into the port and command.
+        fn read_kiss_frame(self: &Serial, deadline: f32) -> KissFrameResult;
+
+        /// Reads the next HDLC-like frame, waiting at most deadline seconds, and returns it without
+        /// its FCS. Same as [read_slip_frame], but frames failing their FCS are also skipped and counted.
+        fn read_hdlc_frame(self: &Serial, fcs: HdlcFcs, deadline: f32) -> FrameReadResult;
+
+        /// Reads the next length-prefixed frame laid out as config describes, waiting at most deadline
+        /// seconds. The frame is returned without its sync bytes and checksum.
+        ///
+        /// Same as [read_slip_frame], but frames that are too short for their header, have a payload
+        /// over 4096 bytes, or fail their checksum are skipped and counted. This function will throw if
+        /// the config is invalid.
+        fn read_length_frame(
+            self: &Serial,
+            config: &LengthFraming,
+            deadline: f32,
+        ) -> Result<FrameReadResult>;
+
+        /// Creates an AT command client on this port, and starts its reader thread.
+        ///
+        /// The client holds the read handle for as long as it lives, exactly like a listener, so other
+        /// reads on this port will block until it is destroyed or stopped.
+        fn create_at_client(self: &Serial) -> Box<AtClient>;
+
+        /// Creates a u-blox UBX client on this port, and starts its reader thread.
+        ///
+        /// The client holds the read handle for as long as it lives, exactly like a listener, so other
+        /// reads on this port will block until it is destroyed or stopped.
+        fn create_ubx_client(self: &Serial) -> Box<UbxClient>;
+
+        /// Creates a reliable link on this port, and starts its reader and retransmit threads.
+        ///
+        /// The other end of the port must run a reliable link too. Either end may be restarted, and
+        /// the other resynchronizes with it, though a message in flight at the time may be delivered
+        /// both before and after the restart. Like listeners, the link holds the read handle until it
+        /// is stopped. This function will throw if the window or timeout in config is invalid.
+        fn create_reliable_link(self: &Serial, config: ArqConfig) -> Result<Box<ReliableLink>>;
+
+        /// Creates a channel mux on this port, and starts its reader and writer threads.
+        ///
+        /// The other end of the port must speak the same framing, see [ChannelMux]. Like listeners,
+        /// the mux holds the read handle until it is stopped.
+        fn create_channel_mux(self: &Serial) -> Box<ChannelMux>;
+
+        /// Creates an RPC endpoint on this port, and starts its reader and timeout threads.
+        ///
+        /// The other end of the port must speak the same protocol, see [RpcEndpoint]. Like listeners,
+        /// the endpoint holds the read handle until it is stopped.
+        fn create_rpc_endpoint(self: &Serial, encoding: RpcEncoding) -> Box<RpcEndpoint>;
+
+        /// Creates a client for the STM32 ROM bootloader (AN3155) on this port. Call
+        /// [Stm32Bootloader::connect] before any other command.
+        ///
+        /// The bootloader expects 8 data bits with even parity, so set [Serial::set_parity] to Even
+        /// first. This function will throw if config uses one line for both pins, or a timeout is not
+        /// positive and finite.
+        fn create_stm32_bootloader(
+            self: &Serial,
+            config: &Stm32Config,
+        ) -> Result<Box<Stm32Bootloader>>;
+
+        /// Creates an XMODEM or YMODEM file transfer on this port. The transfer is idle until it is
+        /// started with [FileTransfer::start_send] or [FileTransfer::start_receive].
+        ///
+        /// This function will throw if the protocol is unknown, or the timeout is not positive and finite.
+        fn create_file_transfer(
+            self: &Serial,
+            config: &TransferConfig,
+        ) -> Result<Box<FileTransfer>>;
+
+        /// Creates a Modbus RTU master that uses this port as its transport.
+        ///
+        /// Frame timing is derived from the ports baud rate and character format at the time of each
+        /// request, so settings may be changed after this call.
+        fn create_modbus_master(self: &Serial) -> Box<ModbusMaster>;
+
+        /// Creates a Modbus server that answers requests for config.unit on this port. The server is idle
+        /// until it is started with [ModbusServer::listen].
+        ///
+        /// This function will throw if the unit is not between 1 and 247.
+        fn create_modbus_server(
+            self: &Serial,
+            config: &ModbusServerConfig,
+        ) -> Result<Box<ModbusServer>>;
+
         /// Attempts to open the serial device at path, using the specified baud rate.
         /// Defaults to a timeout of 99999 seconds.
         fn open_port(path: &str, baud: u32) -> Result<Box<Serial>>;
 
-        /// Sets the timeout for this port.
+        /// Sets the timeout for this port, applying it to both reads and writes.
+        ///
+        /// A negative or infinite value disables the timeout.
         ///
         /// Returns true if the operation succeeded.
         pub fn set_timeout(self: &mut Serial, sec: f32) -> bool;
 
+        /// Sets the timeout for reads only. This also applies to any listeners on this port.
+        ///
+        /// A negative or infinite value disables the timeout.
+        ///
+        /// Returns true if the operation succeeded.
+        pub fn set_read_timeout(self: &mut Serial, sec: f32) -> bool;
+
+        /// Sets the timeout for writes only.
+        ///
+        /// A negative or infinite value disables the timeout.
+        ///
+        /// Returns true if the operation succeeded.
+        pub fn set_write_timeout(self: &mut Serial, sec: f32) -> bool;
+
+        /// Sets the termios read semantics of this port, VMIN and VTIME.
+        ///
+        /// Once the first byte has arrived within the ports timeout, reads will block until either
+        /// `min_bytes` bytes are available, or no byte has been received for `inter_char_timeout` seconds.
+        /// `inter_char_timeout` has a resolution of 0.1 seconds, and is clamped to 25.5 seconds.
+        /// Setting both to 0 returns whatever is available as soon as the port becomes readable.
+        ///
+        /// A nonzero `min_bytes` with an `inter_char_timeout` that rounds to 0 is refused, as reads would
+        /// then block until `min_bytes` arrive, ignoring every timeout and deadline.
+        ///
+        /// Returns true if the operation succeeded. This always fails on platforms without termios.
+        pub fn set_read_semantics(
+            self: &mut Serial,
+            min_bytes: u8,
+            inter_char_timeout: f32,
+        ) -> bool;
+
         /// Sets the character size of this port.
         ///
         /// Returns true if the operation succeeded.
@@ -131,6 +1209,587 @@ pub mod ffi {
         pub fn set_flow_control(self: &mut Serial, mode: FlowControl) -> bool;
     }
 
+    extern "Rust" {
+        /// Parses an Intel HEX file. Extended segment and linear address records are applied, and
+        /// start address records set the entry point. Parsing stops at the end of file record.
+        ///
+        /// This function will throw with the line number if a line is not a record, has a bad
+        /// checksum or length, or is of an unknown type.
+        fn parse_intel_hex(text: &str) -> Result<FirmwareImage>;
+
+        /// Writes image as an Intel HEX file with 16 byte records, in address order. The entry point is
+        /// written as a start linear address record.
+        ///
+        /// This function will throw if a segment runs past the end of the 32 bit address space.
+        fn write_intel_hex(image: &FirmwareImage) -> Result<String>;
+
+        /// Parses a Motorola S-record file. S1 to S3 records hold data, S5 and S6 are checked against
+        /// the number of data records, and S7 to S9 set the entry point and end the file.
+        ///
+        /// This function will throw with the line number if a line is not a record, has a bad
+        /// checksum or length, is of an unknown type, or the count does not match.
+        fn parse_srec(text: &str) -> Result<FirmwareImage>;
+
+        /// Writes image as an S-record file with 16 byte records, in address order, using the
+        /// narrowest record type that fits every address. Images without an entry point end with
+        /// address 0.
+        ///
+        /// This function will throw if a segment runs past the end of the 32 bit address space.
+        fn write_srec(image: &FirmwareImage) -> Result<String>;
+
+        /// Finds every address range covered by more than one segment of image, in address order.
+        fn find_overlaps(image: &FirmwareImage) -> Vec<SegmentOverlap>;
+
+        /// Copies image into one segment spanning its lowest to highest address, with fill in the
+        /// gaps. The result is ready to write with [Stm32Bootloader::flash_binary] or send with
+        /// [FileTransfer::add_file].
+        ///
+        /// This function will throw if segments overlap, or the result would be larger than 64 MiB.
+        fn flatten_image(image: &FirmwareImage, fill: u8) -> Result<MemorySegment>;
+    }
+
+    extern "Rust" {
+        /// A checksum computed over data passed to it in any number of pieces. These are the same
+        /// implementations the framing codecs use.
+        type Checksum;
+
+        /// Computes the checksum of data in one call.
+        ///
+        /// This function will throw if kind is not known.
+        fn compute_checksum(kind: ChecksumKind, data: &[u8]) -> Result<u32>;
+
+        /// Starts a streaming checksum of kind. Feed it with [Checksum::update].
+        ///
+        /// This function will throw if kind is not known.
+        fn new_checksum(kind: ChecksumKind) -> Result<Box<Checksum>>;
+
+        /// Gets the number of bytes in a checksum of kind, or 0 if kind is not known.
+        fn checksum_size(kind: ChecksumKind) -> usize;
+
+        /// Continues the checksum over data.
+        fn update(self: &mut Checksum, data: &[u8]);
+
+        /// Gets the checksum of all data passed so far, in the low bytes. More data may still be added.
+        fn value(self: &Checksum) -> u32;
+
+        /// Restarts the checksum, as if no data had been passed to it.
+        fn reset(self: &mut Checksum);
+
+        /// Gets the algorithm of this checksum.
+        fn kind(self: &Checksum) -> ChecksumKind;
+    }
+
+    extern "Rust" {
+        /// A message being written to a port at a fixed interval.
+        type PeriodicWrite;
+
+        /// Stops the schedule. A write already in progress will complete, but no more will start.
+        ///
+        /// Destroying the handle does the same.
+        fn cancel(self: &PeriodicWrite);
+
+        /// Changes the interval between writes. The next write happens one new interval after the
+        /// previous one.
+        ///
+        /// Returns false if the interval is not a positive number of seconds.
+        fn set_interval(self: &PeriodicWrite, interval: f32) -> bool;
+
+        /// Returns the result of the most recent write.
+        fn last_error(self: &PeriodicWrite) -> SerialError;
+    }
+
+    extern "Rust" {
+        /// A client for modems that speak Hayes AT commands.
+        type AtClient;
+
+        /// Sends command, followed by a carriage return, and waits up to timeout seconds for its final
+        /// result code: OK, ERROR, +CME ERROR, +CMS ERROR, or one of the V.250 call results CONNECT,
+        /// NO CARRIER, BUSY, NO ANSWER and NO DIALTONE. A negative or infinite timeout waits forever.
+        ///
+        /// Commands are sent one at a time, concurrent calls will wait their turn. Echoed commands are
+        /// dropped. Unsolicited result codes that arrive while waiting, such as `RING`, are passed to
+        /// the URC callback instead of being added to the response. Information responses that share
+        /// the commands own prefix, like `+CREG:` in reply to `AT+CREG?`, stay in the response. While
+        /// no command is waiting, lines that are not URCs, such as the late result of a command that
+        /// timed out, are dropped.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - No final result code arrived in time.
+        /// - Other - The command could not be written, such as after a disconnect.
+        fn send(self: &AtClient, command: &str, timeout: f32) -> AtResponse;
+
+        /// Routes lines starting with prefix to the URC callback, unless they answer the pending command.
+        ///
+        /// Common codes such as `RING`, `+CREG:` and `+CMTI:` are registered by default.
+        fn add_urc_prefix(self: &AtClient, prefix: &str);
+
+        /// Stops the reader thread, releasing the read handle once its current read times out.
+        /// Destroying the client does the same. No more commands will complete after this.
+        fn stop(self: &AtClient);
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this client
+        /// to [serialcxx::set_urc_callback].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut AtClient) -> *mut AtClient;
+    }
+
+    extern "Rust" {
+        /// A client for u-blox GNSS receivers that mix UBX binary messages into their NMEA output.
+        ///
+        /// Frames start with the sync bytes 0xB5 0x62 and are checked with their Fletcher checksum.
+        /// Everything between frames is passed on as lines of text, so NMEA keeps working alongside UBX.
+        type UbxClient;
+
+        /// Sends a UBX message with the given class, id and payload, and waits up to timeout seconds
+        /// for the receiver to ACK or NAK it. A negative or infinite timeout waits forever.
+        ///
+        /// Receivers only acknowledge configuration (CFG) messages, so other messages will time out.
+        /// Messages are sent one at a time, concurrent calls will wait their turn.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - No ACK or NAK arrived in time.
+        /// - Other - The message could not be written, or its payload is over 65535 bytes.
+        fn send_ubx(
+            self: &UbxClient,
+            msg_class: u8,
+            msg_id: u8,
+            payload: &[u8],
+            timeout: f32,
+        ) -> UbxAckResult;
+
+        /// Gets the frame counters of this client.
+        fn stats(self: &UbxClient) -> UbxStats;
+
+        /// Stops the reader thread, releasing the read handle once its current read times out.
+        /// Destroying the client does the same. No more messages will be acknowledged after this.
+        fn stop(self: &UbxClient);
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this client
+        /// to [serialcxx::set_ubx_message_callback] and friends.
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut UbxClient) -> *mut UbxClient;
+    }
+
+    extern "Rust" {
+        /// Reliable, in-order message delivery over a noisy link, with retransmission of lost or
+        /// corrupted frames.
+        ///
+        /// Messages are sent in HDLC-like frames checked with CRC-16, numbered, and acknowledged by the
+        /// other end. Up to a window of messages are kept in flight. If the oldest is not acknowledged
+        /// before the retransmit timeout, it and every message after it are sent again. Duplicates are
+        /// dropped, so each message is delivered once.
+        type ReliableLink;
+
+        /// Queues a message for delivery and sends it, waiting up to timeout seconds for room in the
+        /// window. A negative or infinite timeout waits forever. Returning NoErr does not mean the
+        /// message was delivered, use [flush] for that.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - The window stayed full.
+        /// - Other - The link failed or was stopped, or the message is over 4096 bytes.
+        fn send_reliable(self: &ReliableLink, data: &[u8], timeout: f32) -> SerialError;
+
+        /// Waits up to timeout seconds for every queued message to be acknowledged.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - Some messages are still unacknowledged.
+        /// - Other - The link failed or was stopped first.
+        fn flush(self: &ReliableLink, timeout: f32) -> SerialError;
+
+        /// Checks if a message ran out of retries. A failed link sends nothing more, and must be
+        /// recreated on both ends.
+        fn has_failed(self: &ReliableLink) -> bool;
+
+        /// Gets the counters of this link.
+        fn stats(self: &ReliableLink) -> ArqStats;
+
+        /// Stops the link, releasing the read handle once its current read times out. Destroying the
+        /// link does the same. Messages still in flight are abandoned.
+        fn stop(self: &ReliableLink);
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this link
+        /// to [serialcxx::set_reliable_delivery_callback].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut ReliableLink) -> *mut ReliableLink;
+    }
+
+    extern "Rust" {
+        /// Splits one port into up to 256 logical channels, such as a console, telemetry and commands
+        /// to a co-processor.
+        ///
+        /// Data is sent in COBS frames, each starting with the id of its channel. Writes are split into
+        /// chunks of at most 256 bytes, and channels with data queued take turns writing a chunk, so
+        /// one busy channel cannot starve the others.
+        type ChannelMux;
+
+        /// Opens the endpoint for channel id. Data received for a channel that is not open is dropped.
+        ///
+        /// This function will throw if the channel is already open.
+        fn open_channel(self: &ChannelMux, id: u8) -> Result<Box<Channel>>;
+
+        /// Gets the counters of this mux.
+        fn stats(self: &ChannelMux) -> MuxStats;
+
+        /// Stops the mux, releasing the read handle once its current read times out. Destroying the
+        /// mux does the same. Reads and writes on its channels will fail after this.
+        fn stop(self: &ChannelMux);
+    }
+
+    extern "Rust" {
+        /// One logical channel of a [ChannelMux], which can be used like a port of its own.
+        ///
+        /// Destroying the channel closes it, so its id can be opened again.
+        type Channel;
+
+        /// Gets the id of this channel.
+        fn id(self: &Channel) -> u8;
+
+        /// Writes data to this channel, waiting until all of it has been written to the port.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Interrupted - The device transfer was interrupted. Part of the data may have been sent.
+        /// - Other - The mux was stopped, or any other kind of device failure, such as a disconnect.
+        fn write(self: &Channel, data: &[u8]) -> SerialError;
+
+        /// Reads data received on this channel, up to the size of the passed slice. Waits up to the
+        /// channels read timeout for data to arrive.
+        ///
+        /// Errors
+        /// ------
+        ///
+        /// - Timeout - No data arrived in time.
+        /// - Other - The mux was stopped.
+        fn read(self: &Channel, read_buff: &mut [u8]) -> ReadResult;
+
+        /// Reads a line received on this channel. Same as [Serial::read_line], but waits up to the
+        /// channels read timeout.
+        fn read_line(self: &Channel, read_buff: Pin<&mut CxxString>) -> ReadResult;
+
+        /// Sets the timeout for reads on this channel. A negative or infinite value, the default,
+        /// disables the timeout.
+        fn set_read_timeout(self: &Channel, sec: f32);
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this channel
+        /// to [serialcxx::set_channel_callback].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut Channel) -> *mut Channel;
+    }
+
+    extern "Rust" {
+        /// Request/response RPC over a port, in both directions.
+        ///
+        /// Each request carries a correlation id, which its response repeats, so any number of calls
+        /// may be in flight at once and responses may arrive in any order. Requests from the device are
+        /// passed to the handler registered for their method with [serialcxx::set_rpc_handler].
+        ///
+        /// Messages are COBS frames holding a kind byte (1 request, 2 response, 3 error), a little
+        /// endian u32 id, a method name length byte, the method name, and the payload. Responses have
+        /// no method name, and error payloads are UTF-8 messages.
+        ///
+        /// With JSON encoding, payloads are always JSON text on the C++ side. With CBOR encoding they are
+        /// encoded CBOR, passed through unchanged. With either, an empty payload stands for null.
+        type RpcEndpoint;
+
+        /// Calls method on the device, waiting up to timeout seconds for its response. A negative or
+        /// infinite timeout waits forever. Calls from several threads are in flight at once.
+        ///
+        /// Do not call this from a handler or an async callback, which run on the reader thread, as the
+        /// response could not be read until the call times out.
+        fn call(self: &RpcEndpoint, method: &str, payload: &[u8], timeout: f32) -> RpcResult;
+
+        /// Gets the number of calls waiting for a response.
+        fn in_flight(self: &RpcEndpoint) -> usize;
+
+        /// Gets the counters of this endpoint.
+        fn stats(self: &RpcEndpoint) -> RpcStats;
+
+        /// Stops the endpoint, releasing the read handle once its current read times out. Destroying
+        /// the endpoint does the same. Calls still waiting return Stopped.
+        fn stop(self: &RpcEndpoint);
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this endpoint
+        /// to [serialcxx::rpc_call_async] and [serialcxx::set_rpc_handler].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut RpcEndpoint) -> *mut RpcEndpoint;
+    }
+
+    extern "Rust" {
+        /// A client for the STM32 system memory bootloader over USART, following ST AN3155.
+        ///
+        /// Commands are sent one at a time, and each holds the ports read handle until its reply arrives.
+        /// This cannot be used while a listener is alive on the port. Progress of long operations is
+        /// reported to the callback set with [serialcxx::set_stm32_progress_callback].
+        type Stm32Bootloader;
+
+        /// Resets the chip into its bootloader using the configured lines, if any, then syncs with
+        /// 0x7F and asks the bootloader for its commands.
+        fn connect(self: &Stm32Bootloader) -> Stm32Error;
+
+        /// Releases BOOT0 and pulses reset, so the chip starts its application. Returns
+        /// InvalidRequest if there is no reset line.
+        fn reset_to_application(self: &Stm32Bootloader) -> Stm32Error;
+
+        /// Gets the product ID of the chip.
+        fn get_id(self: &Stm32Bootloader) -> Stm32IdResult;
+
+        /// Reads len bytes of memory starting at address, 256 bytes per command.
+        fn read_memory(self: &Stm32Bootloader, address: u32, len: usize) -> Stm32ReadResult;
+
+        /// Writes data to memory starting at address, 256 bytes per command. Flash must be erased
+        /// first. Writes are aligned to 4 byte words, padding partial words at either end with 0xFF.
+        fn write_memory(self: &Stm32Bootloader, address: u32, data: &[u8]) -> Stm32Error;
+
+        /// Erases the given flash pages. Page numbers above 255 need a bootloader with Extended Erase.
+        fn erase_pages(self: &Stm32Bootloader, pages: &[u16]) -> Stm32Error;
+
+        /// Erases all of flash.
+        fn mass_erase(self: &Stm32Bootloader) -> Stm32Error;
+
+        /// Jumps to the code at address. The bootloader stops answering once it succeeds.
+        fn go(self: &Stm32Bootloader, address: u32) -> Stm32Error;
+
+        /// Writes a binary image at address, then reads it back and compares it if verify is set.
+        /// Flash must be erased first.
+        fn flash_binary(
+            self: &Stm32Bootloader,
+            address: u32,
+            data: &[u8],
+            verify: bool,
+        ) -> Stm32Error;
+
+        /// Writes an Intel HEX image, then reads it back and compares it if verify is set. Flash must
+        /// be erased first. Segments are aligned like [write_memory], and segments sharing a word are
+        /// written together. Returns InvalidImage if any segments overlap.
+        fn flash_hex(self: &Stm32Bootloader, hex: &str, verify: bool) -> Stm32Error;
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this client
+        /// to [serialcxx::set_stm32_progress_callback].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut Stm32Bootloader) -> *mut Stm32Bootloader;
+    }
+
+    extern "Rust" {
+        /// An XMODEM-CRC, XMODEM-1K or YMODEM file transfer, which runs once on its own thread.
+        ///
+        /// While running, the transfer holds the read handle exactly like a listener, so other reads on
+        /// this port will block until it finishes or is cancelled. Progress is reported to the callback
+        /// set with [serialcxx::set_transfer_progress_callback] after each block.
+        ///
+        /// Received files keep the padding of their last block with XMODEM, which does not send sizes,
+        /// and are cut to size with YMODEM.
+        type FileTransfer;
+
+        /// Queues a file to send. The name is only used by YMODEM.
+        ///
+        /// Returns false if the transfer has already started.
+        fn add_file(self: &FileTransfer, name: &str, data: &[u8]) -> bool;
+
+        /// Starts sending the queued files, waiting for the receiver to ask for them.
+        ///
+        /// This function will throw if the transfer was already started, no files are queued, more than
+        /// one file is queued for XMODEM, or a YMODEM file name is empty.
+        fn start_send(self: &FileTransfer) -> Result<()>;
+
+        /// Starts receiving files, asking the sender for them.
+        ///
+        /// This function will throw if the transfer was already started.
+        fn start_receive(self: &FileTransfer) -> Result<()>;
+
+        /// Cancels the transfer, telling the peer with CAN bytes. Destroying the transfer does the same.
+        fn cancel(self: &FileTransfer);
+
+        /// Waits up to timeout seconds for the transfer to finish. A negative or infinite timeout waits
+        /// forever. Returns Idle or Running if it has not finished.
+        fn wait(self: &FileTransfer, timeout: f32) -> TransferResult;
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this transfer
+        /// to [serialcxx::set_transfer_progress_callback].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut FileTransfer) -> *mut FileTransfer;
+    }
+
+    extern "Rust" {
+        /// The reply an RPC handler builds for a request from the device. Only valid until the handler
+        /// returns.
+        type RpcReply;
+
+        /// Replies with payload, in the same form [RpcEndpoint::call] takes. Invalid JSON or CBOR is sent
+        /// as an error instead.
+        fn set_result(self: &mut RpcReply, payload: &[u8]);
+
+        /// Replies with an error message instead of a result.
+        fn set_error(self: &mut RpcReply, message: &str);
+    }
+
+    extern "Rust" {
+        /// A Modbus RTU master (client) using a port as its transport.
+        ///
+        /// Requests are sent one at a time, 3.5 character times apart, and each waits for its response
+        /// while holding the ports read handle. This cannot be used while a listener is alive on the port.
+        /// Responses are checked for CRC, unit, function code and length, and exception responses are
+        /// reported as their matching error. Unit 0 broadcasts a write without waiting for a response,
+        /// reads cannot be broadcast.
+        type ModbusMaster;
+
+        /// Sets how long to wait for a response to arrive. Defaults to one second.
+        /// A negative or infinite value waits forever.
+        fn set_response_timeout(self: &ModbusMaster, sec: f32);
+
+        /// Reads count coils, function code 1. Each value is 0 or 1.
+        fn read_coils(self: &ModbusMaster, unit: u8, address: u16, count: u16) -> ModbusBitsResult;
+
+        /// Reads count discrete inputs, function code 2. Each value is 0 or 1.
+        fn read_discrete_inputs(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            count: u16,
+        ) -> ModbusBitsResult;
+
+        /// Reads count holding registers, function code 3.
+        fn read_holding_registers(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            count: u16,
+        ) -> ModbusRegistersResult;
+
+        /// Reads count input registers, function code 4.
+        fn read_input_registers(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            count: u16,
+        ) -> ModbusRegistersResult;
+
+        /// Writes a single coil, function code 5.
+        fn write_single_coil(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            value: bool,
+        ) -> ModbusError;
+
+        /// Writes a single holding register, function code 6.
+        fn write_single_register(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            value: u16,
+        ) -> ModbusError;
+
+        /// Writes consecutive coils, function code 15. Any non-zero value sets the coil.
+        fn write_multiple_coils(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            values: &[u8],
+        ) -> ModbusError;
+
+        /// Writes consecutive holding registers, function code 16.
+        fn write_multiple_registers(
+            self: &ModbusMaster,
+            unit: u8,
+            address: u16,
+            values: &[u16],
+        ) -> ModbusError;
+
+        /// Writes values starting at write_address, then reads read_count registers starting at
+        /// read_address, in one request. Function code 23.
+        fn read_write_registers(
+            self: &ModbusMaster,
+            unit: u8,
+            read_address: u16,
+            read_count: u16,
+            write_address: u16,
+            values: &[u16],
+        ) -> ModbusRegistersResult;
+    }
+
+    extern "Rust" {
+        /// A Modbus server (slave) that answers a master on the bus from a register map.
+        ///
+        /// Function codes 1 to 6, 15, 16 and 23 are supported, anything else is answered with an illegal
+        /// function exception. Requests outside the map are answered with an illegal data address
+        /// exception. The map can be read and written from C++ at any time, including while listening.
+        type ModbusServer;
+
+        /// Starts the server thread, answering requests until [stop] is called or the server is destroyed.
+        ///
+        /// Like a listener, this call will lock the read handle to the serialport for as long as the
+        /// thread is alive, and each iteration of the thread is at most as long as the ports timeout.
+        fn listen(self: &ModbusServer);
+
+        /// Stops the server.
+        ///
+        /// This should be considered a move of this server, you need to create a new server to listen again.
+        fn stop(self: &ModbusServer);
+
+        /// Reads count coils starting at address. Each value is 0 or 1.
+        ///
+        /// This function will throw if the range is outside the map.
+        fn get_coils(self: &ModbusServer, address: u16, count: u16) -> Result<Vec<u8>>;
+
+        /// Writes coils starting at address. Any non-zero value sets the coil.
+        ///
+        /// Returns false if the range is outside the map.
+        fn set_coils(self: &ModbusServer, address: u16, values: &[u8]) -> bool;
+
+        /// Reads count discrete inputs starting at address. Each value is 0 or 1.
+        ///
+        /// This function will throw if the range is outside the map.
+        fn get_discrete_inputs(self: &ModbusServer, address: u16, count: u16) -> Result<Vec<u8>>;
+
+        /// Writes discrete inputs starting at address. Any non-zero value sets the input.
+        ///
+        /// Returns false if the range is outside the map.
+        fn set_discrete_inputs(self: &ModbusServer, address: u16, values: &[u8]) -> bool;
+
+        /// Reads count holding registers starting at address.
+        ///
+        /// This function will throw if the range is outside the map.
+        fn get_holding_registers(self: &ModbusServer, address: u16, count: u16)
+            -> Result<Vec<u16>>;
+
+        /// Writes holding registers starting at address.
+        ///
+        /// Returns false if the range is outside the map.
+        fn set_holding_registers(self: &ModbusServer, address: u16, values: &[u16]) -> bool;
+
+        /// Reads count input registers starting at address.
+        ///
+        /// This function will throw if the range is outside the map.
+        fn get_input_registers(self: &ModbusServer, address: u16, count: u16) -> Result<Vec<u16>>;
+
+        /// Writes input registers starting at address.
+        ///
+        /// Returns false if the range is outside the map.
+        fn set_input_registers(self: &ModbusServer, address: u16, values: &[u16]) -> bool;
+
+        /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this server
+        /// to [serialcxx::set_modbus_write_callback].
+        ///
+        /// Obviously dont free this pointer or things will blow up.
+        fn self_ptr(self: &mut ModbusServer) -> *mut ModbusServer;
+    }
+
     extern "Rust" {
         type SerialListenerBuilder;
         type SerialListener;
@@ -160,6 +1819,78 @@ pub mod ffi {
         /// Obviously dont free this pointer or things will blow up.
         pub fn self_ptr(self: &mut SerialListenerBuilder) -> *mut SerialListenerBuilder;
 
+        /// Switches the listener to NMEA 0183 mode. Lines are checked against their `*hh` checksum,
+        /// and GGA, RMC, VTG, GSA, GSV and GLL sentences are parsed and passed to the callbacks added
+        /// with [serialcxx::add_nmea_gga_callback] and friends. Setting any of those callbacks also
+        /// switches to this mode.
+        ///
+        /// In this mode, the read callback is optional. If set, it receives every valid sentence that
+        /// has no typed callback, including proprietary sentences. Building will throw if no callbacks
+        /// are set at all.
+        pub fn set_nmea_mode(self: &mut SerialListenerBuilder);
+
+        /// Switches the listener to JSON lines mode, for devices that send one JSON value per line.
+        ///
+        /// Each line is parsed, and passed to the read callback as compact JSON on a single line.
+        /// Lines that fail to parse are counted, and passed with the parse error to the callback added
+        /// with [serialcxx::add_json_error_callback], which also switches to this mode. Blank lines
+        /// are skipped. Building will throw if the read callback is not set.
+        pub fn set_json_mode(self: &mut SerialListenerBuilder);
+
+        /// Switches the listener to telemetry mode, which parses each line into a numeric record
+        /// following schema. Records are passed to the callback added with
+        /// [serialcxx::add_telemetry_callback], and building will throw if it is not set.
+        ///
+        /// Lines with a value that does not parse as its fields type, a missing field that is not
+        /// optional, or the wrong number of CSV columns are counted as malformed and dropped. Blank
+        /// lines are skipped.
+        ///
+        /// This function will throw if the schema has no fields, duplicate or empty field names, or a
+        /// separator that is not ASCII.
+        pub fn set_telemetry_mode(
+            self: &mut SerialListenerBuilder,
+            schema: &TelemetrySchema,
+        ) -> Result<()>;
+
+        /// Switches the listener to COBS frame mode. The stream is split on 0x00 delimiters, and each
+        /// frame is decoded and passed to the callback added with [serialcxx::add_frame_callback].
+        ///
+        /// Frames that fail to decode or exceed the maximum frame size are dropped, counted, and
+        /// passed to the callback added with [serialcxx::add_frame_error_callback] if there is one.
+        /// The listener resynchronizes at the next delimiter. Building will throw if no frame
+        /// callback is set.
+        pub fn set_cobs_mode(self: &mut SerialListenerBuilder);
+
+        /// Switches the listener to SLIP (RFC 1055) frame mode. Same as [set_cobs_mode], but frames
+        /// are delimited by 0xC0 END bytes, with 0xDB escapes.
+        pub fn set_slip_mode(self: &mut SerialListenerBuilder);
+
+        /// Switches the listener to KISS frame mode, for packet radio TNCs. Frames are SLIP framed,
+        /// and passed to the callback added with [serialcxx::add_kiss_callback] with their port and
+        /// command split out. Building will throw if that callback is not set.
+        pub fn set_kiss_mode(self: &mut SerialListenerBuilder);
+
+        /// Switches the listener to HDLC-like frame mode. Same as [set_cobs_mode], but frames are
+        /// delimited by 0x7E flags with 0x7D escapes, and end with an FCS that is checked and removed.
+        ///
+        /// Frames failing their FCS are counted as bad checksums, and passed to the error callback.
+        pub fn set_hdlc_mode(self: &mut SerialListenerBuilder, fcs: HdlcFcs);
+
+        /// Switches the listener to length-prefixed frame mode, with frames laid out as config describes.
+        /// Same as [set_cobs_mode], but the stream is searched for the sync bytes, and each frame is
+        /// read by its length field and checked against its checksum. Frames are passed on without
+        /// their sync bytes and checksum.
+        ///
+        /// The maximum frame size limits the payload. This function will throw if the config is invalid,
+        /// such as a length field wider than 4 bytes or overlapping the sync bytes.
+        pub fn set_length_prefixed_mode(
+            self: &mut SerialListenerBuilder,
+            config: &LengthFraming,
+        ) -> Result<()>;
+
+        /// Sets the largest decoded frame a frame listener will accept, 4096 bytes by default.
+        pub fn set_max_frame_size(self: &mut SerialListenerBuilder, size: usize);
+
 
 
         /// Starts the listener thread, calling the callback on each line read from the port.
@@ -181,5 +1912,18 @@ pub mod ffi {
         /// This should be considered a move of this listener, as any future calls to listen will instantly
         /// complete after this is called. You need to build a new listener to listen again.
         pub fn stop(self: & SerialListener);
+
+        /// Gets the sentence counters of a listener in NMEA mode. Listeners in other modes return zeros.
+        pub fn nmea_stats(self: &SerialListener) -> NmeaStats;
+
+        /// Gets the line counters of a listener in JSON mode. Listeners in other modes return zeros.
+        pub fn json_stats(self: &SerialListener) -> JsonStats;
+
+        /// Gets the line counters of a listener in telemetry mode. Listeners in other modes return
+        /// zeros.
+        pub fn telemetry_stats(self: &SerialListener) -> TelemetryStats;
+
+        /// Gets the frame counters of a listener in a frame mode. Listeners in other modes return zeros.
+        pub fn frame_stats(self: &SerialListener) -> FrameStats;
     }
 }
@@ -0,0 +1,195 @@
+//! Modbus encoding shared by the master and server.
+
+use std::time::Duration;
+
+use serialport::{DataBits, Parity, SerialPort, StopBits};
+
+use crate::crc::crc16_modbus;
+use crate::ffi::ModbusError;
+
+pub const READ_COILS: u8 = 0x01;
+pub const READ_DISCRETE_INPUTS: u8 = 0x02;
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+pub const WRITE_SINGLE_COIL: u8 = 0x05;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+pub const READ_WRITE_MULTIPLE_REGISTERS: u8 = 0x17;
+
+/// Set on the function code of an exception response.
+pub const EXCEPTION_FLAG: u8 = 0x80;
+
+/// The most bits a single read may request.
+pub const MAX_READ_BITS: u16 = 2000;
+/// The most registers a single read may request.
+pub const MAX_READ_REGISTERS: u16 = 125;
+/// The most coils a single write may carry.
+pub const MAX_WRITE_BITS: u16 = 1968;
+/// The most registers a single write may carry.
+pub const MAX_WRITE_REGISTERS: u16 = 123;
+/// The most registers the write half of a read/write request may carry.
+pub const MAX_READ_WRITE_REGISTERS: u16 = 121;
+
+/// Unit id that addresses every device. Broadcasts are never answered.
+pub const BROADCAST_UNIT: u8 = 0;
+
+/// Exception codes, as sent on the wire.
+pub mod exception {
+    pub const ILLEGAL_FUNCTION: u8 = 0x01;
+    pub const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+    pub const ILLEGAL_DATA_VALUE: u8 = 0x03;
+}
+
+/// Maps an exception code from a response to its error.
+pub fn exception_to_error(code: u8) -> ModbusError {
+    match code {
+        0x01 => ModbusError::IllegalFunction,
+        0x02 => ModbusError::IllegalDataAddress,
+        0x03 => ModbusError::IllegalDataValue,
+        0x04 => ModbusError::ServerDeviceFailure,
+        0x05 => ModbusError::Acknowledge,
+        0x06 => ModbusError::ServerDeviceBusy,
+        0x08 => ModbusError::MemoryParityError,
+        0x0A => ModbusError::GatewayPathUnavailable,
+        0x0B => ModbusError::GatewayTargetFailedToRespond,
+        _ => ModbusError::UnknownException,
+    }
+}
+
+/// Wraps a PDU in an RTU frame: unit id, PDU, then CRC-16 low byte first.
+pub fn rtu_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+
+    let crc = crc16_modbus(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+
+    frame
+}
+
+/// Checks the CRC of a complete RTU frame.
+pub fn rtu_crc_ok(frame: &[u8]) -> bool {
+    match frame.len().checked_sub(2) {
+        Some(body) if body > 0 => crc16_modbus(&frame[..body]).to_le_bytes() == frame[body..],
+        _ => false,
+    }
+}
+
+/// The longitudinal redundancy check of an ASCII frame: the two's complement of the byte sum.
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        .wrapping_neg()
+}
+
+/// Wraps a PDU in an ASCII frame: `:`, then unit id, PDU and LRC as uppercase hex, then CR LF.
+pub fn ascii_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(pdu.len() + 2);
+    body.push(unit);
+    body.extend_from_slice(pdu);
+    body.push(lrc(&body));
+
+    let mut frame = String::with_capacity(body.len() * 2 + 3);
+    frame.push(':');
+    for byte in body {
+        frame.push_str(&format!("{:02X}", byte));
+    }
+    frame.push_str("\r\n");
+
+    frame.into_bytes()
+}
+
+/// Decodes a line holding an ASCII frame, returning the unit id and PDU.
+///
+/// Returns None if the line is not valid hex, or fails its LRC.
+pub fn parse_ascii_frame(line: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let hex = std::str::from_utf8(line).ok()?.trim_end();
+    let hex = hex.strip_prefix(':')?;
+    //Checked first, as slicing text with multibyte characters in it can panic
+    if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    if hex.len() % 2 != 0 || hex.len() < 6 {
+        return None;
+    }
+
+    let body = (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let (data, check) = body.split_at(body.len() - 1);
+    if lrc(data) != check[0] {
+        return None;
+    }
+
+    Some((data[0], data[1..].to_vec()))
+}
+
+/// The time taken to send one character with the ports current settings, including start, parity
+/// and stop bits.
+pub fn char_time(port: &dyn SerialPort) -> Duration {
+    let data_bits = match port.data_bits() {
+        Ok(DataBits::Five) => 5,
+        Ok(DataBits::Six) => 6,
+        Ok(DataBits::Seven) => 7,
+        _ => 8,
+    };
+    let parity_bits = match port.parity() {
+        Ok(Parity::None) => 0,
+        _ => 1,
+    };
+    let stop_bits = match port.stop_bits() {
+        Ok(StopBits::Two) => 2,
+        _ => 1,
+    };
+    let baud = port.baud_rate().unwrap_or(9600).max(1);
+
+    Duration::from_secs_f64((1 + data_bits + parity_bits + stop_bits) as f64 / baud as f64)
+}
+
+/// The silent interval that delimits RTU frames: 3.5 character times, or a fixed 1.75 ms above
+/// 19200 baud as the spec recommends.
+pub fn frame_gap(port: &dyn SerialPort) -> Duration {
+    if port.baud_rate().unwrap_or(9600) > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        char_time(port).mul_f32(3.5)
+    }
+}
+
+/// Packs one bit per byte, least significant bit first, as coils are sent on the wire.
+pub fn pack_bits(bits: &[u8]) -> Vec<u8> {
+    let mut packed = vec![0u8; bits.len().div_ceil(8)];
+
+    for (idx, &bit) in bits.iter().enumerate() {
+        if bit != 0 {
+            packed[idx / 8] |= 1 << (idx % 8);
+        }
+    }
+
+    packed
+}
+
+/// Unpacks count bits into one byte each, the reverse of [pack_bits].
+pub fn unpack_bits(packed: &[u8], count: usize) -> Vec<u8> {
+    (0..count)
+        .map(|idx| (packed[idx / 8] >> (idx % 8)) & 1)
+        .collect()
+}
+
+/// Reads big endian registers out of a response.
+pub fn unpack_registers(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+/// Appends registers in big endian, as they are sent on the wire.
+pub fn pack_registers(pdu: &mut Vec<u8>, values: &[u16]) {
+    for value in values {
+        pdu.extend_from_slice(&value.to_be_bytes());
+    }
+}
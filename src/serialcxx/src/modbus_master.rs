@@ -0,0 +1,348 @@
+//! Modbus RTU master, created with [crate::Serial::create_modbus_master].
+
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::ffi::{ModbusBitsResult, ModbusError, ModbusRegistersResult, SerialError};
+use crate::modbus::*;
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{
+    discard_input, discard_until_silent, read_exact_until, DeadlineGuard, SerialPortReader,
+};
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+/// The response timeout of new masters.
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A Modbus RTU master (client) using a [crate::Serial] as its transport.
+///
+/// Requests are sent one at a time. Each request locks the read handle of the port until its
+/// response arrives, so this cannot be used while a listener is alive.
+pub struct ModbusMaster {
+    reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+    read_settings: Arc<Mutex<Box<dyn SerialPort>>>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    response_timeout: Mutex<Duration>,
+    /// Earliest time the next request may start, so frames stay 3.5 characters apart.
+    bus_idle_at: Mutex<Instant>,
+}
+
+impl ModbusMaster {
+    pub fn new(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        read_settings: Arc<Mutex<Box<dyn SerialPort>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    ) -> Self {
+        ModbusMaster {
+            reader,
+            read_settings,
+            write_handle,
+            response_timeout: Mutex::new(DEFAULT_RESPONSE_TIMEOUT),
+            bus_idle_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Sets how long to wait for a response to start arriving. Defaults to one second.
+    pub fn set_response_timeout(&self, sec: f32) {
+        *self.response_timeout.lock() = timeout_from_secs(sec);
+    }
+
+    /// Reads count coils, function code 1. Each value is 0 or 1.
+    pub fn read_coils(&self, unit: u8, address: u16, count: u16) -> ModbusBitsResult {
+        self.read_bits(READ_COILS, unit, address, count)
+    }
+
+    /// Reads count discrete inputs, function code 2. Each value is 0 or 1.
+    pub fn read_discrete_inputs(&self, unit: u8, address: u16, count: u16) -> ModbusBitsResult {
+        self.read_bits(READ_DISCRETE_INPUTS, unit, address, count)
+    }
+
+    /// Reads count holding registers, function code 3.
+    pub fn read_holding_registers(
+        &self,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> ModbusRegistersResult {
+        self.read_registers(READ_HOLDING_REGISTERS, unit, address, count)
+    }
+
+    /// Reads count input registers, function code 4.
+    pub fn read_input_registers(
+        &self,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> ModbusRegistersResult {
+        self.read_registers(READ_INPUT_REGISTERS, unit, address, count)
+    }
+
+    /// Writes a single coil, function code 5.
+    pub fn write_single_coil(&self, unit: u8, address: u16, value: bool) -> ModbusError {
+        let mut pdu = vec![WRITE_SINGLE_COIL];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(if value { &[0xFF, 0x00] } else { &[0x00, 0x00] });
+
+        self.write_echoed(unit, &pdu)
+    }
+
+    /// Writes a single holding register, function code 6.
+    pub fn write_single_register(&self, unit: u8, address: u16, value: u16) -> ModbusError {
+        let mut pdu = vec![WRITE_SINGLE_REGISTER];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&value.to_be_bytes());
+
+        self.write_echoed(unit, &pdu)
+    }
+
+    /// Writes consecutive coils, function code 15. Any non-zero value sets the coil.
+    pub fn write_multiple_coils(&self, unit: u8, address: u16, values: &[u8]) -> ModbusError {
+        if values.is_empty() || values.len() > MAX_WRITE_BITS as usize {
+            return ModbusError::InvalidRequest;
+        }
+
+        let packed = pack_bits(values);
+        let mut pdu = vec![WRITE_MULTIPLE_COILS];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push(packed.len() as u8);
+        pdu.extend_from_slice(&packed);
+
+        self.write_multiple(unit, &pdu)
+    }
+
+    /// Writes consecutive holding registers, function code 16.
+    pub fn write_multiple_registers(&self, unit: u8, address: u16, values: &[u16]) -> ModbusError {
+        if values.is_empty() || values.len() > MAX_WRITE_REGISTERS as usize {
+            return ModbusError::InvalidRequest;
+        }
+
+        let mut pdu = vec![WRITE_MULTIPLE_REGISTERS];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push((values.len() * 2) as u8);
+        pack_registers(&mut pdu, values);
+
+        self.write_multiple(unit, &pdu)
+    }
+
+    /// Writes values starting at write_address, then reads read_count registers starting at
+    /// read_address, in one request. Function code 23.
+    pub fn read_write_registers(
+        &self,
+        unit: u8,
+        read_address: u16,
+        read_count: u16,
+        write_address: u16,
+        values: &[u16],
+    ) -> ModbusRegistersResult {
+        if unit == BROADCAST_UNIT
+            || !(1..=MAX_READ_REGISTERS).contains(&read_count)
+            || values.is_empty()
+            || values.len() > MAX_READ_WRITE_REGISTERS as usize
+        {
+            return registers_error(ModbusError::InvalidRequest);
+        }
+
+        let mut pdu = vec![READ_WRITE_MULTIPLE_REGISTERS];
+        pdu.extend_from_slice(&read_address.to_be_bytes());
+        pdu.extend_from_slice(&read_count.to_be_bytes());
+        pdu.extend_from_slice(&write_address.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push((values.len() * 2) as u8);
+        pack_registers(&mut pdu, values);
+
+        match self.request(unit, &pdu) {
+            Ok(response) if response.len() == 2 + read_count as usize * 2 => {
+                ModbusRegistersResult {
+                    error: ModbusError::NoErr,
+                    values: unpack_registers(&response[2..]),
+                }
+            }
+            Ok(_) => registers_error(ModbusError::InvalidResponse),
+            Err(err) => registers_error(err),
+        }
+    }
+
+    fn read_bits(&self, function: u8, unit: u8, address: u16, count: u16) -> ModbusBitsResult {
+        if unit == BROADCAST_UNIT || !(1..=MAX_READ_BITS).contains(&count) {
+            return bits_error(ModbusError::InvalidRequest);
+        }
+
+        match self.request(unit, &read_pdu(function, address, count)) {
+            Ok(response) if response.len() == 2 + (count as usize).div_ceil(8) => {
+                ModbusBitsResult {
+                    error: ModbusError::NoErr,
+                    values: unpack_bits(&response[2..], count as usize),
+                }
+            }
+            Ok(_) => bits_error(ModbusError::InvalidResponse),
+            Err(err) => bits_error(err),
+        }
+    }
+
+    fn read_registers(
+        &self,
+        function: u8,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> ModbusRegistersResult {
+        if unit == BROADCAST_UNIT || !(1..=MAX_READ_REGISTERS).contains(&count) {
+            return registers_error(ModbusError::InvalidRequest);
+        }
+
+        match self.request(unit, &read_pdu(function, address, count)) {
+            Ok(response) if response.len() == 2 + count as usize * 2 => ModbusRegistersResult {
+                error: ModbusError::NoErr,
+                values: unpack_registers(&response[2..]),
+            },
+            Ok(_) => registers_error(ModbusError::InvalidResponse),
+            Err(err) => registers_error(err),
+        }
+    }
+
+    /// Sends a single write, whose response echoes the request.
+    fn write_echoed(&self, unit: u8, pdu: &[u8]) -> ModbusError {
+        match self.request(unit, pdu) {
+            Ok(response) if unit == BROADCAST_UNIT || response == pdu => ModbusError::NoErr,
+            Ok(_) => ModbusError::InvalidResponse,
+            Err(err) => err,
+        }
+    }
+
+    /// Sends a multiple write, whose response echoes the address and quantity of the request.
+    fn write_multiple(&self, unit: u8, pdu: &[u8]) -> ModbusError {
+        match self.request(unit, pdu) {
+            Ok(response) if unit == BROADCAST_UNIT || response == pdu[..5] => ModbusError::NoErr,
+            Ok(_) => ModbusError::InvalidResponse,
+            Err(err) => err,
+        }
+    }
+
+    /// Sends a request PDU and returns the PDU of its response. Broadcasts return an empty PDU
+    /// once the frame has been sent.
+    fn request(&self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, ModbusError> {
+        //Hold the reader for the whole exchange, so nothing else can take the response
+        let mut reader = self.reader.lock();
+        let mut bus_idle_at = self.bus_idle_at.lock();
+
+        let gap = frame_gap(&**self.read_settings.lock());
+
+        //Wait out the inter-frame gap, then drop anything stale so it can't be mistaken for our response
+        let now = Instant::now();
+        if *bus_idle_at > now {
+            std::thread::sleep(*bus_idle_at - now);
+        }
+        discard_input(&mut reader, &self.read_settings);
+
+        let frame = rtu_frame(unit, pdu);
+        {
+            let mut write_handle = self.write_handle.lock();
+            let (error, _) = write_counted(&mut **write_handle, &frame);
+            if error != SerialError::NoErr {
+                return Err(ModbusError::PortErr);
+            }
+            //Wait for the frame to leave the UART, so our timing starts at the end of it
+            let _ = write_handle.flush();
+        }
+
+        if unit == BROADCAST_UNIT {
+            *bus_idle_at = Instant::now() + gap;
+            return Ok(Vec::new());
+        }
+
+        let timeout = *self.response_timeout.lock();
+        let response = self.read_response(&mut reader, unit, pdu[0], timeout);
+
+        //After a bad response, wait for the bus to go quiet so trailing garbage can't corrupt the next one
+        if response.is_err() {
+            //An infinite timeout is too far away to represent, and never passes
+            let limit = Instant::now().checked_add(timeout);
+            discard_until_silent(&mut reader, &self.read_settings, gap, limit);
+        }
+        *bus_idle_at = Instant::now() + gap;
+
+        response
+    }
+
+    /// Reads and validates a response frame, returning its PDU.
+    fn read_response(
+        &self,
+        reader: &mut BufReader<SerialPortReader>,
+        unit: u8,
+        function: u8,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ModbusError> {
+        let deadline = DeadlineGuard::new(&self.read_settings, Instant::now().checked_add(timeout));
+        let read = |reader: &mut BufReader<SerialPortReader>, buf: &mut [u8]| match read_exact_until(
+            reader, &deadline, buf,
+        ) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ModbusError::Timeout),
+            Err(_) => Err(ModbusError::PortErr),
+        };
+
+        //Unit, function code, and either the exception code or the first byte of the body
+        let mut frame = vec![0u8; 3];
+        read(reader, &mut frame)?;
+
+        let remaining = if frame[1] == function | EXCEPTION_FLAG {
+            2
+        } else {
+            match function {
+                WRITE_SINGLE_COIL
+                | WRITE_SINGLE_REGISTER
+                | WRITE_MULTIPLE_COILS
+                | WRITE_MULTIPLE_REGISTERS => 5,
+                //Byte count follows the function code
+                _ => frame[2] as usize + 2,
+            }
+        };
+
+        frame.resize(3 + remaining, 0);
+        read(reader, &mut frame[3..])?;
+
+        if !rtu_crc_ok(&frame) {
+            return Err(ModbusError::CrcMismatch);
+        }
+        if frame[0] != unit {
+            return Err(ModbusError::InvalidResponse);
+        }
+        if frame[1] == function | EXCEPTION_FLAG {
+            return Err(exception_to_error(frame[2]));
+        }
+        if frame[1] != function {
+            return Err(ModbusError::InvalidResponse);
+        }
+
+        frame.truncate(frame.len() - 2);
+        frame.remove(0);
+        Ok(frame)
+    }
+}
+
+fn read_pdu(function: u8, address: u16, count: u16) -> Vec<u8> {
+    let mut pdu = vec![function];
+    pdu.extend_from_slice(&address.to_be_bytes());
+    pdu.extend_from_slice(&count.to_be_bytes());
+    pdu
+}
+
+fn bits_error(error: ModbusError) -> ModbusBitsResult {
+    ModbusBitsResult {
+        error,
+        values: Vec::new(),
+    }
+}
+
+fn registers_error(error: ModbusError) -> ModbusRegistersResult {
+    ModbusRegistersResult {
+        error,
+        values: Vec::new(),
+    }
+}
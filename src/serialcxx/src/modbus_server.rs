@@ -0,0 +1,526 @@
+//! Modbus server (slave), created with [crate::Serial::create_modbus_server].
+
+use std::ffi::c_void;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use serialport::{Error, Result, SerialPort};
+
+use crate::ffi::{ModbusFraming, ModbusServerConfig, ModbusTable, SerialError};
+use crate::modbus::*;
+use crate::serial_ext::{
+    discard_until_silent, read_exact_until, CVoidSend, DeadlineGuard, SerialPortReader,
+};
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+/// Callback for writes made by a master. Called from the server thread with the table, first
+/// address and number of values written.
+pub type ModbusWriteCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, table: ModbusTable, address: u16, count: u16);
+
+/// Extra time allowed for the rest of an RTU frame to arrive, on top of its transmission time.
+/// USB adapters deliver data in bursts, so strict 1.5 character timing is not achievable.
+const RTU_RX_SLACK: Duration = Duration::from_millis(20);
+
+/// The data a server exposes.
+struct RegisterMap {
+    coils: Vec<u8>,
+    discrete_inputs: Vec<u8>,
+    holding_registers: Vec<u16>,
+    input_registers: Vec<u16>,
+}
+
+/// A write made by a master, reported to the write callback once the map is unlocked.
+type WriteEvent = (ModbusTable, u16, u16);
+
+/// Returns the range of table covered by a request, if it is in bounds.
+fn range(len: usize, address: u16, count: usize) -> Option<std::ops::Range<usize>> {
+    let start = address as usize;
+    let end = start.checked_add(count)?;
+    (count > 0 && end <= len).then_some(start..end)
+}
+
+fn exception_pdu(function: u8, code: u8) -> Vec<u8> {
+    vec![function | EXCEPTION_FLAG, code]
+}
+
+fn be_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+impl RegisterMap {
+    /// Handles a request PDU, returning the response PDU and any write to report.
+    fn handle(&mut self, pdu: &[u8]) -> (Vec<u8>, Option<WriteEvent>) {
+        match self.try_handle(pdu) {
+            Ok(result) => result,
+            Err(code) => (exception_pdu(pdu[0], code), None),
+        }
+    }
+
+    fn try_handle(&mut self, pdu: &[u8]) -> std::result::Result<(Vec<u8>, Option<WriteEvent>), u8> {
+        use exception::*;
+
+        let function = pdu[0];
+        let fixed_len = match function {
+            READ_COILS..=WRITE_SINGLE_REGISTER => Some(5),
+            WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => {
+                pdu.get(5).map(|&bytes| 6 + bytes as usize)
+            }
+            READ_WRITE_MULTIPLE_REGISTERS => pdu.get(9).map(|&bytes| 10 + bytes as usize),
+            _ => return Err(ILLEGAL_FUNCTION),
+        };
+        if fixed_len != Some(pdu.len()) {
+            return Err(ILLEGAL_DATA_VALUE);
+        }
+
+        let address = be_u16(pdu, 1);
+        let quantity = be_u16(pdu, 3);
+
+        match function {
+            READ_COILS | READ_DISCRETE_INPUTS => {
+                if !(1..=MAX_READ_BITS).contains(&quantity) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let table = if function == READ_COILS {
+                    &self.coils
+                } else {
+                    &self.discrete_inputs
+                };
+                let range =
+                    range(table.len(), address, quantity as usize).ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                let packed = pack_bits(&table[range]);
+                let mut response = vec![function, packed.len() as u8];
+                response.extend_from_slice(&packed);
+                Ok((response, None))
+            }
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                if !(1..=MAX_READ_REGISTERS).contains(&quantity) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let table = if function == READ_HOLDING_REGISTERS {
+                    &self.holding_registers
+                } else {
+                    &self.input_registers
+                };
+                let range =
+                    range(table.len(), address, quantity as usize).ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                let mut response = vec![function, (quantity * 2) as u8];
+                pack_registers(&mut response, &table[range]);
+                Ok((response, None))
+            }
+            WRITE_SINGLE_COIL => {
+                let value = match quantity {
+                    0xFF00 => 1,
+                    0x0000 => 0,
+                    _ => return Err(ILLEGAL_DATA_VALUE),
+                };
+                let range = range(self.coils.len(), address, 1).ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                self.coils[range.start] = value;
+                Ok((pdu.to_vec(), Some((ModbusTable::Coils, address, 1))))
+            }
+            WRITE_SINGLE_REGISTER => {
+                let range =
+                    range(self.holding_registers.len(), address, 1).ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                self.holding_registers[range.start] = quantity;
+                Ok((
+                    pdu.to_vec(),
+                    Some((ModbusTable::HoldingRegisters, address, 1)),
+                ))
+            }
+            WRITE_MULTIPLE_COILS => {
+                if !(1..=MAX_WRITE_BITS).contains(&quantity)
+                    || pdu[5] as usize != (quantity as usize).div_ceil(8)
+                {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let range = range(self.coils.len(), address, quantity as usize)
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                self.coils[range].copy_from_slice(&unpack_bits(&pdu[6..], quantity as usize));
+                Ok((
+                    pdu[..5].to_vec(),
+                    Some((ModbusTable::Coils, address, quantity)),
+                ))
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                if !(1..=MAX_WRITE_REGISTERS).contains(&quantity)
+                    || pdu[5] as usize != quantity as usize * 2
+                {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let range = range(self.holding_registers.len(), address, quantity as usize)
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                self.holding_registers[range].copy_from_slice(&unpack_registers(&pdu[6..]));
+                Ok((
+                    pdu[..5].to_vec(),
+                    Some((ModbusTable::HoldingRegisters, address, quantity)),
+                ))
+            }
+            _ => {
+                //Read/write multiple registers. The write happens before the read.
+                let write_address = be_u16(pdu, 5);
+                let write_quantity = be_u16(pdu, 7);
+                if !(1..=MAX_READ_REGISTERS).contains(&quantity)
+                    || !(1..=MAX_READ_WRITE_REGISTERS).contains(&write_quantity)
+                    || pdu[9] as usize != write_quantity as usize * 2
+                {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let len = self.holding_registers.len();
+                let read_range =
+                    range(len, address, quantity as usize).ok_or(ILLEGAL_DATA_ADDRESS)?;
+                let write_range = range(len, write_address, write_quantity as usize)
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+
+                self.holding_registers[write_range].copy_from_slice(&unpack_registers(&pdu[10..]));
+
+                let mut response = vec![function, (quantity * 2) as u8];
+                pack_registers(&mut response, &self.holding_registers[read_range]);
+                Ok((
+                    response,
+                    Some((ModbusTable::HoldingRegisters, write_address, write_quantity)),
+                ))
+            }
+        }
+    }
+}
+
+/// State shared between a server and its thread.
+struct Shared {
+    unit: u8,
+    framing: ModbusFraming,
+    map: Mutex<RegisterMap>,
+    write_callback: Mutex<Option<(CVoidSend, ModbusWriteCallback)>>,
+}
+
+/// A Modbus server (slave) that answers requests from a master on the bus, serving a register map
+/// that C++ can read and write at any time.
+///
+/// Like a [crate::SerialListener], the server thread holds the read handle of its port for as long
+/// as it is alive.
+pub struct ModbusServer {
+    shared: Arc<Shared>,
+    reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+    read_settings: Arc<Mutex<Box<dyn SerialPort>>>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    /// Token used to kill the thread.
+    cts: CancellationTokenSource,
+}
+
+impl ModbusServer {
+    pub fn new(
+        config: &ModbusServerConfig,
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        read_settings: Arc<Mutex<Box<dyn SerialPort>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    ) -> Result<ModbusServer> {
+        if config.unit == BROADCAST_UNIT || config.unit > 247 {
+            return Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Modbus server unit must be between 1 and 247.",
+            ));
+        }
+
+        Ok(ModbusServer {
+            shared: Arc::new(Shared {
+                unit: config.unit,
+                framing: config.framing,
+                map: Mutex::new(RegisterMap {
+                    coils: vec![0; config.coils as usize],
+                    discrete_inputs: vec![0; config.discrete_inputs as usize],
+                    holding_registers: vec![0; config.holding_registers as usize],
+                    input_registers: vec![0; config.input_registers as usize],
+                }),
+                write_callback: Mutex::new(None),
+            }),
+            reader,
+            read_settings,
+            write_handle,
+            cts: CancellationTokenSource::new(),
+        })
+    }
+
+    /// Starts the server thread, which answers requests until [ModbusServer::stop] is called.
+    pub fn listen(&self) {
+        //The cancellation token is the only way we have to kill the server thread.
+        let token = self.cts.token().clone();
+        let shared = self.shared.clone();
+        let reader = self.reader.clone();
+        let read_settings = self.read_settings.clone();
+        let write_handle = self.write_handle.clone();
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = self.reader.lock();
+
+        std::thread::spawn(move || {
+            log::debug!("Spawned Modbus server");
+            serve(&shared, &reader, &read_settings, &write_handle, &token);
+            log::debug!("exiting Modbus server thread")
+        });
+        //Thread detaches here
+    }
+
+    /// Stops the server.
+    pub fn stop(&self) {
+        self.cts.cancel();
+    }
+
+    pub fn get_coils(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        get(&self.shared.map.lock().coils, address, count)
+    }
+
+    pub fn set_coils(&self, address: u16, values: &[u8]) -> bool {
+        let values: Vec<u8> = values.iter().map(|&bit| (bit != 0) as u8).collect();
+        set(&mut self.shared.map.lock().coils, address, &values)
+    }
+
+    pub fn get_discrete_inputs(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        get(&self.shared.map.lock().discrete_inputs, address, count)
+    }
+
+    pub fn set_discrete_inputs(&self, address: u16, values: &[u8]) -> bool {
+        let values: Vec<u8> = values.iter().map(|&bit| (bit != 0) as u8).collect();
+        set(
+            &mut self.shared.map.lock().discrete_inputs,
+            address,
+            &values,
+        )
+    }
+
+    pub fn get_holding_registers(&self, address: u16, count: u16) -> Result<Vec<u16>> {
+        get(&self.shared.map.lock().holding_registers, address, count)
+    }
+
+    pub fn set_holding_registers(&self, address: u16, values: &[u16]) -> bool {
+        set(
+            &mut self.shared.map.lock().holding_registers,
+            address,
+            values,
+        )
+    }
+
+    pub fn get_input_registers(&self, address: u16, count: u16) -> Result<Vec<u16>> {
+        get(&self.shared.map.lock().input_registers, address, count)
+    }
+
+    pub fn set_input_registers(&self, address: u16, values: &[u16]) -> bool {
+        set(&mut self.shared.map.lock().input_registers, address, values)
+    }
+
+    /// Sets the callback for writes made by a master, replacing any previous one.
+    pub fn set_write_callback(&self, callback: Option<(CVoidSend, ModbusWriteCallback)>) {
+        *self.shared.write_callback.lock() = callback;
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this server
+    /// to the callback setter function.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut ModbusServer {
+        self as *mut ModbusServer
+    }
+}
+
+impl Drop for ModbusServer {
+    fn drop(&mut self) {
+        self.cts.cancel() //The token will be kept alive by the thread, so this doesn't create a dangling pointer.
+    }
+}
+
+fn get<T: Copy>(table: &[T], address: u16, count: u16) -> Result<Vec<T>> {
+    range(table.len(), address, count as usize)
+        .map(|range| table[range].to_vec())
+        .ok_or_else(|| {
+            Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Address range is outside the register map.",
+            )
+        })
+}
+
+fn set<T: Copy>(table: &mut [T], address: u16, values: &[T]) -> bool {
+    match range(table.len(), address, values.len()) {
+        Some(range) => {
+            table[range].copy_from_slice(values);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Body of the server thread.
+fn serve(
+    shared: &Shared,
+    reader: &Mutex<BufReader<SerialPortReader>>,
+    read_settings: &Mutex<Box<dyn SerialPort>>,
+    write_handle: &Mutex<Box<dyn SerialPort>>,
+    token: &CancellationToken,
+) {
+    //Lock the reader while this server is active
+    let mut reader = reader.lock();
+    let mut line = Vec::new();
+
+    while !token.is_canceled() {
+        let request = match shared.framing {
+            ModbusFraming::Ascii => read_ascii_request(&mut reader, &mut line),
+            _ => read_rtu_request(&mut reader, read_settings),
+        };
+
+        let (unit, pdu) = match request {
+            Some(request) => request,
+            None => continue,
+        };
+        if (unit != shared.unit && unit != BROADCAST_UNIT) || pdu.is_empty() {
+            continue;
+        }
+
+        let (response, write) = shared.map.lock().handle(&pdu);
+
+        if unit != BROADCAST_UNIT {
+            let frame = match shared.framing {
+                ModbusFraming::Ascii => ascii_frame(unit, &response),
+                _ => {
+                    //Leave the bus quiet for 3.5 characters before answering
+                    std::thread::sleep(frame_gap(&**read_settings.lock()));
+                    rtu_frame(unit, &response)
+                }
+            };
+
+            let (error, _) = write_counted(&mut **write_handle.lock(), &frame);
+            if error != SerialError::NoErr {
+                log::warn!("Modbus server failed to respond: {:?}", error.repr);
+            }
+        }
+
+        //Copied out, so the callback can replace itself without deadlocking
+        let callback = *shared.write_callback.lock();
+        if let (Some((table, address, count)), Some((user_data, callback))) = (write, callback) {
+            unsafe {
+                //Safe as long as C++ keeps user_data alive while the callback is set.
+                callback(user_data.0, table, address, count);
+            }
+        }
+    }
+}
+
+/// Reads an ASCII request line, returning its unit and PDU. Partial lines are kept in line
+/// across timeouts.
+fn read_ascii_request(
+    reader: &mut BufReader<SerialPortReader>,
+    line: &mut Vec<u8>,
+) -> Option<(u8, Vec<u8>)> {
+    match reader.read_until(b'\n', line) {
+        Ok(_) if line.ends_with(b"\n") => {
+            //Anything before the start character is noise
+            let request = line
+                .iter()
+                .position(|&byte| byte == b':')
+                .and_then(|start| parse_ascii_frame(&line[start..]));
+            if request.is_none() {
+                log::debug!("Dropping malformed Modbus ASCII frame");
+            }
+
+            line.clear();
+            request
+        }
+        _ => None,
+    }
+}
+
+/// Reads an RTU request, returning its unit and PDU.
+///
+/// The length of a request is known from its function code, so the frame ends as soon as it is
+/// complete. Frames that stall, fail their CRC, or use an unknown function code are dropped by
+/// waiting for the line to go quiet for 3.5 characters.
+fn read_rtu_request(
+    reader: &mut BufReader<SerialPortReader>,
+    read_settings: &Mutex<Box<dyn SerialPort>>,
+) -> Option<(u8, Vec<u8>)> {
+    //Wait for the first byte using the ports own timeout
+    let mut frame = vec![0u8; 1];
+    match reader.read(&mut frame) {
+        Ok(1) => {}
+        Ok(_) => return None,
+        Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+            return None
+        }
+        Err(err) => {
+            log::warn!("Modbus server read failed: {}", err);
+            return None;
+        }
+    }
+
+    let (gap, char_time) = {
+        let settings = read_settings.lock();
+        (frame_gap(&**settings), char_time(&**settings))
+    };
+
+    //Reads more of the frame, allowing for its transmission time
+    let read_more = |reader: &mut BufReader<SerialPortReader>, frame: &mut Vec<u8>, total| {
+        let start = frame.len();
+        frame.resize(total, 0);
+
+        let limit = Instant::now() + char_time * (total - start) as u32 + gap + RTU_RX_SLACK;
+        let deadline = DeadlineGuard::new(read_settings, Some(limit));
+        matches!(
+            read_exact_until(reader, &deadline, &mut frame[start..]),
+            Ok(true)
+        )
+    };
+
+    let resync = |reader: &mut BufReader<SerialPortReader>| {
+        let limit = Instant::now() + Duration::from_secs(1);
+        discard_until_silent(reader, read_settings, gap, Some(limit))
+    };
+
+    //Unit, function, and enough of the body to find the byte count of any supported request
+    let header_len = |function: u8| match function {
+        READ_COILS..=WRITE_SINGLE_REGISTER => Some(8),
+        WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => Some(7),
+        READ_WRITE_MULTIPLE_REGISTERS => Some(11),
+        _ => None,
+    };
+
+    if !read_more(reader, &mut frame, 2) {
+        return None;
+    }
+    let header = match header_len(frame[1]) {
+        Some(header) => header,
+        None => {
+            //Unknown length, so the frame can only end on silence. If it is intact, the handler
+            //will answer with an exception.
+            frame.extend(resync(reader));
+            if !rtu_crc_ok(&frame) {
+                return None;
+            }
+            return Some((frame[0], frame[1..frame.len() - 2].to_vec()));
+        }
+    };
+    if !read_more(reader, &mut frame, header) {
+        resync(reader);
+        return None;
+    }
+
+    let total = match frame[1] {
+        WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => header + frame[6] as usize + 2,
+        READ_WRITE_MULTIPLE_REGISTERS => header + frame[10] as usize + 2,
+        _ => header,
+    };
+    if !read_more(reader, &mut frame, total) || !rtu_crc_ok(&frame) {
+        log::debug!("Dropping bad Modbus RTU frame");
+        resync(reader);
+        return None;
+    }
+
+    frame.truncate(frame.len() - 2);
+    let unit = frame.remove(0);
+    Some((unit, frame))
+}
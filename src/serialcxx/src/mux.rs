@@ -0,0 +1,471 @@
+//! Logical channels sharing one port, created with [crate::Serial::create_channel_mux].
+//!
+//! Each write is split into chunks sent as COBS frames, starting with the id of their channel. The
+//! writer thread takes one chunk from each channel with data queued in turn, so a large write on one
+//! channel does not hold up the others.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::c_void;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use cxx::CxxString;
+use parking_lot::Condvar;
+use serialport::{Error, SerialPort};
+
+use crate::cobs::{self, CobsDeframer};
+use crate::ffi::{MuxStats, ReadResult, SerialError};
+use crate::framing::{Deframer, DEFAULT_MAX_FRAME_LEN};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{CVoidSend, SerialPortReader};
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+/// The most payload sent in one frame.
+const CHUNK_LEN: usize = 256;
+/// Received data kept per channel before more is dropped.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// Callback for data received on a channel. The data is only valid until the callback returns.
+pub type ChannelCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize);
+
+/// Tracks the chunks of one write, so the writer can wait for all of them.
+struct Ticket {
+    /// Chunks not written yet, and the first error.
+    state: Mutex<(usize, SerialError)>,
+    done: Condvar,
+}
+
+impl Ticket {
+    fn complete(&self, error: SerialError) {
+        let mut state = self.state.lock();
+        state.0 -= 1;
+        if state.1 == SerialError::NoErr {
+            state.1 = error;
+        }
+        if state.0 == 0 {
+            self.done.notify_all();
+        }
+    }
+}
+
+struct Chunk {
+    frame: Vec<u8>,
+    ticket: Arc<Ticket>,
+}
+
+#[derive(Default)]
+struct WriteState {
+    /// Chunks waiting to be written, by channel.
+    queues: BTreeMap<u8, VecDeque<Chunk>>,
+    /// The channel written last.
+    cursor: u8,
+    stopped: bool,
+}
+
+impl WriteState {
+    /// Takes a chunk from the first channel after the cursor with data queued.
+    fn next_chunk(&mut self) -> Option<Chunk> {
+        let after = self.cursor.checked_add(1).unwrap_or(0);
+        let id = *self
+            .queues
+            .range(after..)
+            .chain(self.queues.range(..after))
+            .find(|(_, queue)| !queue.is_empty())?
+            .0;
+
+        self.cursor = id;
+        self.queues.get_mut(&id)?.pop_front()
+    }
+}
+
+struct ChannelRx {
+    buf: VecDeque<u8>,
+    callback: Option<(CVoidSend, ChannelCallback)>,
+}
+
+struct ChannelShared {
+    rx: Mutex<ChannelRx>,
+    /// Notified when data is received, or the mux is stopped.
+    received: Condvar,
+    read_timeout: Mutex<Duration>,
+}
+
+struct MuxShared {
+    channels: Mutex<HashMap<u8, Arc<ChannelShared>>>,
+    writes: Mutex<WriteState>,
+    /// Notified when chunks are queued, or the mux is stopped.
+    write_ready: Condvar,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    frames: AtomicU64,
+    unrouted: AtomicU64,
+    overruns: AtomicU64,
+    bad_frames: AtomicU64,
+}
+
+impl MuxShared {
+    fn stopped(&self) -> bool {
+        self.writes.lock().stopped
+    }
+
+    fn handle_frame(&self, frame: &[u8]) {
+        let (id, data) = match frame.split_first() {
+            Some((&id, data)) => (id, data),
+            None => {
+                self.bad_frames.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        self.frames.fetch_add(1, Ordering::Relaxed);
+
+        let channel = match self.channels.lock().get(&id) {
+            Some(channel) => channel.clone(),
+            None => {
+                self.unrouted.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let mut rx = channel.rx.lock();
+        if let Some((user_data, callback)) = rx.callback {
+            //Unlocked first, so the callback can read the channel or replace itself
+            drop(rx);
+            unsafe {
+                //Safe only if callback does not store a reference to the data, which it does not own.
+                callback(user_data.0, data.as_ptr(), data.len());
+            }
+        } else if rx.buf.len() + data.len() > MAX_BUFFERED {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        } else {
+            rx.buf.extend(data);
+            channel.received.notify_all();
+        }
+    }
+}
+
+/// Splits a port into logical channels, with its reader and writer threads.
+pub struct ChannelMux {
+    shared: Arc<MuxShared>,
+    /// Token used to kill the reader thread.
+    cts: CancellationTokenSource,
+}
+
+impl ChannelMux {
+    /// Creates a mux and starts its threads.
+    pub fn start(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    ) -> ChannelMux {
+        let shared = Arc::new(MuxShared {
+            channels: Mutex::new(HashMap::new()),
+            writes: Mutex::new(WriteState::default()),
+            write_ready: Condvar::new(),
+            write_handle,
+            frames: AtomicU64::new(0),
+            unrouted: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            bad_frames: AtomicU64::new(0),
+        });
+        let cts = CancellationTokenSource::new();
+
+        let token = cts.token().clone();
+        let thread_shared = shared.clone();
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = reader.lock();
+
+        let thread_reader = reader.clone();
+        std::thread::spawn(move || {
+            log::debug!("Spawned channel mux reader");
+            read_loop(&thread_shared, &thread_reader, &token);
+            log::debug!("exiting channel mux reader thread")
+        });
+
+        let thread_shared = shared.clone();
+        std::thread::spawn(move || {
+            write_loop(&thread_shared);
+            log::debug!("exiting channel mux writer thread")
+        });
+        //Threads detach here
+
+        ChannelMux { shared, cts }
+    }
+
+    /// Opens the endpoint for channel id, or throws if it is already open.
+    pub fn open_channel(&self, id: u8) -> serialport::Result<Box<Channel>> {
+        let mut channels = self.shared.channels.lock();
+        if channels.contains_key(&id) {
+            return Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Channel is already open.",
+            ));
+        }
+
+        let channel = Arc::new(ChannelShared {
+            rx: Mutex::new(ChannelRx {
+                buf: VecDeque::new(),
+                callback: None,
+            }),
+            received: Condvar::new(),
+            read_timeout: Mutex::new(Duration::MAX),
+        });
+        channels.insert(id, channel.clone());
+
+        Ok(Box::new(Channel {
+            id,
+            channel,
+            mux: self.shared.clone(),
+        }))
+    }
+
+    pub fn stats(&self) -> MuxStats {
+        MuxStats {
+            frames: self.shared.frames.load(Ordering::Relaxed),
+            unrouted: self.shared.unrouted.load(Ordering::Relaxed),
+            overruns: self.shared.overruns.load(Ordering::Relaxed),
+            bad_frames: self.shared.bad_frames.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops both threads, releasing the read handle once its current read times out.
+    pub fn stop(&self) {
+        self.cts.cancel();
+
+        let mut writes = self.shared.writes.lock();
+        writes.stopped = true;
+        //Fail anything still queued, so writers do not wait forever
+        for queue in writes.queues.values_mut() {
+            for chunk in queue.drain(..) {
+                chunk.ticket.complete(SerialError::Other);
+            }
+        }
+        drop(writes);
+        self.shared.write_ready.notify_all();
+
+        for channel in self.shared.channels.lock().values() {
+            let _rx = channel.rx.lock();
+            channel.received.notify_all();
+        }
+    }
+}
+
+impl Drop for ChannelMux {
+    fn drop(&mut self) {
+        self.stop() //Shared state is kept alive by the threads and channels, so this doesn't create a dangling pointer.
+    }
+}
+
+/// One logical channel of a [ChannelMux].
+pub struct Channel {
+    id: u8,
+    channel: Arc<ChannelShared>,
+    mux: Arc<MuxShared>,
+}
+
+impl Channel {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Writes data to this channel, waiting until all of it is written.
+    pub fn write(&self, data: &[u8]) -> SerialError {
+        if data.is_empty() {
+            return SerialError::NoErr;
+        }
+
+        let chunks = data.chunks(CHUNK_LEN);
+        let ticket = Arc::new(Ticket {
+            state: Mutex::new((chunks.len(), SerialError::NoErr)),
+            done: Condvar::new(),
+        });
+
+        {
+            let mut writes = self.mux.writes.lock();
+            if writes.stopped {
+                return SerialError::Other;
+            }
+
+            let queue = writes.queues.entry(self.id).or_default();
+            for chunk in chunks {
+                let mut frame = Vec::with_capacity(chunk.len() + 1);
+                frame.push(self.id);
+                frame.extend_from_slice(chunk);
+
+                queue.push_back(Chunk {
+                    frame: cobs::encode(&frame),
+                    ticket: ticket.clone(),
+                });
+            }
+        }
+        self.mux.write_ready.notify_all();
+
+        let mut state = ticket.state.lock();
+        while state.0 > 0 {
+            ticket.done.wait(&mut state);
+        }
+        state.1
+    }
+
+    /// Reads data received on this channel, waiting up to the read timeout for some to arrive.
+    pub fn read(&self, read_buff: &mut [u8]) -> ReadResult {
+        let mut rx = match self.wait_for(|buf| !buf.is_empty()) {
+            Ok(rx) => rx,
+            Err(error) => {
+                return ReadResult {
+                    error,
+                    bytes_read: 0,
+                }
+            }
+        };
+
+        let bytes_read = read_buff.len().min(rx.buf.len());
+        for (dst, src) in read_buff.iter_mut().zip(rx.buf.drain(..bytes_read)) {
+            *dst = src;
+        }
+
+        ReadResult {
+            error: SerialError::NoErr,
+            bytes_read,
+        }
+    }
+
+    /// Reads a line received on this channel, waiting up to the read timeout for all of it to arrive.
+    pub fn read_line(&self, read_buff: Pin<&mut CxxString>) -> ReadResult {
+        let mut rx = match self.wait_for(|buf| buf.contains(&b'\n')) {
+            Ok(rx) => rx,
+            Err(error) => {
+                return ReadResult {
+                    error,
+                    bytes_read: 0,
+                }
+            }
+        };
+
+        let bytes_read = rx.buf.iter().position(|&byte| byte == b'\n').unwrap() + 1;
+        let line: Vec<u8> = rx.buf.drain(..bytes_read).collect();
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        read_buff.push_bytes(line.strip_suffix(b"\r").unwrap_or(line));
+
+        ReadResult {
+            error: SerialError::NoErr,
+            bytes_read,
+        }
+    }
+
+    /// Waits up to the read timeout for the received data to satisfy ready.
+    fn wait_for(
+        &self,
+        ready: impl Fn(&VecDeque<u8>) -> bool,
+    ) -> Result<parking_lot::MutexGuard<'_, ChannelRx>, SerialError> {
+        let deadline = Instant::now().checked_add(*self.channel.read_timeout.lock());
+        let mut rx = self.channel.rx.lock();
+
+        while !ready(&rx.buf) {
+            if self.mux.stopped() {
+                return Err(SerialError::Other);
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if self
+                        .channel
+                        .received
+                        .wait_until(&mut rx, deadline)
+                        .timed_out()
+                    {
+                        return Err(SerialError::Timeout);
+                    }
+                }
+                None => self.channel.received.wait(&mut rx),
+            }
+        }
+
+        Ok(rx)
+    }
+
+    /// Sets the timeout for reads on this channel. A negative or infinite value disables it.
+    pub fn set_read_timeout(&self, sec: f32) {
+        *self.channel.read_timeout.lock() = timeout_from_secs(sec);
+    }
+
+    /// Sets the callback that receives data on this channel, replacing any previous one.
+    pub fn set_callback(&self, callback: Option<(CVoidSend, ChannelCallback)>) {
+        self.channel.rx.lock().callback = callback;
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this channel
+    /// to the callback setter functions.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut Channel {
+        self as *mut Channel
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.mux.channels.lock().remove(&self.id);
+    }
+}
+
+/// Body of the writer thread.
+fn write_loop(shared: &MuxShared) {
+    loop {
+        let chunk = {
+            let mut writes = shared.writes.lock();
+            loop {
+                if writes.stopped {
+                    return;
+                }
+                match writes.next_chunk() {
+                    Some(chunk) => break chunk,
+                    None => shared.write_ready.wait(&mut writes),
+                }
+            }
+        };
+
+        let (error, _) = write_counted(&mut **shared.write_handle.lock(), &chunk.frame);
+        chunk.ticket.complete(error);
+    }
+}
+
+/// Body of the reader thread.
+fn read_loop(
+    shared: &MuxShared,
+    reader: &Mutex<BufReader<SerialPortReader>>,
+    token: &CancellationToken,
+) {
+    //Lock the reader while this mux is alive
+    let mut reader = reader.lock();
+    let mut deframer = CobsDeframer::new(DEFAULT_MAX_FRAME_LEN);
+
+    while !token.is_canceled() {
+        match reader.fill_buf() {
+            Ok([]) => {
+                log::warn!("Channel mux reader hit end of file");
+                break;
+            }
+            Ok(buf) => {
+                deframer.push(buf, &mut |frame| match frame {
+                    Ok(frame) => shared.handle_frame(frame),
+                    Err(error) => {
+                        shared.bad_frames.fetch_add(1, Ordering::Relaxed);
+                        log::debug!("Dropping frame: {:?}", error.repr);
+                    }
+                });
+                let len = buf.len();
+                reader.consume(len);
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("Channel mux reader failed: {}", err);
+                break;
+            }
+        }
+    }
+}
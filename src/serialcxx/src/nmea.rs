@@ -0,0 +1,435 @@
+//! NMEA 0183 parsing for listeners in NMEA mode, see [crate::SerialListenerBuilder::set_nmea_mode].
+
+use std::ffi::{c_void, CString};
+use std::io::{BufReader, ErrorKind};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use cancellation::CancellationToken;
+
+use crate::crc::xor8;
+use crate::ffi::{NmeaGga, NmeaGll, NmeaGsa, NmeaGsv, NmeaRmc, NmeaSatellite, NmeaStats, NmeaVtg};
+use crate::serial::ReadCallback;
+use crate::serial_ext::{read_line_bounded, CVoidSend, SerialPortReader};
+
+/// Callback for a parsed sentence. The sentence is only valid until the callback returns.
+pub type NmeaCallback<T> = unsafe extern "C" fn(user_data: *mut c_void, sentence: *const T);
+
+/// Talker ids of satellite receivers. Sentences from anything else are counted and dropped.
+const KNOWN_TALKERS: &[&str] = &["GP", "GL", "GA", "GB", "BD", "GQ", "QZ", "GI", "GN"];
+
+/// Longest line kept while waiting for a terminator. The standard limit is 82 characters, but some
+/// receivers exceed it.
+const MAX_LINE_LEN: usize = 1024;
+
+/// The per sentence type callbacks of a listener.
+#[derive(Default, Copy, Clone)]
+pub struct NmeaCallbacks {
+    pub gga: Option<(CVoidSend, NmeaCallback<NmeaGga>)>,
+    pub rmc: Option<(CVoidSend, NmeaCallback<NmeaRmc>)>,
+    pub vtg: Option<(CVoidSend, NmeaCallback<NmeaVtg>)>,
+    pub gsa: Option<(CVoidSend, NmeaCallback<NmeaGsa>)>,
+    pub gsv: Option<(CVoidSend, NmeaCallback<NmeaGsv>)>,
+    pub gll: Option<(CVoidSend, NmeaCallback<NmeaGll>)>,
+}
+
+impl NmeaCallbacks {
+    pub fn is_empty(&self) -> bool {
+        self.gga.is_none()
+            && self.rmc.is_none()
+            && self.vtg.is_none()
+            && self.gsa.is_none()
+            && self.gsv.is_none()
+            && self.gll.is_none()
+    }
+}
+
+/// Splits lines into sentences, checks them, and passes them to the callbacks.
+pub struct NmeaDecoder {
+    callbacks: NmeaCallbacks,
+    /// The listeners read callback, which receives valid sentences with no typed callback.
+    fallback: Option<(CVoidSend, ReadCallback)>,
+    sentences: AtomicU64,
+    bad_checksums: AtomicU64,
+    unknown_talkers: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl NmeaDecoder {
+    pub fn new(callbacks: NmeaCallbacks, fallback: Option<(CVoidSend, ReadCallback)>) -> Self {
+        NmeaDecoder {
+            callbacks,
+            fallback,
+            sentences: AtomicU64::new(0),
+            bad_checksums: AtomicU64::new(0),
+            unknown_talkers: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> NmeaStats {
+        NmeaStats {
+            sentences: self.sentences.load(Ordering::Relaxed),
+            bad_checksums: self.bad_checksums.load(Ordering::Relaxed),
+            unknown_talkers: self.unknown_talkers.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Handles one line read from the port.
+    pub fn handle_line(&self, line: &[u8]) {
+        let line = line.trim_ascii_end();
+        if line.is_empty() {
+            return;
+        }
+
+        //Anything before the last start character is noise, such as binary messages from the receiver
+        let sentence = match line.iter().rposition(|&byte| byte == b'$' || byte == b'!') {
+            Some(start) => &line[start..],
+            None => {
+                self.malformed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let body = match checked_body(sentence) {
+            Some(body) => body,
+            None => {
+                log::debug!("Dropping NMEA sentence with bad checksum");
+                self.bad_checksums.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let fields: Vec<&str> = body.split(',').collect();
+        let address = fields[0];
+
+        //Proprietary sentences have no talker, so they are passed through untouched
+        let parsed = if address.starts_with('P') {
+            Some(false)
+        } else if address.len() != 5 {
+            None
+        } else if !KNOWN_TALKERS.contains(&&address[..2]) {
+            log::debug!(
+                "Dropping NMEA sentence from unknown talker {}",
+                &address[..2]
+            );
+            self.unknown_talkers.fetch_add(1, Ordering::Relaxed);
+            return;
+        } else {
+            let talker = &address[..2];
+            match &address[2..] {
+                "GGA" => deliver(self.callbacks.gga, parse_gga(talker, &fields)),
+                "RMC" => deliver(self.callbacks.rmc, parse_rmc(talker, &fields)),
+                "VTG" => deliver(self.callbacks.vtg, parse_vtg(talker, &fields)),
+                "GSA" => deliver(self.callbacks.gsa, parse_gsa(talker, &fields)),
+                "GSV" => deliver(self.callbacks.gsv, parse_gsv(talker, &fields)),
+                "GLL" => deliver(self.callbacks.gll, parse_gll(talker, &fields)),
+                _ => Some(false),
+            }
+        };
+
+        match parsed {
+            Some(delivered) => {
+                self.sentences.fetch_add(1, Ordering::Relaxed);
+                if !delivered {
+                    self.deliver_raw(sentence);
+                }
+            }
+            None => {
+                log::debug!("Dropping malformed NMEA sentence {}", body);
+                self.malformed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Passes a sentence without a typed callback to the read callback.
+    fn deliver_raw(&self, sentence: &[u8]) {
+        if let Some((user_data, callback)) = self.fallback {
+            //Sentences are checked to be ascii, so this cannot fail
+            let c_str = CString::new(sentence).unwrap_or_default();
+
+            unsafe {
+                //Safe only if callback does not store a reference to the string, which it does not own.
+                callback(user_data.0, c_str.as_ptr(), sentence.len());
+            }
+        }
+    }
+}
+
+/// Calls callback with the parsed sentence, if both exist.
+///
+/// Returns None if the sentence did not parse, otherwise whether it was delivered.
+fn deliver<T>(callback: Option<(CVoidSend, NmeaCallback<T>)>, sentence: Option<T>) -> Option<bool> {
+    let sentence = sentence?;
+
+    match callback {
+        Some((user_data, callback)) => {
+            unsafe {
+                //Safe only if callback does not store a reference to the sentence, which it does not own.
+                callback(user_data.0, &sentence);
+            }
+            Some(true)
+        }
+        None => Some(false),
+    }
+}
+
+/// Checks the `*hh` checksum of a sentence starting with its start character, returning the text
+/// between the start character and the checksum.
+fn checked_body(sentence: &[u8]) -> Option<&str> {
+    let sentence = std::str::from_utf8(sentence).ok()?;
+    if !sentence.is_ascii() {
+        return None;
+    }
+
+    let (body, checksum) = sentence[1..].split_once('*')?;
+    if checksum.len() != 2 {
+        return None;
+    }
+    let checksum = u8::from_str_radix(checksum, 16).ok()?;
+
+    (xor8(body.as_bytes()) == checksum).then_some(body)
+}
+
+/// Gets an optional trailing field, which older receivers leave out entirely.
+fn field<'a>(fields: &[&'a str], idx: usize) -> &'a str {
+    fields.get(idx).copied().unwrap_or_default()
+}
+
+/// Parses a number, mapping an empty field to missing.
+fn number<T: FromStr>(field: &str, missing: T) -> Option<T> {
+    if field.is_empty() {
+        Some(missing)
+    } else {
+        field.parse().ok()
+    }
+}
+
+/// Parses a real number, mapping an empty field to NaN.
+fn real(field: &str) -> Option<f64> {
+    number(field, f64::NAN)
+}
+
+/// Parses a ddmm.mmmm or dddmm.mmmm coordinate into signed degrees, south and west being negative.
+fn coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    if value.is_empty() {
+        return Some(f64::NAN);
+    }
+
+    let raw: f64 = value.parse().ok()?;
+    let degrees = (raw / 100.0).trunc();
+    let degrees = degrees + (raw - degrees * 100.0) / 60.0;
+
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+/// Parses hhmmss.ss into seconds since midnight UTC.
+fn time(field: &str) -> Option<f64> {
+    if field.is_empty() {
+        return Some(f64::NAN);
+    }
+    if field.len() < 6 || !field[..4].bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: f64 = field[..2].parse().ok()?;
+    let minutes: f64 = field[2..4].parse().ok()?;
+    let seconds: f64 = field[4..].parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Parses ddmmyy into day, month and four digit year, all zero if the field is empty.
+fn date(field: &str) -> Option<(u8, u8, u16)> {
+    if field.is_empty() {
+        return Some((0, 0, 0));
+    }
+    if field.len() != 6 || !field.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let year: u16 = field[4..].parse().ok()?;
+    //GNSS dates cannot be before 1980
+    let year = if year < 80 { 2000 + year } else { 1900 + year };
+    Some((field[..2].parse().ok()?, field[2..4].parse().ok()?, year))
+}
+
+/// The first character of a single character field, or 0 if it is empty.
+fn flag(field: &str) -> u8 {
+    field.bytes().next().unwrap_or(0)
+}
+
+fn parse_gga(talker: &str, fields: &[&str]) -> Option<NmeaGga> {
+    if fields.len() < 15 {
+        return None;
+    }
+
+    Some(NmeaGga {
+        talker: talker.to_string(),
+        time: time(fields[1])?,
+        latitude: coordinate(fields[2], fields[3])?,
+        longitude: coordinate(fields[4], fields[5])?,
+        fix_quality: number(fields[6], 0)?,
+        satellites: number(fields[7], 0)?,
+        hdop: real(fields[8])?,
+        altitude: real(fields[9])?,
+        geoid_separation: real(fields[11])?,
+        dgps_age: real(fields[13])?,
+        dgps_station: number(fields[14], -1)?,
+    })
+}
+
+fn parse_rmc(talker: &str, fields: &[&str]) -> Option<NmeaRmc> {
+    if fields.len() < 12 {
+        return None;
+    }
+
+    let (day, month, year) = date(fields[9])?;
+    let variation = real(fields[10])?;
+
+    Some(NmeaRmc {
+        talker: talker.to_string(),
+        time: time(fields[1])?,
+        valid: fields[2] == "A",
+        latitude: coordinate(fields[3], fields[4])?,
+        longitude: coordinate(fields[5], fields[6])?,
+        speed_knots: real(fields[7])?,
+        course: real(fields[8])?,
+        day,
+        month,
+        year,
+        magnetic_variation: if fields[11] == "W" {
+            -variation
+        } else {
+            variation
+        },
+        mode: flag(field(fields, 12)),
+    })
+}
+
+fn parse_vtg(talker: &str, fields: &[&str]) -> Option<NmeaVtg> {
+    if fields.len() < 9 {
+        return None;
+    }
+
+    Some(NmeaVtg {
+        talker: talker.to_string(),
+        course_true: real(fields[1])?,
+        course_magnetic: real(fields[3])?,
+        speed_knots: real(fields[5])?,
+        speed_kmh: real(fields[7])?,
+        mode: flag(field(fields, 9)),
+    })
+}
+
+fn parse_gsa(talker: &str, fields: &[&str]) -> Option<NmeaGsa> {
+    if fields.len() < 18 {
+        return None;
+    }
+
+    let satellites = fields[3..15]
+        .iter()
+        .filter(|prn| !prn.is_empty())
+        .map(|prn| prn.parse().ok())
+        .collect::<Option<Vec<u16>>>()?;
+
+    Some(NmeaGsa {
+        talker: talker.to_string(),
+        automatic: fields[1] == "A",
+        fix_type: number(fields[2], 0)?,
+        satellites,
+        pdop: real(fields[15])?,
+        hdop: real(fields[16])?,
+        vdop: real(fields[17])?,
+        system_id: number(field(fields, 18), -1)?,
+    })
+}
+
+fn parse_gsv(talker: &str, fields: &[&str]) -> Option<NmeaGsv> {
+    if fields.len() < 4 {
+        return None;
+    }
+
+    //Satellites come in groups of four, optionally followed by a signal id
+    let groups = &fields[4..];
+    let (groups, signal_id) = match groups.len() % 4 {
+        0 => (groups, ""),
+        1 => (&groups[..groups.len() - 1], groups[groups.len() - 1]),
+        _ => return None,
+    };
+
+    let satellites = groups
+        .chunks_exact(4)
+        .filter(|group| !group[0].is_empty())
+        .map(|group| {
+            Some(NmeaSatellite {
+                prn: group[0].parse().ok()?,
+                elevation: number(group[1], -1)?,
+                azimuth: number(group[2], -1)?,
+                snr: number(group[3], -1)?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(NmeaGsv {
+        talker: talker.to_string(),
+        total_messages: number(fields[1], 0)?,
+        message_number: number(fields[2], 0)?,
+        satellites_in_view: number(fields[3], 0)?,
+        satellites,
+        signal_id: number(signal_id, -1)?,
+    })
+}
+
+fn parse_gll(talker: &str, fields: &[&str]) -> Option<NmeaGll> {
+    if fields.len() < 5 {
+        return None;
+    }
+
+    Some(NmeaGll {
+        talker: talker.to_string(),
+        latitude: coordinate(fields[1], fields[2])?,
+        longitude: coordinate(fields[3], fields[4])?,
+        time: time(field(fields, 5))?,
+        valid: field(fields, 6) == "A",
+        mode: flag(field(fields, 7)),
+    })
+}
+
+/// Body of a listener thread in NMEA mode.
+pub fn read_loop(
+    reader: &mut BufReader<SerialPortReader>,
+    decoder: &NmeaDecoder,
+    token: &CancellationToken,
+) {
+    let mut line = Vec::new();
+
+    while !token.is_canceled() {
+        match read_line_bounded(reader, &mut line, MAX_LINE_LEN) {
+            Ok(0) => {
+                log::warn!("NMEA listener hit end of file");
+                break;
+            }
+            Ok(_) if line.ends_with(b"\n") => {
+                decoder.handle_line(&line);
+                line.clear();
+            }
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("NMEA listener failed: {}", err);
+                break;
+            }
+        }
+
+        //Binary data with no newlines would otherwise grow the line forever
+        if line.len() > MAX_LINE_LEN {
+            decoder.malformed.fetch_add(1, Ordering::Relaxed);
+            line.clear();
+        }
+    }
+}
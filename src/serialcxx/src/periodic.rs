@@ -0,0 +1,150 @@
+//! Periodic writes, created with [crate::Serial::schedule_periodic].
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Condvar;
+use serialport::SerialPort;
+
+use crate::ffi::SerialError;
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+struct ScheduleState {
+    interval: Duration,
+    /// Set when the interval changes, so the scheduler can rebase its next deadline.
+    interval_changed: bool,
+    cancelled: bool,
+    last_error: SerialError,
+}
+
+struct Shared {
+    state: Mutex<ScheduleState>,
+    /// Signalled on cancellation and interval changes.
+    changed: Condvar,
+}
+
+/// Handle to a message being written to the port at a fixed interval.
+///
+/// Each handle owns a thread that writes through the same locked handle as [crate::Serial::write].
+/// Deadlines are computed from the time of the first write rather than from the end of the previous
+/// write, so the rate does not drift. If a write overruns one or more periods, those ticks are
+/// skipped instead of being written back to back.
+///
+/// Dropping the handle cancels the schedule.
+pub struct PeriodicWrite {
+    shared: Arc<Shared>,
+}
+
+impl PeriodicWrite {
+    /// Starts writing data every interval, beginning immediately.
+    pub fn start(
+        port: Arc<Mutex<Box<dyn SerialPort>>>,
+        data: Vec<u8>,
+        interval: Duration,
+    ) -> PeriodicWrite {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(ScheduleState {
+                interval,
+                interval_changed: false,
+                cancelled: false,
+                last_error: SerialError::NoErr,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread_shared = shared.clone();
+        std::thread::spawn(move || {
+            log::debug!("Spawned periodic writer");
+            schedule_loop(&thread_shared, &port, &data);
+            log::debug!("exiting periodic writer thread")
+        });
+        //Thread detaches here
+
+        PeriodicWrite { shared }
+    }
+
+    /// Stops the schedule. A write already in progress will complete, but no more will start.
+    pub fn cancel(&self) {
+        self.shared.state.lock().cancelled = true;
+        self.shared.changed.notify_all();
+    }
+
+    /// Changes the interval between writes. The next write happens one new interval after the
+    /// previous one.
+    ///
+    /// Returns false if the interval is not a positive number of seconds.
+    pub fn set_interval(&self, interval: f32) -> bool {
+        match Duration::try_from_secs_f32(interval) {
+            Ok(interval) if !interval.is_zero() => {
+                let mut state = self.shared.state.lock();
+                state.interval = interval;
+                state.interval_changed = true;
+                self.shared.changed.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the result of the most recent write.
+    pub fn last_error(&self) -> SerialError {
+        self.shared.state.lock().last_error
+    }
+}
+
+impl Drop for PeriodicWrite {
+    fn drop(&mut self) {
+        self.cancel() //The thread keeps its own reference to the shared state, so it can exit on its own time.
+    }
+}
+
+/// Body of the scheduler thread.
+fn schedule_loop(shared: &Shared, port: &Mutex<Box<dyn SerialPort>>, data: &[u8]) {
+    let mut last = Instant::now();
+    //None when the next write is too far away to represent, so it never comes
+    let mut next = Some(last);
+
+    loop {
+        let due = {
+            let mut state = shared.state.lock();
+            loop {
+                if state.cancelled {
+                    return;
+                }
+                if state.interval_changed {
+                    state.interval_changed = false;
+                    next = last.checked_add(state.interval);
+                }
+                match next {
+                    Some(due) if Instant::now() >= due => break due,
+                    Some(due) => {
+                        shared.changed.wait_until(&mut state, due);
+                    }
+                    None => shared.changed.wait(&mut state),
+                }
+            }
+        };
+
+        let (error, _) = write_counted(&mut **port.lock(), data);
+        if error != SerialError::NoErr {
+            log::warn!("Periodic write failed: {:?}", error.repr);
+        }
+
+        let mut state = shared.state.lock();
+        state.last_error = error;
+
+        //Advance by whole periods, skipping any we overran
+        let interval = state.interval;
+        last = due;
+        next = due.checked_add(interval);
+        let now = Instant::now();
+        if let Some(behind_since) = next.filter(|&next| next < now) {
+            let behind = (now - behind_since).as_nanos() / interval.as_nanos();
+            next = behind_since.checked_add(interval * (behind + 1).min(u32::MAX as u128) as u32);
+            if let Some(next) = next {
+                last = next - interval;
+            }
+        }
+    }
+}
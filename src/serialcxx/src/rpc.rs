@@ -0,0 +1,516 @@
+//! Request/response RPC with correlation ids, created with [crate::Serial::create_rpc_endpoint].
+//!
+//! Every message is a COBS frame: a kind byte, a little endian u32 correlation id, the length of the
+//! method name, the method name, and the payload. Responses and errors carry the id of their request
+//! and no method. Error payloads are UTF-8 messages, everything else uses the endpoints encoding.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use parking_lot::Condvar;
+use serialport::SerialPort;
+
+use crate::cobs::{self, CobsDeframer};
+use crate::ffi::{RpcEncoding, RpcResult, RpcStats, RpcStatus, SerialError};
+use crate::framing::{Deframer, DEFAULT_MAX_FRAME_LEN};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{CVoidSend, SerialPortReader};
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+const KIND_REQUEST: u8 = 0x01;
+const KIND_RESPONSE: u8 = 0x02;
+const KIND_ERROR: u8 = 0x03;
+
+/// Kind, id and method length.
+const HEADER_LEN: usize = 6;
+
+/// CBOR encoding of null, sent for an empty CBOR payload.
+const CBOR_NULL: u8 = 0xF6;
+
+/// Callback for the result of [call_async]. The result is only valid until the callback returns.
+pub type RpcCallback = unsafe extern "C" fn(user_data: *mut c_void, result: *const RpcResult);
+/// Handler for requests from the device. Leaving reply untouched sends an empty result.
+pub type RpcHandler = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    payload: *const u8,
+    payload_size: usize,
+    reply: *mut RpcReply,
+);
+
+impl RpcEncoding {
+    /// Converts a payload from C++ into its wire form. JSON payloads are given as JSON text, and CBOR
+    /// payloads as encoded CBOR, which is checked but sent unchanged. An empty payload stands for null.
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        match *self {
+            RpcEncoding::Json => {
+                let value: serde_json::Value = if payload.is_empty() {
+                    serde_json::Value::Null
+                } else {
+                    serde_json::from_slice(payload).map_err(|err| err.to_string())?
+                };
+                Ok(serde_json::to_vec(&value).unwrap())
+            }
+            RpcEncoding::Cbor if payload.is_empty() => Ok(vec![CBOR_NULL]),
+            RpcEncoding::Cbor => {
+                check_cbor(payload)?;
+                Ok(payload.to_vec())
+            }
+            _ => Ok(payload.to_vec()),
+        }
+    }
+
+    /// Converts a payload from the wire into the form C++ sees, compact JSON text for JSON. CBOR is
+    /// checked and passed through, so byte strings, tags and non-text map keys survive.
+    fn decode(&self, wire: &[u8]) -> Result<Vec<u8>, String> {
+        match *self {
+            RpcEncoding::Json => {
+                let value: serde_json::Value =
+                    serde_json::from_slice(wire).map_err(|err| err.to_string())?;
+                Ok(serde_json::to_vec(&value).unwrap())
+            }
+            RpcEncoding::Cbor => {
+                check_cbor(wire)?;
+                Ok(wire.to_vec())
+            }
+            _ => Ok(wire.to_vec()),
+        }
+    }
+}
+
+/// Checks that data is exactly one well formed CBOR item.
+fn check_cbor(mut data: &[u8]) -> Result<(), String> {
+    ciborium::from_reader::<ciborium::Value, _>(&mut data).map_err(|err| err.to_string())?;
+    if data.is_empty() {
+        Ok(())
+    } else {
+        Err("Trailing bytes after CBOR item.".to_string())
+    }
+}
+
+/// The reply a handler builds for a request from the device.
+pub struct RpcReply {
+    result: Result<Vec<u8>, String>,
+}
+
+impl RpcReply {
+    /// Replies with payload, in the same form [RpcEndpoint::call] takes.
+    pub fn set_result(&mut self, payload: &[u8]) {
+        self.result = Ok(payload.to_vec());
+    }
+
+    /// Replies with an error message instead of a result.
+    pub fn set_error(&mut self, message: &str) {
+        self.result = Err(message.to_string());
+    }
+}
+
+enum Waiter {
+    Blocking(Arc<(Mutex<Option<RpcResult>>, Condvar)>),
+    Callback(CVoidSend, RpcCallback),
+}
+
+struct Pending {
+    deadline: Option<Instant>,
+    waiter: Waiter,
+}
+
+struct Calls {
+    pending: HashMap<u32, Pending>,
+    stopped: bool,
+}
+
+struct Shared {
+    encoding: RpcEncoding,
+    calls: Mutex<Calls>,
+    /// Notified when a call is added, or the endpoint is stopped.
+    calls_changed: Condvar,
+    handlers: Mutex<HashMap<String, (CVoidSend, RpcHandler)>>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    next_id: AtomicU32,
+    requests_sent: AtomicU64,
+    requests_handled: AtomicU64,
+    timeouts: AtomicU64,
+    unmatched: AtomicU64,
+    bad_frames: AtomicU64,
+}
+
+fn result(status: RpcStatus, payload: Vec<u8>, message: String) -> RpcResult {
+    RpcResult {
+        status,
+        payload,
+        message,
+    }
+}
+
+impl Shared {
+    fn write_message(&self, kind: u8, id: u32, method: &str, payload: &[u8]) -> bool {
+        let mut frame = Vec::with_capacity(HEADER_LEN + method.len() + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.push(method.len() as u8);
+        frame.extend_from_slice(method.as_bytes());
+        frame.extend_from_slice(payload);
+
+        let (error, _) = write_counted(&mut **self.write_handle.lock(), &cobs::encode(&frame));
+        error == SerialError::NoErr
+    }
+
+    /// Removes a pending call and passes it result, if it is still waiting.
+    fn complete(&self, id: u32, result: RpcResult) -> bool {
+        let pending = match self.calls.lock().pending.remove(&id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+
+        match pending.waiter {
+            Waiter::Blocking(slot) => {
+                *slot.0.lock() = Some(result);
+                slot.1.notify_all();
+            }
+            Waiter::Callback(user_data, callback) => unsafe {
+                callback(user_data.0, &result);
+            },
+        }
+        true
+    }
+
+    /// Sends a request, registering waiter for its response first so it cannot be missed.
+    fn start_call(&self, method: &str, payload: &[u8], timeout: f32, waiter: Waiter) -> u32 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let deadline = Instant::now().checked_add(timeout_from_secs(timeout));
+
+        let wire = if method.len() > u8::MAX as usize {
+            Err("Method name is over 255 bytes.".to_string())
+        } else {
+            self.encoding.encode(payload)
+        };
+
+        {
+            let mut calls = self.calls.lock();
+            if calls.stopped {
+                drop(calls);
+                self.finish_unsent(waiter, RpcStatus::Stopped, String::new());
+                return id;
+            }
+            calls.pending.insert(id, Pending { deadline, waiter });
+        }
+        self.calls_changed.notify_all();
+
+        match wire {
+            Ok(wire) => {
+                self.requests_sent.fetch_add(1, Ordering::Relaxed);
+                if !self.write_message(KIND_REQUEST, id, method, &wire) {
+                    self.complete(id, result(RpcStatus::PortErr, Vec::new(), String::new()));
+                }
+            }
+            Err(message) => {
+                self.complete(id, result(RpcStatus::EncodingError, Vec::new(), message));
+            }
+        }
+        id
+    }
+
+    fn finish_unsent(&self, waiter: Waiter, status: RpcStatus, message: String) {
+        let result = result(status, Vec::new(), message);
+        match waiter {
+            Waiter::Blocking(slot) => *slot.0.lock() = Some(result),
+            Waiter::Callback(user_data, callback) => unsafe {
+                callback(user_data.0, &result);
+            },
+        }
+    }
+
+    fn handle_frame(&self, frame: &[u8]) {
+        if frame.len() < HEADER_LEN || frame.len() < HEADER_LEN + frame[5] as usize {
+            self.bad_frames.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let kind = frame[0];
+        let id = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        let (method, payload) = frame[HEADER_LEN..].split_at(frame[5] as usize);
+
+        let matched = match kind {
+            KIND_REQUEST => {
+                self.handle_request(id, &String::from_utf8_lossy(method), payload);
+                true
+            }
+            KIND_RESPONSE => self.complete(
+                id,
+                match self.encoding.decode(payload) {
+                    Ok(payload) => result(RpcStatus::Ok, payload, String::new()),
+                    Err(message) => result(RpcStatus::EncodingError, Vec::new(), message),
+                },
+            ),
+            KIND_ERROR => self.complete(
+                id,
+                result(
+                    RpcStatus::RemoteError,
+                    Vec::new(),
+                    String::from_utf8_lossy(payload).into_owned(),
+                ),
+            ),
+            _ => {
+                self.bad_frames.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        if !matched {
+            //Late responses to calls that timed out end up here
+            self.unmatched.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn handle_request(&self, id: u32, method: &str, payload: &[u8]) {
+        self.requests_handled.fetch_add(1, Ordering::Relaxed);
+        let handler = self.handlers.lock().get(method).copied();
+
+        let reply = match (handler, self.encoding.decode(payload)) {
+            (None, _) => Err(format!("Unknown method {}", method)),
+            (_, Err(message)) => Err(message),
+            (Some((user_data, handler)), Ok(payload)) => {
+                let mut reply = RpcReply {
+                    result: Ok(Vec::new()),
+                };
+                unsafe {
+                    handler(user_data.0, payload.as_ptr(), payload.len(), &mut reply);
+                }
+                reply
+                    .result
+                    .and_then(|payload| self.encoding.encode(&payload))
+            }
+        };
+
+        let sent = match reply {
+            Ok(payload) => self.write_message(KIND_RESPONSE, id, "", &payload),
+            Err(message) => self.write_message(KIND_ERROR, id, "", message.as_bytes()),
+        };
+        if !sent {
+            log::warn!("RPC endpoint failed to write response to {}", id);
+        }
+    }
+}
+
+/// An RPC endpoint on a port, with its reader and timeout threads.
+pub struct RpcEndpoint {
+    shared: Arc<Shared>,
+    /// Token used to kill the reader thread.
+    cts: CancellationTokenSource,
+}
+
+impl RpcEndpoint {
+    /// Creates an endpoint and starts its threads.
+    pub fn start(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+        encoding: RpcEncoding,
+    ) -> RpcEndpoint {
+        let shared = Arc::new(Shared {
+            encoding,
+            calls: Mutex::new(Calls {
+                pending: HashMap::new(),
+                stopped: false,
+            }),
+            calls_changed: Condvar::new(),
+            handlers: Mutex::new(HashMap::new()),
+            write_handle,
+            next_id: AtomicU32::new(0),
+            requests_sent: AtomicU64::new(0),
+            requests_handled: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            unmatched: AtomicU64::new(0),
+            bad_frames: AtomicU64::new(0),
+        });
+        let cts = CancellationTokenSource::new();
+
+        let token = cts.token().clone();
+        let thread_shared = shared.clone();
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = reader.lock();
+
+        let thread_reader = reader.clone();
+        std::thread::spawn(move || {
+            log::debug!("Spawned RPC reader");
+            read_loop(&thread_shared, &thread_reader, &token);
+            log::debug!("exiting RPC reader thread")
+        });
+
+        let thread_shared = shared.clone();
+        std::thread::spawn(move || {
+            timeout_loop(&thread_shared);
+            log::debug!("exiting RPC timeout thread")
+        });
+        //Threads detach here
+
+        RpcEndpoint { shared, cts }
+    }
+
+    /// Calls method on the device, waiting up to timeout seconds for its response.
+    pub fn call(&self, method: &str, payload: &[u8], timeout: f32) -> RpcResult {
+        let slot = Arc::new((Mutex::new(None), Condvar::new()));
+        self.shared
+            .start_call(method, payload, timeout, Waiter::Blocking(slot.clone()));
+
+        let mut result = slot.0.lock();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            slot.1.wait(&mut result);
+        }
+    }
+
+    /// Calls method on the device, passing the response to callback from another thread.
+    pub fn call_async(
+        &self,
+        method: &str,
+        payload: &[u8],
+        timeout: f32,
+        callback: (CVoidSend, RpcCallback),
+    ) -> u32 {
+        self.shared.start_call(
+            method,
+            payload,
+            timeout,
+            Waiter::Callback(callback.0, callback.1),
+        )
+    }
+
+    /// Sets the handler for requests to method, replacing any previous one.
+    pub fn set_handler(&self, method: &str, handler: Option<(CVoidSend, RpcHandler)>) {
+        let mut handlers = self.shared.handlers.lock();
+        match handler {
+            Some(handler) => handlers.insert(method.to_string(), handler),
+            None => handlers.remove(method),
+        };
+    }
+
+    /// Gets the number of calls waiting for a response.
+    pub fn in_flight(&self) -> usize {
+        self.shared.calls.lock().pending.len()
+    }
+
+    pub fn stats(&self) -> RpcStats {
+        let shared = &self.shared;
+        RpcStats {
+            requests_sent: shared.requests_sent.load(Ordering::Relaxed),
+            requests_handled: shared.requests_handled.load(Ordering::Relaxed),
+            timeouts: shared.timeouts.load(Ordering::Relaxed),
+            unmatched: shared.unmatched.load(Ordering::Relaxed),
+            bad_frames: shared.bad_frames.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops both threads, failing any calls still waiting.
+    pub fn stop(&self) {
+        self.cts.cancel();
+
+        let ids: Vec<u32> = {
+            let mut calls = self.shared.calls.lock();
+            calls.stopped = true;
+            calls.pending.keys().copied().collect()
+        };
+        self.shared.calls_changed.notify_all();
+
+        for id in ids {
+            self.shared
+                .complete(id, result(RpcStatus::Stopped, Vec::new(), String::new()));
+        }
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this endpoint
+    /// to the callback setter functions.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut RpcEndpoint {
+        self as *mut RpcEndpoint
+    }
+}
+
+impl Drop for RpcEndpoint {
+    fn drop(&mut self) {
+        self.stop() //Shared state is kept alive by the threads, so this doesn't create a dangling pointer.
+    }
+}
+
+/// Body of the timeout thread, which fails calls that pass their deadline.
+fn timeout_loop(shared: &Shared) {
+    let mut calls = shared.calls.lock();
+
+    while !calls.stopped {
+        let now = Instant::now();
+        let expired: Vec<u32> = calls
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(&id, _)| id)
+            .collect();
+
+        if !expired.is_empty() {
+            //Callbacks may start new calls, so they run without the lock
+            drop(calls);
+            for id in expired {
+                if shared.complete(id, result(RpcStatus::Timeout, Vec::new(), String::new())) {
+                    shared.timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            calls = shared.calls.lock();
+            continue;
+        }
+
+        match calls
+            .pending
+            .values()
+            .filter_map(|pending| pending.deadline)
+            .min()
+        {
+            Some(deadline) => {
+                shared.calls_changed.wait_until(&mut calls, deadline);
+            }
+            None => shared.calls_changed.wait(&mut calls),
+        }
+    }
+}
+
+/// Body of the reader thread.
+fn read_loop(
+    shared: &Shared,
+    reader: &Mutex<BufReader<SerialPortReader>>,
+    token: &CancellationToken,
+) {
+    //Lock the reader while this endpoint is alive
+    let mut reader = reader.lock();
+    let mut deframer = CobsDeframer::new(DEFAULT_MAX_FRAME_LEN);
+
+    while !token.is_canceled() {
+        match reader.fill_buf() {
+            Ok([]) => {
+                log::warn!("RPC reader hit end of file");
+                break;
+            }
+            Ok(buf) => {
+                deframer.push(buf, &mut |frame| match frame {
+                    Ok(frame) => shared.handle_frame(frame),
+                    Err(error) => {
+                        shared.bad_frames.fetch_add(1, Ordering::Relaxed);
+                        log::debug!("Dropping frame: {:?}", error.repr);
+                    }
+                });
+                let len = buf.len();
+                reader.consume(len);
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("RPC reader failed: {}", err);
+                break;
+            }
+        }
+    }
+}
@@ -5,19 +5,52 @@ use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
 use std::os::raw::c_char;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use cancellation::CancellationTokenSource;
-use cxx::{CxxString};
+use cxx::{CxxString, CxxVector};
 use serialport::{DataBits, Error, Result, SerialPort, StopBits};
 
-use crate::ffi::{CharSize, FlowControl, Parity, ReadResult, SerialError};
-use crate::serial_ext::{CVoidSend, SerialPortReader};
+use crate::arq::ReliableLink;
+use crate::at::AtClient;
+use crate::cobs;
+use crate::expect::{compile_patterns, expect};
+use crate::ffi::{
+    ArqConfig, CharSize, ExpectResult, FlowControl, FrameReadResult, FrameStats, HdlcFcs,
+    JsonStats, KissFrameResult, LengthFraming, MatchKind, ModbusServerConfig, NmeaStats, Parity,
+    ReadResult, RpcEncoding, SerialError, Stm32Config, TelemetrySchema, TelemetryStats,
+    TransactResult, TransferConfig, WritePriority,
+};
+use crate::framing::{self, read_frame, FrameCallbacks, FrameSink, Framing, DEFAULT_MAX_FRAME_LEN};
+use crate::hdlc::{self, HdlcDeframer};
+use crate::json_lines::{self, JsonDecoder, JsonErrorCallback};
+use crate::length_frame::{check_config, LengthDeframer};
+use crate::modbus_master::ModbusMaster;
+use crate::modbus_server::ModbusServer;
+use crate::mux::ChannelMux;
+use crate::nmea::{self, NmeaCallbacks, NmeaDecoder};
+use crate::periodic::PeriodicWrite;
+use crate::rpc::RpcEndpoint;
+#[cfg(unix)]
+use crate::serial_ext::set_vmin_vtime;
+use crate::serial_ext::{CVoidSend, DeadlineGuard, SerialPortReader};
+use crate::slip::{self, kiss_split, SlipDeframer};
+use crate::stm32::Stm32Bootloader;
+use crate::telemetry::{self, check_schema, TelemetryCallback, TelemetryDecoder};
+use crate::transact::{await_response, Matcher};
+use crate::ubx::UbxClient;
+use crate::write_queue::{write_counted, WriteCallback, WriteQueue};
+use crate::xmodem::FileTransfer;
 
 pub(crate) type Mutex<T> = parking_lot::Mutex<T>;
 pub(crate) type MutexGuard<'a, T> = parking_lot::MutexGuard<'a, T>;
+/// Callback a listener passes each line read to.
+pub type ReadCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, string_read: *const c_char, str_size: usize);
 
 /// The Rust side of the serial facade.
 ///
@@ -45,11 +78,19 @@ pub(crate) type MutexGuard<'a, T> = parking_lot::MutexGuard<'a, T>;
 /// until the thread dies. Once the thread dies, there will be a race on the mutex. It is for this reason
 /// that there should be no more than one listener alive at once.
 pub struct Serial {
-    write_handle: Mutex<Box<dyn SerialPort>>,
+    /// Shared with the background writer of [write_queue].
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    /// Writes queued through [Serial::write_async].
+    write_queue: WriteQueue,
+    /// Held for the duration of a [Serial::transact] or [Serial::expect], so concurrent transactions cannot steal each other's replies.
+    transaction_lock: Mutex<()>,
     /// Shared mutex over a reader (shared between main and listener threads) that houses a shared mutex to a handle (Shared to allow for changing settings across all readers).
     read_handle: Arc<Mutex<BufReader<SerialPortReader>>>, //A handle wrapped in a bufreader to allow for using read_line.
     /// Same shared mutex to handle as is inside of [read_handle].
     read_settings_handle: Arc<Mutex<Box<dyn SerialPort>>>, //A reference to the handle above, but not wrapped to allow for changing settings.
+    /// Raw descriptor of the write handle, used for termios settings serialport does not expose.
+    #[cfg(unix)]
+    raw_fd: RawFd,
 }
 
 impl Serial {
@@ -57,7 +98,10 @@ impl Serial {
         //Create two handles, one for reading, and one for writing.
         let raw_port = serialport::new(path, baud)
             .timeout(Duration::from_secs(99999))
-            .open()?;
+            .open_native()?;
+        #[cfg(unix)]
+        let raw_fd = raw_port.as_raw_fd();
+        let raw_port: Box<dyn SerialPort> = Box::new(raw_port);
 
         //Create shared handle
         let port_clone = Arc::from(Mutex::from(raw_port.try_clone()?));
@@ -67,16 +111,23 @@ impl Serial {
         //Move og handle into a wrapper object that impls Read by delegating to SerialPorts impl, bypassing Rust's lack of Trait casting.
         let port_reader = SerialPortReader::new(port_clone);
 
+        let write_handle = Arc::new(Mutex::new(raw_port));
+
         Ok(Serial {
-            write_handle: Mutex::new(raw_port),
+            write_queue: WriteQueue::new(write_handle.clone()),
+            transaction_lock: Mutex::new(()),
+            write_handle,
             read_handle: Arc::new(Mutex::new(BufReader::new(port_reader))),
             read_settings_handle: port_reader_settings,
+            #[cfg(unix)]
+            raw_fd,
         })
     }
 
     /// Locks both mutexes, and returns their handles.
     /// This is used to sync settings between the read and write handles.
     /// This shouldn't be a performance issue, as users should not be changing settings frequently.
+    #[allow(clippy::type_complexity)]
     fn lock_both_handles(
         &mut self,
     ) -> (
@@ -89,23 +140,77 @@ impl Serial {
         (read_settings_lock, write_lock)
     }
 
-    /// Sets the timeout for this port.
+    /// Sets the timeout for this port, applying it to both reads and writes.
+    ///
+    /// A negative or infinite value disables the timeout.
     ///
     /// Returns true if the operation succeeded.
     pub fn set_timeout(&mut self, sec: f32) -> bool {
         let (mut read_handle, mut write_handle) = self.lock_both_handles();
 
-        let read_res = read_handle
-            .set_timeout(Duration::from_secs_f32(sec))
-            .is_ok();
+        let read_res = read_handle.set_timeout(timeout_from_secs(sec)).is_ok();
 
-        let write_res = write_handle
-            .set_timeout(Duration::from_secs_f32(sec))
-            .is_ok();
+        let write_res = write_handle.set_timeout(timeout_from_secs(sec)).is_ok();
 
         read_res && write_res
     }
 
+    /// Sets the timeout for reads only. This also applies to any listeners on this port.
+    ///
+    /// A negative or infinite value disables the timeout.
+    ///
+    /// Returns true if the operation succeeded.
+    pub fn set_read_timeout(&mut self, sec: f32) -> bool {
+        self.read_settings_handle
+            .lock()
+            .set_timeout(timeout_from_secs(sec))
+            .is_ok()
+    }
+
+    /// Sets the timeout for writes only.
+    ///
+    /// A negative or infinite value disables the timeout.
+    ///
+    /// Returns true if the operation succeeded.
+    pub fn set_write_timeout(&mut self, sec: f32) -> bool {
+        self.write_handle
+            .lock()
+            .set_timeout(timeout_from_secs(sec))
+            .is_ok()
+    }
+
+    /// Sets the termios read semantics of this port, VMIN and VTIME.
+    ///
+    /// Once the first byte has arrived within the ports timeout, reads will block until either
+    /// `min_bytes` bytes are available, or no byte has been received for `inter_char_timeout` seconds.
+    /// `inter_char_timeout` has a resolution of 0.1 seconds, and is clamped to 25.5 seconds.
+    /// Setting both to 0 returns whatever is available as soon as the port becomes readable.
+    ///
+    /// A nonzero `min_bytes` with an `inter_char_timeout` that rounds to 0 is refused, as reads would
+    /// then block until `min_bytes` arrive, ignoring every timeout and deadline.
+    ///
+    /// Returns true if the operation succeeded. This always fails on platforms without termios.
+    pub fn set_read_semantics(&mut self, min_bytes: u8, inter_char_timeout: f32) -> bool {
+        #[cfg(unix)]
+        {
+            let fd = self.raw_fd;
+            let vtime = (inter_char_timeout * 10.0).round().clamp(0.0, 255.0) as u8;
+            if min_bytes > 0 && vtime == 0 {
+                return false;
+            }
+
+            //Hold both handles to avoid racing serialport's own termios updates
+            let _handles = self.lock_both_handles();
+            set_vmin_vtime(fd, min_bytes, vtime).is_ok()
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = (min_bytes, inter_char_timeout);
+            false
+        }
+    }
+
     /// Sets the character size of this port.
     ///
     /// Returns true if the operation succeeded.
@@ -249,6 +354,72 @@ impl Serial {
         }
     }
 
+    /// COBS encodes data and writes it as a single frame, followed by a 0x00 delimiter.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn write_frame(&mut self, data: &[u8]) -> SerialError {
+        self.write(&cobs::encode(data))
+    }
+
+    /// Writes data as a single SLIP (RFC 1055) frame, with END bytes before and after it.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn write_slip_frame(&mut self, data: &[u8]) -> SerialError {
+        self.write(&slip::encode(data))
+    }
+
+    /// Writes data as a single KISS frame to a TNC.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+    /// - Other - The port or command is out of range, or any other kind of device failure.
+    pub fn write_kiss_frame(&mut self, port: u8, command: u8, data: &[u8]) -> SerialError {
+        match slip::kiss_encode(port, command, data) {
+            Some(frame) => self.write(&frame),
+            None => SerialError::Other,
+        }
+    }
+
+    /// Writes data as a single HDLC-like frame, with its FCS.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn write_hdlc_frame(&mut self, data: &[u8], fcs: HdlcFcs) -> SerialError {
+        self.write(&hdlc::encode(data, fcs))
+    }
+
+    /// Writes json as a single compact line, followed by \n.
+    ///
+    /// This function will throw if json is not a single valid JSON value.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Interrupted - The device transfer was interrupted. You may retry this transfer.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn write_json(&mut self, json: &str) -> Result<SerialError> {
+        match json_lines::to_line(json) {
+            Ok(line) => Ok(self.write(&line)),
+            Err(err) => Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                format!("Invalid JSON: {}", err),
+            )),
+        }
+    }
+
     /// Attempts to write the entire string to the serial device.
     ///
     /// Errors
@@ -270,6 +441,75 @@ impl Serial {
         }
     }
 
+    /// Queues the buffer to be written by a background thread, returning immediately.
+    ///
+    /// The callback, if any, is called from the writer thread once the write completes, with the
+    /// outcome and the number of bytes written. Higher priority messages are written first, but only
+    /// once the message currently being written completes. Within a priority, messages are written in
+    /// the order they are queued. Messages are never split or interleaved with [Serial::write] calls.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - QueueFull - The queue for this priority is at capacity. The data was not queued, and the callback will not be called.
+    pub fn write_async(
+        &self,
+        data: &[u8],
+        priority: WritePriority,
+        callback: Option<(CVoidSend, WriteCallback)>,
+    ) -> SerialError {
+        self.write_queue.push(data.to_vec(), priority, callback)
+    }
+
+    /// Returns the number of queued writes that have not completed yet, including any being written.
+    pub fn queue_depth(&self) -> usize {
+        self.write_queue.depth()
+    }
+
+    /// Sets the maximum number of writes waiting at each priority. Defaults to 64.
+    pub fn set_queue_capacity(&self, messages: usize) {
+        self.write_queue.set_capacity(messages)
+    }
+
+    /// Blocks until all queued writes have completed, waiting at most deadline seconds.
+    /// A negative or infinite deadline waits forever. Called from a write completion callback, this
+    /// returns false at once, as the callbacks own message is not complete until it returns.
+    ///
+    /// Returns true if the queue emptied before the deadline.
+    pub fn flush_queue(&self, deadline: f32) -> bool {
+        self.write_queue
+            .flush(Instant::now().checked_add(timeout_from_secs(deadline)))
+    }
+
+    /// Starts writing data to the port every interval seconds, beginning immediately.
+    ///
+    /// Writes go through the same lock as [Serial::write], so they never interleave with other writes.
+    /// The schedule keeps a steady rate, skipping ticks rather than bursting if a write overruns.
+    /// The schedule ends when the returned handle is cancelled or destroyed.
+    ///
+    /// This function will throw if the interval is not a positive number of seconds.
+    pub fn schedule_periodic(&self, data: &[u8], interval: f32) -> Result<Box<PeriodicWrite>> {
+        match Duration::try_from_secs_f32(interval) {
+            Ok(interval) if !interval.is_zero() => Ok(Box::new(PeriodicWrite::start(
+                self.write_handle.clone(),
+                data.to_vec(),
+                interval,
+            ))),
+            _ => Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Periodic write interval must be a positive number of seconds.",
+            )),
+        }
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this port
+    /// to the free functions that take callbacks.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut Serial {
+        self as *mut Serial
+    }
+
     /// Attempts to read the remaining serial device's buffer, up to the size of the passed slice.
     /// Return struct includes the number of bytes read into this buffer. This function does not
     /// block if the serial buffer is read to completion.
@@ -351,6 +591,275 @@ impl Serial {
         }
     }
 
+    /// Writes command, then reads lines until one matches, waiting at most deadline seconds.
+    ///
+    /// The matcher compares each line, without its terminator, against pattern either exactly, by
+    /// prefix, or as a regex that may match anywhere in the line. Lines that do not match are collected
+    /// in the result, in the order they arrived. Transactions are serialized, so concurrent calls
+    /// will each receive their own reply. A negative or infinite deadline waits forever.
+    ///
+    /// Like other reads, this blocks while a listener is alive.
+    ///
+    /// This function will throw if pattern is not a valid regex.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Timeout - No matching line arrived before the deadline.
+    /// - Interrupted - The device transfer was interrupted. You may retry this transaction.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn transact(
+        &self,
+        command: &CxxString,
+        kind: MatchKind,
+        pattern: &str,
+        deadline: f32,
+    ) -> Result<TransactResult> {
+        let matcher = Matcher::new(kind, pattern)?;
+        let deadline = Instant::now().checked_add(timeout_from_secs(deadline));
+
+        let _transaction = self.transaction_lock.lock();
+        //Take the reader before writing, so nothing else can read the reply
+        let mut read_handle = self.read_handle.lock();
+
+        let (error, _) = write_counted(&mut **self.write_handle.lock(), command.as_bytes());
+        if error != SerialError::NoErr {
+            return Ok(TransactResult {
+                error,
+                response: String::new(),
+                unmatched: Vec::new(),
+            });
+        }
+
+        let guard = DeadlineGuard::new(&self.read_settings_handle, deadline);
+        Ok(await_response(&mut read_handle, &guard, &matcher))
+    }
+
+    /// Reads from the port until one of patterns matches, waiting at most deadline seconds.
+    ///
+    /// Each pattern is a regex. Unlike [Serial::read_line], this matches raw data as it arrives, so it
+    /// can wait for prompts such as `login: ` that do not end in a newline. If several patterns match,
+    /// the one whose match ends first wins. Only data up to the end of the match is consumed, so output
+    /// that follows the match is left for the next read. A negative or infinite deadline waits forever.
+    ///
+    /// The result holds the index of the matching pattern, the text received before the match, and the
+    /// matched text itself. On failure the index is -1, and the text before holds everything received.
+    ///
+    /// Like other reads, this blocks while a listener is alive.
+    ///
+    /// This function will throw if any pattern is not a valid regex.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Timeout - No pattern matched before the deadline.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn expect(&self, patterns: &CxxVector<CxxString>, deadline: f32) -> Result<ExpectResult> {
+        let patterns = compile_patterns(patterns.iter().map(CxxString::to_string_lossy))?;
+        let deadline = Instant::now().checked_add(timeout_from_secs(deadline));
+
+        let _transaction = self.transaction_lock.lock();
+        let mut read_handle = self.read_handle.lock();
+
+        let guard = DeadlineGuard::new(&self.read_settings_handle, deadline);
+        Ok(expect(&mut read_handle, &guard, &patterns))
+    }
+
+    /// Reads the next SLIP frame, waiting at most deadline seconds.
+    ///
+    /// Only input up to the end of the frame is consumed. Malformed frames are skipped, and counted
+    /// in the result.
+    ///
+    /// Errors
+    /// ------
+    ///
+    /// - Timeout - No complete frame arrived before the deadline. A partial frame is lost.
+    /// - Other - Any other kind of device failure, such as a disconnect.
+    pub fn read_slip_frame(&self, deadline: f32) -> FrameReadResult {
+        let deadline = Instant::now().checked_add(timeout_from_secs(deadline));
+        let mut read_handle = self.read_handle.lock();
+
+        let guard = DeadlineGuard::new(&self.read_settings_handle, deadline);
+        read_frame(
+            &mut read_handle,
+            &guard,
+            &mut SlipDeframer::new(usize::MAX),
+        )
+    }
+
+    /// Reads the next KISS frame, waiting at most deadline seconds. Frames without a type byte are
+    /// skipped as malformed.
+    pub fn read_kiss_frame(&self, deadline: f32) -> KissFrameResult {
+        let deadline = Instant::now().checked_add(timeout_from_secs(deadline));
+        let mut dropped = 0;
+
+        loop {
+            let remaining = deadline.map_or(-1.0, |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f32()
+            });
+            let frame = self.read_slip_frame(remaining);
+            dropped += frame.dropped;
+
+            if frame.error != SerialError::NoErr {
+                return KissFrameResult {
+                    error: frame.error,
+                    port: 0,
+                    command: 0,
+                    data: Vec::new(),
+                    dropped,
+                };
+            }
+
+            match kiss_split(&frame.data) {
+                Some((port, command, data)) => {
+                    return KissFrameResult {
+                        error: SerialError::NoErr,
+                        port,
+                        command,
+                        data: data.to_vec(),
+                        dropped,
+                    }
+                }
+                None => dropped += 1,
+            }
+        }
+    }
+
+    /// Reads the next HDLC-like frame, waiting at most deadline seconds, and returns it without its FCS.
+    ///
+    /// Frames failing their FCS are skipped, and counted in the result.
+    pub fn read_hdlc_frame(&self, fcs: HdlcFcs, deadline: f32) -> FrameReadResult {
+        let deadline = Instant::now().checked_add(timeout_from_secs(deadline));
+        let mut read_handle = self.read_handle.lock();
+
+        let guard = DeadlineGuard::new(&self.read_settings_handle, deadline);
+        read_frame(
+            &mut read_handle,
+            &guard,
+            &mut HdlcDeframer::new(fcs, usize::MAX),
+        )
+    }
+
+    /// Reads the next length-prefixed frame laid out as config describes, waiting at most deadline
+    /// seconds. The frame is returned without its sync bytes and checksum.
+    ///
+    /// Frames failing their checksum, or with a payload over 4096 bytes, are skipped, and counted in the
+    /// result. This function will throw if the config is invalid.
+    pub fn read_length_frame(
+        &self,
+        config: &LengthFraming,
+        deadline: f32,
+    ) -> Result<FrameReadResult> {
+        check_config(config)?;
+
+        let deadline = Instant::now().checked_add(timeout_from_secs(deadline));
+        let mut read_handle = self.read_handle.lock();
+
+        let guard = DeadlineGuard::new(&self.read_settings_handle, deadline);
+        Ok(read_frame(
+            &mut read_handle,
+            &guard,
+            //Bounded, so a corrupt length cannot swallow the frames after it
+            &mut LengthDeframer::new(config.clone(), DEFAULT_MAX_FRAME_LEN),
+        ))
+    }
+
+    /// Creates an AT command client on this port, and starts its reader thread.
+    ///
+    /// The client holds the read handle for as long as it lives, exactly like a listener, so other
+    /// reads on this port will block until it is destroyed or stopped.
+    pub fn create_at_client(&self) -> Box<AtClient> {
+        Box::new(AtClient::start(
+            self.read_handle.clone(),
+            self.write_handle.clone(),
+        ))
+    }
+
+    /// Creates a u-blox UBX client on this port, and starts its reader thread.
+    ///
+    /// The client holds the read handle for as long as it lives, exactly like a listener.
+    pub fn create_ubx_client(&self) -> Box<UbxClient> {
+        Box::new(UbxClient::start(
+            self.read_handle.clone(),
+            self.write_handle.clone(),
+        ))
+    }
+
+    /// Creates a reliable link on this port, and starts its threads.
+    ///
+    /// This function will throw if the window or timeout in config is invalid.
+    pub fn create_reliable_link(&self, config: ArqConfig) -> Result<Box<ReliableLink>> {
+        Ok(Box::new(ReliableLink::start(
+            self.read_handle.clone(),
+            self.write_handle.clone(),
+            config,
+        )?))
+    }
+
+    /// Creates a channel mux on this port, and starts its threads.
+    pub fn create_channel_mux(&self) -> Box<ChannelMux> {
+        Box::new(ChannelMux::start(
+            self.read_handle.clone(),
+            self.write_handle.clone(),
+        ))
+    }
+
+    /// Creates an RPC endpoint on this port, and starts its threads.
+    pub fn create_rpc_endpoint(&self, encoding: RpcEncoding) -> Box<RpcEndpoint> {
+        Box::new(RpcEndpoint::start(
+            self.read_handle.clone(),
+            self.write_handle.clone(),
+            encoding,
+        ))
+    }
+
+    /// Creates an STM32 bootloader client on this port, or throws if config is invalid.
+    pub fn create_stm32_bootloader(&self, config: &Stm32Config) -> Result<Box<Stm32Bootloader>> {
+        Ok(Box::new(Stm32Bootloader::new(
+            self.read_handle.clone(),
+            self.read_settings_handle.clone(),
+            self.write_handle.clone(),
+            config,
+        )?))
+    }
+
+    /// Creates an idle file transfer on this port, or throws if config is invalid.
+    pub fn create_file_transfer(&self, config: &TransferConfig) -> Result<Box<FileTransfer>> {
+        Ok(Box::new(FileTransfer::new(
+            self.read_handle.clone(),
+            self.read_settings_handle.clone(),
+            self.write_handle.clone(),
+            config,
+        )?))
+    }
+
+    /// Creates a Modbus RTU master that uses this port as its transport.
+    ///
+    /// Frame timing is derived from the ports baud rate and character format at the time of each
+    /// request, so settings may be changed after this call.
+    pub fn create_modbus_master(&self) -> Box<ModbusMaster> {
+        Box::new(ModbusMaster::new(
+            self.read_handle.clone(),
+            self.read_settings_handle.clone(),
+            self.write_handle.clone(),
+        ))
+    }
+
+    /// Creates a Modbus server that answers requests for config.unit on this port. The server is idle
+    /// until it is started with [ModbusServer::listen].
+    ///
+    /// This function will throw if the unit is not between 1 and 247.
+    pub fn create_modbus_server(&self, config: &ModbusServerConfig) -> Result<Box<ModbusServer>> {
+        Ok(Box::new(ModbusServer::new(
+            config,
+            self.read_handle.clone(),
+            self.read_settings_handle.clone(),
+            self.write_handle.clone(),
+        )?))
+    }
+
     /// Creates a builder to build a reader on this port. This reader will asynchronously read
     /// lines from the port, and perform a callback on each. This reader will inherit all settings from
     /// this port, including any changes after this call.
@@ -366,22 +875,50 @@ impl Serial {
         Ok(Box::from(SerialListenerBuilder {
             reader: Some(clone),
             callback: None,
+            mode: ListenerMode::Lines,
+            frame_callbacks: FrameCallbacks::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            telemetry_callback: None,
         }))
     }
 }
 
+/// Converts a C++ timeout in seconds to a Duration, mapping negative, infinite or otherwise
+/// unrepresentable values to an effectively infinite timeout.
+pub(crate) fn timeout_from_secs(sec: f32) -> Duration {
+    Duration::try_from_secs_f32(sec).unwrap_or(Duration::MAX)
+}
+
 /// Attempts to open the serial device at path, using the specified baud rate.
 /// Defaults to a timeout of 99999 seconds.
 pub fn open_port(path: &str, baud: u32) -> Result<Box<Serial>> {
     Ok(Box::from(Serial::new(path, baud)?))
 }
 
+/// What a listener reads from the port.
+pub enum ListenerMode {
+    /// Newline terminated lines, passed to the read callback.
+    Lines,
+    /// NMEA 0183 sentences, see [SerialListenerBuilder::set_nmea_mode].
+    Nmea(NmeaCallbacks),
+    /// One JSON value per line, see [SerialListenerBuilder::set_json_mode]. Holds the error callback.
+    Json(Option<(CVoidSend, JsonErrorCallback)>),
+    /// Numeric records, see [SerialListenerBuilder::set_telemetry_mode].
+    Telemetry(TelemetrySchema),
+    /// Binary frames, passed to the frame callbacks.
+    Frames(Framing),
+}
+
 pub struct SerialListenerBuilder {
     pub reader: Option<Arc<Mutex<BufReader<SerialPortReader>>>>, //This is optional as it allows us to 'move' into the listener without move available in cxx.
     pub callback: Option<(
         *mut c_void,
         unsafe extern "C" fn(user_data: *mut c_void, string_read: *const c_char, str_size: usize),
     )>,
+    pub mode: ListenerMode,
+    pub frame_callbacks: FrameCallbacks,
+    pub max_frame_len: usize,
+    pub telemetry_callback: Option<(CVoidSend, TelemetryCallback)>,
 }
 
 impl SerialListenerBuilder {
@@ -390,23 +927,120 @@ impl SerialListenerBuilder {
     ///
     /// This function will throw if the callback is not set, or this builder is used twice.
     pub fn build(&mut self) -> Result<Box<SerialListener>> {
-        if self.callback.is_none() {
-            Err(Error::new(
-                serialport::ErrorKind::InvalidInput,
-                "No callback provided to reader builder.",
-            ))
-        } else if self.reader.is_none() {
-            Err(Error::new(
+        let callback = self.callback.map(|callb| (CVoidSend(callb.0), callb.1));
+
+        let parser = match (&self.mode, callback) {
+            (ListenerMode::Lines, Some(callback)) => ListenerParser::Lines(callback),
+            (ListenerMode::Nmea(callbacks), _) if callback.is_some() || !callbacks.is_empty() => {
+                ListenerParser::Nmea(Arc::new(NmeaDecoder::new(*callbacks, callback)))
+            }
+            (ListenerMode::Json(error_callback), Some(callback)) => {
+                ListenerParser::Json(Arc::new(JsonDecoder::new(callback, *error_callback)))
+            }
+            (ListenerMode::Telemetry(schema), _) if self.telemetry_callback.is_some() => {
+                ListenerParser::Telemetry(Arc::new(TelemetryDecoder::new(
+                    schema.clone(),
+                    self.telemetry_callback.unwrap(),
+                )))
+            }
+            (ListenerMode::Frames(framing), _) if self.frame_callbacks.can_deliver(framing) => {
+                ListenerParser::Frames(
+                    framing.clone(),
+                    self.max_frame_len,
+                    Arc::new(FrameSink::new(self.frame_callbacks, framing)),
+                )
+            }
+            _ => {
+                return Err(Error::new(
+                    serialport::ErrorKind::InvalidInput,
+                    "No callback provided to reader builder.",
+                ))
+            }
+        };
+
+        match self.reader.take() {
+            None => Err(Error::new(
                 serialport::ErrorKind::InvalidInput,
                 "Attempting to reuse spent builder. Please make another instead.",
-            ))
-        } else {
-            let callb = self.callback.unwrap();
-            Ok(Box::from(SerialListener {
-                callback: (CVoidSend(callb.0), callb.1),
-                reader: self.reader.take().unwrap(), //Note the take-foo to avoid a move
+            )),
+            Some(reader) => Ok(Box::from(SerialListener {
+                parser,
+                reader,
                 cts: CancellationTokenSource::new(),
-            }))
+            })),
+        }
+    }
+
+    /// Switches this builder to NMEA mode, keeping any NMEA callbacks already set.
+    pub fn set_nmea_mode(&mut self) {
+        self.nmea_callbacks();
+    }
+
+    /// Switches this builder to JSON lines mode, keeping any error callback already set.
+    pub fn set_json_mode(&mut self) {
+        self.json_error_callback();
+    }
+
+    /// Switches this builder to telemetry mode, or throws if schema is invalid.
+    pub fn set_telemetry_mode(&mut self, schema: &TelemetrySchema) -> Result<()> {
+        check_schema(schema)?;
+        self.mode = ListenerMode::Telemetry(schema.clone());
+        Ok(())
+    }
+
+    /// Switches this builder to COBS frame mode.
+    pub fn set_cobs_mode(&mut self) {
+        self.mode = ListenerMode::Frames(Framing::Cobs);
+    }
+
+    /// Switches this builder to SLIP frame mode.
+    pub fn set_slip_mode(&mut self) {
+        self.mode = ListenerMode::Frames(Framing::Slip);
+    }
+
+    /// Switches this builder to KISS frame mode.
+    pub fn set_kiss_mode(&mut self) {
+        self.mode = ListenerMode::Frames(Framing::Kiss);
+    }
+
+    /// Switches this builder to HDLC-like frame mode.
+    pub fn set_hdlc_mode(&mut self, fcs: HdlcFcs) {
+        self.mode = ListenerMode::Frames(Framing::Hdlc(fcs));
+    }
+
+    /// Switches this builder to length-prefixed frame mode, or throws if config is invalid.
+    pub fn set_length_prefixed_mode(&mut self, config: &LengthFraming) -> Result<()> {
+        check_config(config)?;
+        self.mode = ListenerMode::Frames(Framing::Length(config.clone()));
+        Ok(())
+    }
+
+    /// Sets the largest decoded frame a frame listener will accept.
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_len = size;
+    }
+
+    /// Gets the NMEA callbacks of this builder, switching it to NMEA mode.
+    pub fn nmea_callbacks(&mut self) -> &mut NmeaCallbacks {
+        if !matches!(self.mode, ListenerMode::Nmea(_)) {
+            self.mode = ListenerMode::Nmea(NmeaCallbacks::default());
+        }
+
+        match &mut self.mode {
+            ListenerMode::Nmea(callbacks) => callbacks,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the JSON parse error callback of this builder, switching it to JSON lines mode.
+    pub fn json_error_callback(&mut self) -> &mut Option<(CVoidSend, JsonErrorCallback)> {
+        if !matches!(self.mode, ListenerMode::Json(_)) {
+            self.mode = ListenerMode::Json(None);
+        }
+
+        match &mut self.mode {
+            ListenerMode::Json(callback) => callback,
+            _ => unreachable!(),
         }
     }
 
@@ -419,12 +1053,19 @@ impl SerialListenerBuilder {
     }
 }
 
+/// How a built listener handles what it reads.
+#[derive(Clone)]
+enum ListenerParser {
+    Lines((CVoidSend, ReadCallback)),
+    Nmea(Arc<NmeaDecoder>),
+    Json(Arc<JsonDecoder>),
+    Telemetry(Arc<TelemetryDecoder>),
+    Frames(Framing, usize, Arc<FrameSink>),
+}
+
 pub struct SerialListener {
     reader: Arc<Mutex<BufReader<SerialPortReader>>>,
-    callback: (
-        CVoidSend,
-        unsafe extern "C" fn(user_data: *mut c_void, string_read: *const c_char, str_size: usize),
-    ),
+    parser: ListenerParser,
     /// Token used to kill the thread.
     cts: CancellationTokenSource,
 }
@@ -446,7 +1087,7 @@ impl SerialListener {
         //The cancellation token is the only way we have to kill the listener thread.
         let token = self.cts.token().clone();
         let reader = self.reader.clone();
-        let callback = self.callback;
+        let parser = self.parser.clone();
 
         //Lock the mutex to prevent a race before this thread spawns
         let _out_lock = self.reader.lock();
@@ -454,10 +1095,34 @@ impl SerialListener {
         std::thread::spawn(move || {
             log::debug!("Spawned listener");
 
-            let (user_data, callback) = callback;
             //Lock the reader while this listener is active
             let mut reader = reader.lock();
 
+            let (user_data, callback) = match parser {
+                ListenerParser::Lines(callback) => callback,
+                ListenerParser::Nmea(decoder) => {
+                    nmea::read_loop(&mut reader, &decoder, &token);
+                    log::debug!("exiting listener thread");
+                    return;
+                }
+                ListenerParser::Json(decoder) => {
+                    json_lines::read_loop(&mut reader, &decoder, &token);
+                    log::debug!("exiting listener thread");
+                    return;
+                }
+                ListenerParser::Telemetry(decoder) => {
+                    telemetry::read_loop(&mut reader, &decoder, &token);
+                    log::debug!("exiting listener thread");
+                    return;
+                }
+                ListenerParser::Frames(framing, max_len, sink) => {
+                    let mut deframer = framing.deframer(max_len);
+                    framing::read_loop(&mut reader, &mut *deframer, &sink, &token);
+                    log::debug!("exiting listener thread");
+                    return;
+                }
+            };
+
             while !token.is_canceled() {
                 let mut str_buf = String::with_capacity(40);
                 let read_num = reader.read_line(&mut str_buf); //This will wait until timeout
@@ -465,9 +1130,14 @@ impl SerialListener {
                 if let Ok(num) = read_num {
                     if num > 0 {
                         //Strip newline and add nullchar
-                        let c_str = CString::new(&str_buf[..str_buf.len() - 1]).expect(
-                            "Read String with internal null bytes. This is not C compatible.",
-                        );
+                        let c_str = match CString::new(&str_buf[..str_buf.len() - 1]) {
+                            Ok(c_str) => c_str,
+                            Err(_) => {
+                                //Not C compatible, binary data should use a frame mode instead
+                                log::warn!("Dropping line with internal null bytes");
+                                continue;
+                            }
+                        };
 
                         unsafe {
                             //Safe only if callback does not store a reference to the string, which it does not own.
@@ -490,6 +1160,54 @@ impl SerialListener {
     pub fn stop(&self) {
         self.cts.cancel();
     }
+
+    /// Gets the sentence counters of a listener in NMEA mode. Listeners in other modes return zeros.
+    pub fn nmea_stats(&self) -> NmeaStats {
+        match &self.parser {
+            ListenerParser::Nmea(decoder) => decoder.stats(),
+            _ => NmeaStats {
+                sentences: 0,
+                bad_checksums: 0,
+                unknown_talkers: 0,
+                malformed: 0,
+            },
+        }
+    }
+
+    /// Gets the line counters of a listener in JSON mode. Listeners in other modes return zeros.
+    pub fn json_stats(&self) -> JsonStats {
+        match &self.parser {
+            ListenerParser::Json(decoder) => decoder.stats(),
+            _ => JsonStats {
+                values: 0,
+                parse_errors: 0,
+            },
+        }
+    }
+
+    /// Gets the line counters of a listener in telemetry mode. Listeners in other modes return zeros.
+    pub fn telemetry_stats(&self) -> TelemetryStats {
+        match &self.parser {
+            ListenerParser::Telemetry(decoder) => decoder.stats(),
+            _ => TelemetryStats {
+                records: 0,
+                malformed: 0,
+            },
+        }
+    }
+
+    /// Gets the frame counters of a listener in a frame mode. Listeners in other modes return zeros.
+    pub fn frame_stats(&self) -> FrameStats {
+        match &self.parser {
+            ListenerParser::Frames(_, _, sink) => sink.stats(),
+            _ => FrameStats {
+                frames: 0,
+                malformed: 0,
+                too_long: 0,
+                bad_checksums: 0,
+            },
+        }
+    }
 }
 
 impl Drop for SerialListener {
@@ -1,10 +1,14 @@
 //! Extra types used in [serial].
 
+use crate::ffi::SerialError;
 use crate::Mutex;
 use serialport::SerialPort;
 use std::ffi::c_void;
-use std::io::{IoSliceMut, Read};
+use std::io::{BufRead, BufReader, ErrorKind, IoSliceMut, Read};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Internal Struct that wraps a Mutex protected serial port in a Read trait.
 ///
@@ -55,3 +59,167 @@ impl Read for SerialPortReader {
 pub struct CVoidSend(pub *mut c_void);
 
 unsafe impl Send for CVoidSend {}
+//Rust never dereferences the pointer, it only hands it back to C++.
+unsafe impl Sync for CVoidSend {}
+
+/// Sets the VMIN and VTIME control characters of the tty behind fd, leaving all other settings alone.
+///
+/// vtime is in tenths of a second, as termios expects.
+#[cfg(unix)]
+pub fn set_vmin_vtime(fd: RawFd, vmin: u8, vtime: u8) -> std::io::Result<()> {
+    let mut termios = std::mem::MaybeUninit::uninit();
+
+    unsafe {
+        if libc::tcgetattr(fd, termios.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut termios = termios.assume_init();
+        termios.c_cc[libc::VMIN] = vmin;
+        termios.c_cc[libc::VTIME] = vtime;
+
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps an IO error from the port to the error reported across the bridge.
+pub fn io_error_to_serial(err: &std::io::Error) -> SerialError {
+    match err.kind() {
+        ErrorKind::Interrupted => SerialError::Interrupted,
+        ErrorKind::TimedOut => SerialError::Timeout,
+        _ => SerialError::Other,
+    }
+}
+
+/// Limits the read timeout of a port so reads cannot run past a deadline. The original timeout is
+/// restored on drop.
+///
+/// The guard does not hold the settings lock between calls, as [SerialPortReader] needs it to read.
+pub struct DeadlineGuard<'a> {
+    settings: &'a Mutex<Box<dyn SerialPort>>,
+    original: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineGuard<'a> {
+    /// Creates a guard for the port behind settings. A deadline of None never expires.
+    pub fn new(settings: &'a Mutex<Box<dyn SerialPort>>, deadline: Option<Instant>) -> Self {
+        let original = settings.lock().timeout();
+
+        DeadlineGuard {
+            settings,
+            original,
+            deadline,
+        }
+    }
+
+    /// Sets the port timeout to the time remaining before the deadline, if that is shorter than the
+    /// original timeout. Call this before each read.
+    ///
+    /// Returns false if the deadline has already passed.
+    pub fn arm(&self) -> bool {
+        let timeout = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => remaining.min(self.original),
+                _ => return false,
+            },
+            None => self.original,
+        };
+
+        let _ = self.settings.lock().set_timeout(timeout);
+        true
+    }
+}
+
+impl Drop for DeadlineGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.settings.lock().set_timeout(self.original);
+    }
+}
+
+/// Fills buf completely, retrying timeouts until the deadline guarded by deadline passes.
+///
+/// Returns Ok(false) if the deadline passed or the port hit end of file before buf was filled.
+pub fn read_exact_until(
+    reader: &mut BufReader<SerialPortReader>,
+    deadline: &DeadlineGuard,
+    buf: &mut [u8],
+) -> std::io::Result<bool> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        if !deadline.arm() {
+            return Ok(false);
+        }
+
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => filled += n,
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Reads into line up to and including a newline, like [BufRead::read_until], but stops once line
+/// holds more than max bytes, so a stream without newlines cannot grow it forever. Callers check
+/// line.len() against max to spot an overlong line.
+pub fn read_line_bounded(
+    reader: &mut BufReader<SerialPortReader>,
+    line: &mut Vec<u8>,
+    max: usize,
+) -> std::io::Result<usize> {
+    //Always allow a byte, so a full line is not mistaken for end of file
+    let limit = (max + 1).saturating_sub(line.len()).max(1);
+    reader.by_ref().take(limit as u64).read_until(b'\n', line)
+}
+
+/// Throws away input until the port has been silent for gap, or until limit passes. A limit of None
+/// waits for silence however long it takes.
+///
+/// Used to resynchronize protocols that delimit frames by idle time. Returns the discarded input.
+pub fn discard_until_silent(
+    reader: &mut BufReader<SerialPortReader>,
+    settings: &Mutex<Box<dyn SerialPort>>,
+    gap: Duration,
+    limit: Option<Instant>,
+) -> Vec<u8> {
+    let mut discarded = Vec::new();
+
+    while limit.is_none_or(|limit| Instant::now() < limit) {
+        let deadline = DeadlineGuard::new(settings, Some(Instant::now() + gap));
+        if !deadline.arm() {
+            break;
+        }
+
+        match reader.fill_buf() {
+            Ok([]) => break,
+            Ok(buf) => {
+                discarded.extend_from_slice(buf);
+                let len = buf.len();
+                reader.consume(len);
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => break, //Timed out, so the line is silent
+        }
+    }
+
+    discarded
+}
+
+/// Throws away everything already received, both in reader and in the OS buffer.
+pub fn discard_input(
+    reader: &mut BufReader<SerialPortReader>,
+    settings: &Mutex<Box<dyn SerialPort>>,
+) {
+    let buffered = reader.buffer().len();
+    reader.consume(buffered);
+
+    let _ = settings.lock().clear(serialport::ClearBuffer::Input);
+}
@@ -0,0 +1,139 @@
+//! SLIP (RFC 1055) framing, and the KISS TNC protocol built on it.
+
+use crate::ffi::FrameError;
+use crate::framing::{Deframer, FrameOut};
+
+/// Ends every frame. KISS calls this FEND.
+pub const END: u8 = 0xC0;
+/// Starts an escape sequence. KISS calls this FESC.
+pub const ESC: u8 = 0xDB;
+/// END, escaped. KISS calls this TFEND.
+pub const ESC_END: u8 = 0xDC;
+/// ESC, escaped. KISS calls this TFESC.
+pub const ESC_ESC: u8 = 0xDD;
+
+/// The KISS command that takes the TNC out of KISS mode. Unlike other commands it has no port.
+pub const KISS_RETURN: u8 = 0xFF;
+
+fn escape_into(out: &mut Vec<u8>, data: &[u8]) {
+    for &byte in data {
+        match byte {
+            END => out.extend_from_slice(&[ESC, ESC_END]),
+            ESC => out.extend_from_slice(&[ESC, ESC_ESC]),
+            _ => out.push(byte),
+        }
+    }
+}
+
+/// Encodes data as a SLIP frame. The frame starts with an END as well, which flushes any line noise
+/// out of the receivers buffer, as RFC 1055 recommends.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 2);
+    out.push(END);
+    escape_into(&mut out, data);
+    out.push(END);
+    out
+}
+
+/// Encodes data as a KISS frame for a TNC port, with command in the low nibble of the type byte.
+///
+/// Returns None if port is over 15, or command is over 15 and not [KISS_RETURN].
+pub fn kiss_encode(port: u8, command: u8, data: &[u8]) -> Option<Vec<u8>> {
+    let type_byte = match command {
+        KISS_RETURN => KISS_RETURN,
+        _ if port > 0x0F || command > 0x0F => return None,
+        _ => (port << 4) | command,
+    };
+
+    let mut out = Vec::with_capacity(data.len() + 3);
+    out.push(END);
+    escape_into(&mut out, &[type_byte]);
+    escape_into(&mut out, data);
+    out.push(END);
+    Some(out)
+}
+
+/// Splits a KISS frame into its port, command and payload.
+///
+/// Returns None if the frame has no type byte.
+pub fn kiss_split(frame: &[u8]) -> Option<(u8, u8, &[u8])> {
+    let (&type_byte, data) = frame.split_first()?;
+
+    Some(match type_byte {
+        KISS_RETURN => (0, KISS_RETURN, data),
+        _ => (type_byte >> 4, type_byte & 0x0F, data),
+    })
+}
+
+/// Splits a stream on END bytes and removes escapes.
+pub struct SlipDeframer {
+    buf: Vec<u8>,
+    max_len: usize,
+    escaped: bool,
+    /// Set when the current frame is bad, so the rest of it is dropped up to the next END.
+    discarding: bool,
+}
+
+impl SlipDeframer {
+    pub fn new(max_len: usize) -> Self {
+        SlipDeframer {
+            buf: Vec::new(),
+            max_len,
+            escaped: false,
+            discarding: false,
+        }
+    }
+
+    fn discard(&mut self, error: FrameError, out: &mut FrameOut) {
+        self.buf.clear();
+        self.escaped = false;
+        self.discarding = true;
+        out(Err(error));
+    }
+}
+
+impl Deframer for SlipDeframer {
+    fn push(&mut self, data: &[u8], out: &mut FrameOut) {
+        for &byte in data {
+            if byte == END {
+                if self.escaped {
+                    out(Err(FrameError::Malformed));
+                } else if !self.discarding && !self.buf.is_empty() {
+                    //Back to back ENDs are padding, not empty frames
+                    out(Ok(&self.buf));
+                }
+
+                self.buf.clear();
+                self.escaped = false;
+                self.discarding = false;
+                continue;
+            }
+            if self.discarding {
+                continue;
+            }
+
+            let byte = if self.escaped {
+                self.escaped = false;
+                match byte {
+                    ESC_END => END,
+                    ESC_ESC => ESC,
+                    _ => {
+                        self.discard(FrameError::Malformed, out);
+                        continue;
+                    }
+                }
+            } else if byte == ESC {
+                self.escaped = true;
+                continue;
+            } else {
+                byte
+            };
+
+            if self.buf.len() >= self.max_len {
+                self.discard(FrameError::TooLong, out);
+                continue;
+            }
+            self.buf.push(byte);
+        }
+    }
+}
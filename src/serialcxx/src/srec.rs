@@ -0,0 +1,187 @@
+//! Motorola S-record images.
+
+use serialport::{Error, ErrorKind};
+
+use crate::ffi::FirmwareImage;
+use crate::image::{
+    check_segments, decode_hex, encode_hex, end, line_error, sorted_segments, ImageBuilder,
+};
+
+/// Data bytes per record written.
+const RECORD_LEN: usize = 16;
+
+/// Gets the address width of a data or termination record type.
+fn address_width(record_type: u8) -> usize {
+    match record_type {
+        b'1' | b'9' => 2,
+        b'2' | b'8' => 3,
+        _ => 4,
+    }
+}
+
+/// Parses an S-record file into segments, in file order. Records continuing the previous one are
+/// merged into its segment. Throws on the first bad line, a count record that does not match, or if
+/// there is no termination record.
+pub fn parse_srec(text: &str) -> serialport::Result<FirmwareImage> {
+    let mut image = ImageBuilder::new();
+    let mut data_records = 0u32;
+    let mut finished = false;
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fail = |desc: &str| line_error(idx + 1, desc);
+
+        let (record_type, record) = match line.as_bytes() {
+            //get rather than slicing, as the type may be the start of a multibyte character
+            [b'S', record_type, ..] => (*record_type, line.get(2..).and_then(decode_hex)),
+            _ => (0, None),
+        };
+        let record = record.ok_or_else(|| fail("not an S-record"))?;
+        if record.len() < 2 || record.len() != record[0] as usize + 1 {
+            return Err(fail("wrong record length"));
+        }
+        //The checksum is the ones' complement of the sum of everything before it
+        let (checksum, body) = record.split_last().unwrap();
+        if !body.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != *checksum {
+            return Err(fail("bad checksum"));
+        }
+
+        let width = match record_type {
+            b'0' | b'5' => 2,
+            b'6' => 3,
+            b'1'..=b'3' | b'7'..=b'9' => address_width(record_type),
+            _ => return Err(fail("unknown record type")),
+        };
+        if body.len() < 1 + width {
+            return Err(fail("record too short for its address"));
+        }
+        let address = body[1..1 + width]
+            .iter()
+            .fold(0u32, |acc, &byte| (acc << 8) | byte as u32);
+        let data = &body[1 + width..];
+
+        match record_type {
+            //The header is free text, usually a file name
+            b'0' => {}
+            b'1'..=b'3' => {
+                if !image.push(address, data) {
+                    return Err(fail("data runs past the end of the address space"));
+                }
+                data_records += 1;
+            }
+            b'5' | b'6' if address != data_records => {
+                return Err(fail("record count does not match the data records"))
+            }
+            b'5' | b'6' => {}
+            _ => {
+                image.set_entry_point(address);
+                finished = true;
+                break;
+            }
+        }
+    }
+
+    //Without it, a truncated file would pass as a shorter image
+    if !finished {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Missing termination record.",
+        ));
+    }
+
+    Ok(image.finish())
+}
+
+/// Appends one record to out.
+fn write_record(out: &mut String, record_type: u8, address: u32, width: usize, data: &[u8]) {
+    let mut record = vec![(width + data.len() + 1) as u8];
+    record.extend_from_slice(&address.to_be_bytes()[4 - width..]);
+    record.extend_from_slice(data);
+    record.push(!record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+
+    out.push('S');
+    out.push(record_type as char);
+    encode_hex(out, &record);
+    out.push('\n');
+}
+
+/// Writes image as an S-record file, in address order. The narrowest of S1, S2 and S3 records that
+/// fits every address is used. Throws if a segment runs past the end of the address space.
+pub fn write_srec(image: &FirmwareImage) -> serialport::Result<String> {
+    check_segments(image)?;
+
+    let segments = sorted_segments(image);
+    let top = segments
+        .iter()
+        .map(|segment| end(segment).saturating_sub(1))
+        .chain(image.has_entry_point.then_some(image.entry_point as u64))
+        .max()
+        .unwrap_or(0);
+    let (data_type, end_type) = match top {
+        0..=0xFFFF => (b'1', b'9'),
+        0x1_0000..=0xFF_FFFF => (b'2', b'8'),
+        _ => (b'3', b'7'),
+    };
+    let width = address_width(data_type);
+
+    let mut out = String::new();
+    write_record(&mut out, b'0', 0, 2, &[]);
+
+    let mut count = 0u32;
+    for segment in segments {
+        for (idx, chunk) in segment.data.chunks(RECORD_LEN).enumerate() {
+            let address = segment.address + (idx * RECORD_LEN) as u32;
+            write_record(&mut out, data_type, address, width, chunk);
+            count += 1;
+        }
+    }
+
+    if count <= 0xFFFF {
+        write_record(&mut out, b'5', count, 2, &[]);
+    } else if count <= 0xFF_FFFF {
+        write_record(&mut out, b'6', count, 3, &[]);
+    }
+    write_record(&mut out, end_type, image.entry_point, width, &[]);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = "S00600004844521B\nS107000001020304EE\nS5030001FB\nS9030000FC\n";
+        let image = parse_srec(text).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].data, [1, 2, 3, 4]);
+        assert!(image.has_entry_point);
+        let written = write_srec(&image).unwrap();
+        assert_eq!(parse_srec(&written).unwrap().segments[0].data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for text in [
+            "Sé0000\n",
+            "Sé\n",
+            "S1é0\n",
+            "S\n",
+            "S1\n",
+            "S107000001020304EF\n",
+            "S108000001020304EE\n",
+            "S4030000FC\n",
+            "S10200FD\n",
+            "S5030002FA\n",
+            "X107000001020304EE\n",
+            "S107000001020304EE\n",
+            "S00600004844521B\nS107000001020304EE\nS5030001FB\n",
+        ] {
+            assert!(parse_srec(text).is_err(), "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,583 @@
+//! Client for the STM32 system memory bootloader over USART (ST AN3155), created with
+//! [crate::Serial::create_stm32_bootloader].
+//!
+//! Every command is a byte followed by its complement, and is answered with ACK or NACK. Addresses
+//! and data blocks end with the XOR of their bytes. The bootloader is entered by holding BOOT0 high
+//! through a reset, which the client can do with the DTR and RTS lines.
+
+use std::ffi::c_void;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serialport::{Error, SerialPort};
+
+use crate::crc::xor8;
+use crate::ffi::{
+    ControlLine, MemorySegment, SerialError, Stm32Config, Stm32Error, Stm32IdResult,
+    Stm32Operation, Stm32Progress, Stm32ReadResult,
+};
+use crate::ihex::parse_intel_hex;
+use crate::image::{end, find_overlaps};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{
+    discard_input, read_exact_until, CVoidSend, DeadlineGuard, SerialPortReader,
+};
+use crate::write_queue::write_counted;
+use crate::{Mutex, MutexGuard};
+
+const ACK: u8 = 0x79;
+const NACK: u8 = 0x1F;
+/// Sent first, so the bootloader can measure the baud rate.
+const SYNC: u8 = 0x7F;
+
+const CMD_GET: u8 = 0x00;
+const CMD_GET_ID: u8 = 0x02;
+const CMD_READ_MEMORY: u8 = 0x11;
+const CMD_GO: u8 = 0x21;
+const CMD_WRITE_MEMORY: u8 = 0x31;
+const CMD_ERASE: u8 = 0x43;
+const CMD_EXTENDED_ERASE: u8 = 0x44;
+
+/// Most bytes a single read or write command moves.
+const MAX_BLOCK: usize = 256;
+/// Most pages a single erase command clears.
+const MAX_ERASE_PAGES: usize = 255;
+/// Flash is programmed in words of this many bytes, each only once between erases.
+const WORD: usize = 4;
+/// The value of erased flash, used to pad partial words.
+const ERASED: u8 = 0xFF;
+
+/// How long reset is held, and how long the bootloader gets to start after it.
+const RESET_PULSE: Duration = Duration::from_millis(50);
+const BOOT_DELAY: Duration = Duration::from_millis(100);
+/// Times the sync byte is sent before giving up.
+const SYNC_ATTEMPTS: u32 = 5;
+
+/// Callback for bootloader progress. The progress is only valid until the callback returns.
+///
+/// It runs while the command reporting it holds the port, so it must not call back into the client.
+pub type Stm32ProgressCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, progress: *const Stm32Progress);
+
+/// A client for the STM32 ROM bootloader using a [crate::Serial] as its transport.
+///
+/// Each command locks the read handle of the port until it completes, so this cannot be used while a
+/// listener is alive.
+pub struct Stm32Bootloader {
+    reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+    read_settings: Arc<Mutex<Box<dyn SerialPort>>>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    boot0_line: ControlLine,
+    reset_line: ControlLine,
+    invert_boot0: bool,
+    invert_reset: bool,
+    timeout: Duration,
+    erase_timeout: Duration,
+    /// Commands the bootloader reported in response to Get, learnt by [Stm32Bootloader::connect].
+    commands: Mutex<Vec<u8>>,
+    progress_callback: Mutex<Option<(CVoidSend, Stm32ProgressCallback)>>,
+}
+
+impl Stm32Bootloader {
+    /// Creates a client, or throws if config is invalid.
+    pub fn new(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        read_settings: Arc<Mutex<Box<dyn SerialPort>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+        config: &Stm32Config,
+    ) -> serialport::Result<Self> {
+        let lines = [ControlLine::None, ControlLine::Dtr, ControlLine::Rts];
+        if !lines.contains(&config.boot0_line) || !lines.contains(&config.reset_line) {
+            return Err(invalid("Unknown control line."));
+        }
+        if config.boot0_line != ControlLine::None && config.boot0_line == config.reset_line {
+            return Err(invalid("BOOT0 and reset must use different lines."));
+        }
+        for timeout in [config.timeout, config.erase_timeout] {
+            if !timeout.is_finite() || timeout <= 0.0 {
+                return Err(invalid("Timeouts must be positive and finite."));
+            }
+        }
+
+        Ok(Stm32Bootloader {
+            reader,
+            read_settings,
+            write_handle,
+            boot0_line: config.boot0_line,
+            reset_line: config.reset_line,
+            invert_boot0: config.invert_boot0,
+            invert_reset: config.invert_reset,
+            timeout: timeout_from_secs(config.timeout),
+            erase_timeout: timeout_from_secs(config.erase_timeout),
+            commands: Mutex::new(Vec::new()),
+            progress_callback: Mutex::new(None),
+        })
+    }
+
+    /// Resets the chip into its bootloader if the lines allow, syncs, and learns its commands.
+    pub fn connect(&self) -> Stm32Error {
+        if self.boot0_line != ControlLine::None || self.reset_line != ControlLine::None {
+            if let Err(err) = self.reset(true) {
+                return err;
+            }
+        }
+
+        let mut link = self.link();
+        discard_input(&mut link.reader, &self.read_settings);
+        if let Err(err) = link.sync() {
+            return err;
+        }
+
+        match link.get_commands() {
+            Ok(commands) => {
+                *self.commands.lock() = commands;
+                Stm32Error::NoErr
+            }
+            Err(err) => err,
+        }
+    }
+
+    /// Releases BOOT0 and pulses reset, so the chip starts its application.
+    pub fn reset_to_application(&self) -> Stm32Error {
+        if self.reset_line == ControlLine::None {
+            return Stm32Error::InvalidRequest;
+        }
+        match self.reset(false) {
+            Ok(()) => Stm32Error::NoErr,
+            Err(err) => err,
+        }
+    }
+
+    /// Gets the product ID of the chip.
+    pub fn get_id(&self) -> Stm32IdResult {
+        let result = self.link().get_id();
+        Stm32IdResult {
+            error: result.err().unwrap_or(Stm32Error::NoErr),
+            product_id: result.unwrap_or(0),
+        }
+    }
+
+    /// Reads len bytes of memory starting at address.
+    pub fn read_memory(&self, address: u32, len: usize) -> Stm32ReadResult {
+        match self.read_blocks(address, len, Stm32Operation::Read, (0, len)) {
+            Ok(data) => Stm32ReadResult {
+                error: Stm32Error::NoErr,
+                data,
+            },
+            Err(error) => Stm32ReadResult {
+                error,
+                data: Vec::new(),
+            },
+        }
+    }
+
+    /// Writes data to memory starting at address, padded out to whole words.
+    pub fn write_memory(&self, address: u32, data: &[u8]) -> Stm32Error {
+        to_error(self.flash(
+            &[MemorySegment {
+                address,
+                data: data.to_vec(),
+            }],
+            false,
+        ))
+    }
+
+    /// Erases the given flash pages.
+    pub fn erase_pages(&self, pages: &[u16]) -> Stm32Error {
+        if pages.is_empty() {
+            return Stm32Error::InvalidRequest;
+        }
+        let extended = self.extended_erase();
+        if !extended && pages.iter().any(|&page| page > u8::MAX as u16) {
+            return Stm32Error::InvalidRequest;
+        }
+
+        let mut link = self.link();
+        for (idx, chunk) in pages.chunks(MAX_ERASE_PAGES).enumerate() {
+            if let Err(err) = link.erase(extended, Some(chunk), self.erase_timeout) {
+                return err;
+            }
+            let done = (idx * MAX_ERASE_PAGES + chunk.len()) as u64;
+            self.report(Stm32Operation::Erase, done, pages.len() as u64);
+        }
+
+        Stm32Error::NoErr
+    }
+
+    /// Erases all of flash.
+    pub fn mass_erase(&self) -> Stm32Error {
+        let result = self
+            .link()
+            .erase(self.extended_erase(), None, self.erase_timeout);
+        if result.is_ok() {
+            self.report(Stm32Operation::Erase, 1, 1);
+        }
+        to_error(result)
+    }
+
+    /// Jumps to the code at address.
+    pub fn go(&self, address: u32) -> Stm32Error {
+        to_error(self.link().go(address))
+    }
+
+    /// Writes a binary image at address, then reads it back to check it if verify is set.
+    pub fn flash_binary(&self, address: u32, data: &[u8], verify: bool) -> Stm32Error {
+        to_error(self.flash(
+            &[MemorySegment {
+                address,
+                data: data.to_vec(),
+            }],
+            verify,
+        ))
+    }
+
+    /// Writes an Intel HEX image, then reads it back to check it if verify is set. Images with
+    /// overlapping segments are refused, as some of their bytes would be written twice.
+    pub fn flash_hex(&self, hex: &str, verify: bool) -> Stm32Error {
+        match parse_intel_hex(hex) {
+            Ok(image) if !find_overlaps(&image).is_empty() => {
+                log::warn!("Intel HEX image has overlapping segments");
+                Stm32Error::InvalidImage
+            }
+            Ok(image) => to_error(self.flash(&image.segments, verify)),
+            Err(err) => {
+                log::warn!("Invalid Intel HEX image: {}", err);
+                Stm32Error::InvalidImage
+            }
+        }
+    }
+
+    /// Sets the callback that receives progress, replacing any previous one.
+    pub fn set_progress_callback(&self, callback: Option<(CVoidSend, Stm32ProgressCallback)>) {
+        *self.progress_callback.lock() = callback;
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this client
+    /// to [crate::bindgenffi::set_stm32_progress_callback].
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut Stm32Bootloader {
+        self as *mut Stm32Bootloader
+    }
+
+    fn link(&self) -> Link<'_> {
+        Link {
+            reader: self.reader.lock(),
+            settings: &self.read_settings,
+            write_handle: &self.write_handle,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Whether to erase with Extended Erase, which newer bootloaders offer instead of Erase.
+    fn extended_erase(&self) -> bool {
+        let commands = self.commands.lock();
+        commands.contains(&CMD_EXTENDED_ERASE) || !commands.contains(&CMD_ERASE)
+    }
+
+    /// Pulses reset, with BOOT0 selecting the bootloader or the application.
+    fn reset(&self, bootloader: bool) -> Result<(), Stm32Error> {
+        self.set_line(self.boot0_line, bootloader != self.invert_boot0)?;
+        self.set_line(self.reset_line, !self.invert_reset)?;
+        std::thread::sleep(RESET_PULSE);
+        self.set_line(self.reset_line, self.invert_reset)?;
+        std::thread::sleep(BOOT_DELAY);
+
+        //Leave BOOT0 alone once the chip is running, as the pin may be shared
+        Ok(())
+    }
+
+    fn set_line(&self, line: ControlLine, asserted: bool) -> Result<(), Stm32Error> {
+        let mut port = self.write_handle.lock();
+        let result = match line {
+            ControlLine::Dtr => port.write_data_terminal_ready(asserted),
+            ControlLine::Rts => port.write_request_to_send(asserted),
+            _ => Ok(()),
+        };
+        result.map_err(|_| Stm32Error::PortErr)
+    }
+
+    fn report(&self, operation: Stm32Operation, bytes_done: u64, bytes_total: u64) {
+        //Copied out, so the callback can replace itself without deadlocking
+        let callback = *self.progress_callback.lock();
+        if let Some((user_data, callback)) = callback {
+            let progress = Stm32Progress {
+                operation,
+                bytes_done,
+                bytes_total,
+            };
+            unsafe {
+                //Safe only if callback does not store a reference to the progress, which it does not own.
+                callback(user_data.0, &progress);
+            }
+        }
+    }
+
+    /// Reads memory in blocks, reporting progress as bytes after done out of total.
+    fn read_blocks(
+        &self,
+        address: u32,
+        len: usize,
+        operation: Stm32Operation,
+        (done, total): (usize, usize),
+    ) -> Result<Vec<u8>, Stm32Error> {
+        if address.checked_add(len as u32).is_none() || len > u32::MAX as usize {
+            return Err(Stm32Error::InvalidRequest);
+        }
+
+        let mut link = self.link();
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let block_len = (len - data.len()).min(MAX_BLOCK);
+            data.extend(link.read_memory(address + data.len() as u32, block_len)?);
+            self.report(operation, (done + data.len()) as u64, total as u64);
+        }
+
+        Ok(data)
+    }
+
+    /// Writes word aligned data in blocks, reporting progress as bytes after done out of total.
+    fn write_blocks(
+        &self,
+        address: u32,
+        data: &[u8],
+        (done, total): (usize, usize),
+    ) -> Result<(), Stm32Error> {
+        if address.checked_add(data.len() as u32).is_none() || data.len() > u32::MAX as usize {
+            return Err(Stm32Error::InvalidRequest);
+        }
+
+        let mut link = self.link();
+        for (idx, block) in data.chunks(MAX_BLOCK).enumerate() {
+            let offset = idx * MAX_BLOCK;
+            link.write_memory(address + offset as u32, block)?;
+            self.report(
+                Stm32Operation::Write,
+                (done + offset + block.len()) as u64,
+                total as u64,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Writes segments, which must not overlap, then reads them back to check them if verify is set.
+    fn flash(&self, segments: &[MemorySegment], verify: bool) -> Result<(), Stm32Error> {
+        let blocks = align_segments(segments);
+        let total = blocks.iter().map(|block| block.data.len()).sum();
+
+        let mut done = 0;
+        for block in &blocks {
+            self.write_blocks(block.address, &block.data, (done, total))?;
+            done += block.data.len();
+        }
+
+        if verify {
+            //Only the bytes of the image are compared, not the padding
+            let total = segments.iter().map(|segment| segment.data.len()).sum();
+            let mut done = 0;
+            for segment in segments {
+                let read = self.read_blocks(
+                    segment.address,
+                    segment.data.len(),
+                    Stm32Operation::Verify,
+                    (done, total),
+                )?;
+                if read != segment.data {
+                    return Err(Stm32Error::VerifyFailed);
+                }
+                done += segment.data.len();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A locked connection to the bootloader, held for one or more commands.
+struct Link<'a> {
+    reader: MutexGuard<'a, BufReader<SerialPortReader>>,
+    settings: &'a Mutex<Box<dyn SerialPort>>,
+    write_handle: &'a Mutex<Box<dyn SerialPort>>,
+    timeout: Duration,
+}
+
+impl Link<'_> {
+    fn write(&self, data: &[u8]) -> Result<(), Stm32Error> {
+        match write_counted(&mut **self.write_handle.lock(), data) {
+            (SerialError::NoErr, _) => Ok(()),
+            _ => Err(Stm32Error::PortErr),
+        }
+    }
+
+    fn read(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>, Stm32Error> {
+        let deadline = DeadlineGuard::new(self.settings, Instant::now().checked_add(timeout));
+        let mut buf = vec![0; len];
+
+        match read_exact_until(&mut self.reader, &deadline, &mut buf) {
+            Ok(true) => Ok(buf),
+            Ok(false) => Err(Stm32Error::Timeout),
+            Err(_) => Err(Stm32Error::PortErr),
+        }
+    }
+
+    fn await_ack(&mut self, timeout: Duration) -> Result<(), Stm32Error> {
+        match self.read(1, timeout)?[0] {
+            ACK => Ok(()),
+            NACK => Err(Stm32Error::Nack),
+            _ => Err(Stm32Error::InvalidResponse),
+        }
+    }
+
+    /// Sends data followed by its XOR checksum, and waits for the ACK.
+    fn send_checked(&mut self, data: &[u8], timeout: Duration) -> Result<(), Stm32Error> {
+        let mut frame = data.to_vec();
+        frame.push(xor8(data));
+        self.write(&frame)?;
+        self.await_ack(timeout)
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Stm32Error> {
+        self.write(&[command, !command])?;
+        self.await_ack(self.timeout)
+    }
+
+    fn sync(&mut self) -> Result<(), Stm32Error> {
+        for _ in 0..SYNC_ATTEMPTS {
+            self.write(&[SYNC])?;
+            match self.read(1, self.timeout) {
+                //A bootloader that is already synced refuses the sync byte as an unknown command
+                Ok(reply) if reply[0] == ACK || reply[0] == NACK => return Ok(()),
+                Ok(_) | Err(Stm32Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(Stm32Error::Timeout)
+    }
+
+    fn get_commands(&mut self) -> Result<Vec<u8>, Stm32Error> {
+        self.command(CMD_GET)?;
+        let count = self.read(1, self.timeout)?[0] as usize;
+        //The version, then the command codes
+        let reply = self.read(count + 1, self.timeout)?;
+        self.await_ack(self.timeout)?;
+
+        Ok(reply[1..].to_vec())
+    }
+
+    fn get_id(&mut self) -> Result<u16, Stm32Error> {
+        self.command(CMD_GET_ID)?;
+        let count = self.read(1, self.timeout)?[0] as usize;
+        let id = self.read(count + 1, self.timeout)?;
+        self.await_ack(self.timeout)?;
+
+        match id[..] {
+            [high, low] => Ok(u16::from_be_bytes([high, low])),
+            _ => Err(Stm32Error::InvalidResponse),
+        }
+    }
+
+    fn read_memory(&mut self, address: u32, len: usize) -> Result<Vec<u8>, Stm32Error> {
+        self.command(CMD_READ_MEMORY)?;
+        self.send_checked(&address.to_be_bytes(), self.timeout)?;
+        let count = (len - 1) as u8;
+        self.write(&[count, !count])?;
+        self.await_ack(self.timeout)?;
+
+        self.read(len, self.timeout)
+    }
+
+    /// Writes a block of whole words, from 4 to 256 bytes, at a word aligned address.
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), Stm32Error> {
+        let mut block = vec![(data.len() - 1) as u8];
+        block.extend_from_slice(data);
+
+        self.command(CMD_WRITE_MEMORY)?;
+        self.send_checked(&address.to_be_bytes(), self.timeout)?;
+        self.send_checked(&block, self.timeout)
+    }
+
+    /// Erases pages, or all of flash if pages is None.
+    fn erase(
+        &mut self,
+        extended: bool,
+        pages: Option<&[u16]>,
+        timeout: Duration,
+    ) -> Result<(), Stm32Error> {
+        self.command(if extended {
+            CMD_EXTENDED_ERASE
+        } else {
+            CMD_ERASE
+        })?;
+
+        let request = match pages {
+            None if extended => vec![0xFF, 0xFF],
+            //Erase has no checksum for its global erase, just a byte and its complement
+            None => {
+                self.write(&[0xFF, 0x00])?;
+                return self.await_ack(timeout);
+            }
+            Some(pages) if extended => {
+                let mut request = ((pages.len() - 1) as u16).to_be_bytes().to_vec();
+                for page in pages {
+                    request.extend_from_slice(&page.to_be_bytes());
+                }
+                request
+            }
+            Some(pages) => {
+                let mut request = vec![(pages.len() - 1) as u8];
+                request.extend(pages.iter().map(|&page| page as u8));
+                request
+            }
+        };
+        self.send_checked(&request, timeout)
+    }
+
+    fn go(&mut self, address: u32) -> Result<(), Stm32Error> {
+        self.command(CMD_GO)?;
+        self.send_checked(&address.to_be_bytes(), self.timeout)
+    }
+}
+
+/// Lays segments out as word aligned blocks in address order, padding partial words with the erased
+/// value. Segments sharing a word are merged, so no word is programmed twice. Segments must not
+/// overlap.
+fn align_segments(segments: &[MemorySegment]) -> Vec<MemorySegment> {
+    let mut sorted: Vec<&MemorySegment> = segments
+        .iter()
+        .filter(|segment| !segment.data.is_empty())
+        .collect();
+    sorted.sort_by_key(|segment| segment.address);
+
+    let mut blocks: Vec<MemorySegment> = Vec::new();
+    for segment in sorted {
+        let start = segment.address & !(WORD as u32 - 1);
+        if blocks.last().is_none_or(|block| start as u64 > end(block)) {
+            blocks.push(MemorySegment {
+                address: start,
+                data: Vec::new(),
+            });
+        }
+
+        //Replaces the padding at the end of the block, or fills the gap up to the segment
+        let block = blocks.last_mut().unwrap();
+        block
+            .data
+            .resize((segment.address - block.address) as usize, ERASED);
+        block.data.extend_from_slice(&segment.data);
+        let len = block.data.len().next_multiple_of(WORD);
+        block.data.resize(len, ERASED);
+    }
+
+    blocks
+}
+
+fn invalid(desc: &str) -> Error {
+    Error::new(serialport::ErrorKind::InvalidInput, desc)
+}
+
+fn to_error(result: Result<(), Stm32Error>) -> Stm32Error {
+    result.err().unwrap_or(Stm32Error::NoErr)
+}
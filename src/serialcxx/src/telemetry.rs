@@ -0,0 +1,205 @@
+//! Schema driven parsing of CSV and key=value telemetry lines, for listeners in telemetry mode, see
+//! [crate::SerialListenerBuilder::set_telemetry_mode].
+
+use std::collections::HashSet;
+use std::ffi::c_void;
+use std::io::{BufReader, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use cancellation::CancellationToken;
+use serialport::Error;
+
+use crate::ffi::{
+    TelemetryFieldType, TelemetryFormat, TelemetryRecord, TelemetrySchema, TelemetryStats,
+};
+use crate::serial_ext::{read_line_bounded, CVoidSend, SerialPortReader};
+
+/// Callback for a parsed record. The record is only valid until the callback returns.
+pub type TelemetryCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, record: *const TelemetryRecord);
+
+/// Longest line kept while waiting for a terminator.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Checks that a schema can be parsed.
+pub fn check_schema(schema: &TelemetrySchema) -> serialport::Result<()> {
+    let invalid = |desc: &str| Err(Error::new(serialport::ErrorKind::InvalidInput, desc));
+
+    if schema.fields.is_empty() {
+        return invalid("Telemetry schema has no fields.");
+    }
+    if !schema.separator.is_ascii() || schema.separator == b'\n' || schema.separator == b'=' {
+        return invalid("Telemetry separator must be ASCII, and not a newline or '='.");
+    }
+
+    let mut names = HashSet::new();
+    for field in &schema.fields {
+        if field.name.is_empty() || !names.insert(field.name.as_str()) {
+            return invalid("Telemetry field names must be unique and not empty.");
+        }
+        if field.field_type != TelemetryFieldType::Int
+            && field.field_type != TelemetryFieldType::Float
+        {
+            return invalid("Unknown telemetry field type.");
+        }
+    }
+    if schema.format != TelemetryFormat::Csv && schema.format != TelemetryFormat::KeyValue {
+        return invalid("Unknown telemetry format.");
+    }
+
+    Ok(())
+}
+
+/// Parses a value of a field, returning None if it is not of the fields type.
+fn parse_value(text: &str, field_type: TelemetryFieldType) -> Option<f64> {
+    if field_type == TelemetryFieldType::Int {
+        text.parse::<i64>().ok().map(|value| value as f64)
+    } else {
+        text.parse::<f64>().ok()
+    }
+}
+
+/// Parses lines into records following a schema, and passes them to the callback.
+pub struct TelemetryDecoder {
+    schema: TelemetrySchema,
+    callback: (CVoidSend, TelemetryCallback),
+    records: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl TelemetryDecoder {
+    /// Creates a decoder for schema, which must have passed [check_schema].
+    pub fn new(schema: TelemetrySchema, callback: (CVoidSend, TelemetryCallback)) -> Self {
+        TelemetryDecoder {
+            schema,
+            callback,
+            records: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> TelemetryStats {
+        TelemetryStats {
+            records: self.records.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Handles one line read from the port.
+    pub fn handle_line(&self, line: &[u8]) {
+        let line = match std::str::from_utf8(line.trim_ascii()) {
+            Ok("") => return,
+            Ok(line) => line,
+            Err(_) => {
+                self.malformed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let parts = line.split(self.schema.separator as char).map(str::trim);
+        let values = if self.schema.format == TelemetryFormat::Csv {
+            //A header naming the fields is skipped, rather than counted as malformed
+            if parts
+                .clone()
+                .eq(self.schema.fields.iter().map(|field| field.name.as_str()))
+            {
+                return;
+            }
+            self.parse_csv(parts)
+        } else {
+            self.parse_key_value(parts)
+        };
+
+        match values {
+            Some(values) => {
+                self.records.fetch_add(1, Ordering::Relaxed);
+
+                let record = TelemetryRecord { values };
+                let (user_data, callback) = self.callback;
+                unsafe {
+                    //Safe only if callback does not store a reference to the record, which it does not own.
+                    callback(user_data.0, &record);
+                }
+            }
+            None => {
+                self.malformed.fetch_add(1, Ordering::Relaxed);
+                log::debug!("Dropping malformed telemetry line: {}", line);
+            }
+        }
+    }
+
+    /// Parses one value per field, in schema order.
+    fn parse_csv<'a>(&self, mut parts: impl Iterator<Item = &'a str>) -> Option<Vec<f64>> {
+        let values = self
+            .schema
+            .fields
+            .iter()
+            .map(|field| match parts.next()? {
+                "" if field.optional => Some(f64::NAN),
+                text => parse_value(text, field.field_type),
+            })
+            .collect::<Option<Vec<f64>>>()?;
+
+        //Extra columns mean the line does not follow the schema
+        match parts.next() {
+            None => Some(values),
+            Some(_) => None,
+        }
+    }
+
+    /// Parses key=value pairs in any order. Keys not in the schema are ignored.
+    fn parse_key_value<'a>(&self, parts: impl Iterator<Item = &'a str>) -> Option<Vec<f64>> {
+        let fields = &self.schema.fields;
+        let mut values: Vec<Option<f64>> = vec![None; fields.len()];
+
+        for part in parts.filter(|part| !part.is_empty()) {
+            let (key, text) = part.split_once('=')?;
+            if let Some(idx) = fields.iter().position(|field| field.name == key.trim()) {
+                values[idx] = Some(parse_value(text.trim(), fields[idx].field_type)?);
+            }
+        }
+
+        values
+            .into_iter()
+            .zip(fields)
+            .map(|(value, field)| match value {
+                None if field.optional => Some(f64::NAN),
+                value => value,
+            })
+            .collect()
+    }
+}
+
+/// Body of a listener thread in telemetry mode.
+pub fn read_loop(
+    reader: &mut BufReader<SerialPortReader>,
+    decoder: &TelemetryDecoder,
+    token: &CancellationToken,
+) {
+    let mut line = Vec::new();
+
+    while !token.is_canceled() {
+        match read_line_bounded(reader, &mut line, MAX_LINE_LEN) {
+            Ok(0) => {
+                log::warn!("Telemetry listener hit end of file");
+                break;
+            }
+            Ok(_) if line.ends_with(b"\n") => {
+                decoder.handle_line(&line);
+                line.clear();
+            }
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("Telemetry listener failed: {}", err);
+                break;
+            }
+        }
+
+        //Binary data with no newlines would otherwise grow the line forever
+        if line.len() > MAX_LINE_LEN {
+            decoder.malformed.fetch_add(1, Ordering::Relaxed);
+            line.clear();
+        }
+    }
+}
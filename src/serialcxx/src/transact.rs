@@ -0,0 +1,119 @@
+//! Request/response transactions, see [crate::Serial::transact].
+
+use std::io::{BufRead, BufReader, ErrorKind};
+
+use regex::Regex;
+use serialport::{Error, Result};
+
+use crate::ffi::{MatchKind, SerialError, TransactResult};
+use crate::serial_ext::{io_error_to_serial, DeadlineGuard, SerialPortReader};
+
+/// Decides which line is the response to a transaction.
+pub enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    /// Creates a matcher of the given kind.
+    ///
+    /// This function will error if a regex pattern does not compile.
+    pub fn new(kind: MatchKind, pattern: &str) -> Result<Matcher> {
+        match kind {
+            MatchKind::Exact => Ok(Matcher::Exact(pattern.to_string())),
+            MatchKind::Prefix => Ok(Matcher::Prefix(pattern.to_string())),
+            MatchKind::Regex => Regex::new(pattern)
+                .map(Matcher::Regex)
+                .map_err(|err| Error::new(serialport::ErrorKind::InvalidInput, err.to_string())),
+            _ => Err(Error::new(
+                serialport::ErrorKind::InvalidInput,
+                "Unknown match kind.",
+            )),
+        }
+    }
+
+    pub fn matches(&self, line: &str) -> bool {
+        match self {
+            Matcher::Exact(pattern) => line == pattern,
+            Matcher::Prefix(pattern) => line.starts_with(pattern.as_str()),
+            Matcher::Regex(regex) => regex.is_match(line),
+        }
+    }
+}
+
+/// Reads a single line, retrying timeouts until the deadline guarded by deadline passes.
+///
+/// Bytes read before a failure stay in line, so a partial line is never lost. Returns Ok(false) if
+/// the deadline passed or the port hit end of file before a full line arrived. The terminator is
+/// included in line.
+pub fn read_line_until(
+    reader: &mut BufReader<SerialPortReader>,
+    deadline: &DeadlineGuard,
+    line: &mut Vec<u8>,
+) -> std::io::Result<bool> {
+    while deadline.arm() {
+        match reader.read_until(b'\n', line) {
+            Ok(0) => return Ok(false),
+            Ok(_) if line.ends_with(b"\n") => return Ok(true),
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Converts a raw line to a string, removing the \n or \r\n terminator.
+pub fn trim_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// Reads lines until one satisfies matcher, collecting the lines that did not.
+///
+/// The command must already have been written, and the caller must hold the read handle for the
+/// whole transaction so no other reader can take the reply.
+pub fn await_response(
+    reader: &mut BufReader<SerialPortReader>,
+    deadline: &DeadlineGuard,
+    matcher: &Matcher,
+) -> TransactResult {
+    let mut unmatched = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        match read_line_until(reader, deadline, &mut line) {
+            Ok(true) => {
+                let text = trim_line(&line);
+                line.clear();
+
+                if matcher.matches(&text) {
+                    return TransactResult {
+                        error: SerialError::NoErr,
+                        response: text,
+                        unmatched,
+                    };
+                }
+                unmatched.push(text);
+            }
+            Ok(false) => {
+                return TransactResult {
+                    error: SerialError::Timeout,
+                    response: String::new(),
+                    unmatched,
+                }
+            }
+            Err(err) => {
+                return TransactResult {
+                    error: io_error_to_serial(&err),
+                    response: String::new(),
+                    unmatched,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,512 @@
+//! u-blox UBX protocol client, created with [crate::Serial::create_ubx_client].
+
+use std::ffi::{c_void, CString};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use parking_lot::Condvar;
+use serialport::SerialPort;
+
+use crate::crc::ubx_checksum;
+use crate::ffi::{SerialError, UbxAckResult, UbxNavPvt, UbxStats};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{CVoidSend, SerialPortReader};
+use crate::write_queue::write_counted;
+use crate::Mutex;
+
+pub const SYNC_1: u8 = 0xB5;
+pub const SYNC_2: u8 = 0x62;
+
+pub const CLASS_NAV: u8 = 0x01;
+pub const CLASS_ACK: u8 = 0x05;
+pub const NAV_PVT: u8 = 0x07;
+pub const ACK_NAK: u8 = 0x00;
+pub const ACK_ACK: u8 = 0x01;
+
+/// Sync, class, id and length.
+const HEADER_LEN: usize = 6;
+/// Larger payloads are assumed to be a false sync in the NMEA text. The biggest messages sent by
+/// current receivers are a few kilobytes.
+const MAX_PAYLOAD_LEN: usize = 8192;
+/// Longest text line kept while waiting for a terminator.
+const MAX_LINE_LEN: usize = 1024;
+
+/// Callback for every valid UBX frame.
+pub type UbxMessageCallback = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    msg_class: u8,
+    msg_id: u8,
+    payload: *const u8,
+    payload_size: usize,
+);
+/// Callback for decoded NAV-PVT messages.
+pub type UbxNavPvtCallback = unsafe extern "C" fn(user_data: *mut c_void, pvt: *const UbxNavPvt);
+/// Callback for lines of text between frames, such as NMEA sentences.
+pub type UbxTextCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, line: *const c_char, line_size: usize);
+
+/// Builds a UBX frame around a payload.
+pub fn ubx_frame(class: u8, id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + HEADER_LEN + 2);
+    frame.extend_from_slice(&[SYNC_1, SYNC_2, class, id]);
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(payload);
+
+    let (ck_a, ck_b) = ubx_checksum(&frame[2..]);
+    frame.extend_from_slice(&[ck_a, ck_b]);
+
+    frame
+}
+
+/// Something separated out of the byte stream by [UbxSplitter].
+#[derive(Debug, PartialEq)]
+pub enum UbxChunk {
+    Frame {
+        class: u8,
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A frame header was found, but its checksum failed.
+    BadFrame,
+    /// A line of text, including its terminator.
+    Text(Vec<u8>),
+}
+
+/// Separates UBX frames from the text interleaved with them.
+///
+/// A frame may start anywhere, even in the middle of a line. Bytes that fail to form a frame are
+/// treated as text, so a false sync in the text only delays it.
+#[derive(Default)]
+pub struct UbxSplitter {
+    pending: Vec<u8>,
+    text: Vec<u8>,
+}
+
+impl UbxSplitter {
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Returns the next complete chunk, or None if more input is needed.
+    pub fn next_chunk(&mut self) -> Option<UbxChunk> {
+        loop {
+            if self.pending.is_empty() {
+                return None;
+            }
+
+            if self.pending[0] == SYNC_1 {
+                match self.pending.get(1) {
+                    None => return None,
+                    Some(&SYNC_2) => match self.take_frame() {
+                        Ok(chunk) => return Some(chunk),
+                        Err(true) => return None, //Need more input
+                        Err(false) => {}          //Not a frame, fall through to text
+                    },
+                    Some(_) => {}
+                }
+                self.text.push(SYNC_1);
+                self.pending.remove(0);
+                continue;
+            }
+
+            //Take text up to the next possible frame or end of line
+            match self
+                .pending
+                .iter()
+                .position(|&byte| byte == SYNC_1 || byte == b'\n')
+            {
+                Some(end) if self.pending[end] == b'\n' => {
+                    self.text.extend(self.pending.drain(..=end));
+                    return Some(UbxChunk::Text(std::mem::take(&mut self.text)));
+                }
+                Some(end) => {
+                    self.text.extend(self.pending.drain(..end));
+                }
+                None => {
+                    self.text.append(&mut self.pending);
+                }
+            }
+
+            if self.text.len() > MAX_LINE_LEN {
+                log::debug!("Dropping overlong text between UBX frames");
+                self.text.clear();
+            }
+        }
+    }
+
+    /// Takes a frame off the front of pending, which starts with both sync bytes.
+    ///
+    /// Errs with true if more input is needed, or false if this is not a frame.
+    fn take_frame(&mut self) -> Result<UbxChunk, bool> {
+        if self.pending.len() < HEADER_LEN {
+            return Err(true);
+        }
+
+        let len = u16::from_le_bytes([self.pending[4], self.pending[5]]) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(false);
+        }
+        if self.pending.len() < HEADER_LEN + len + 2 {
+            return Err(true);
+        }
+
+        let checksum = ubx_checksum(&self.pending[2..HEADER_LEN + len]);
+        if checksum
+            != (
+                self.pending[HEADER_LEN + len],
+                self.pending[HEADER_LEN + len + 1],
+            )
+        {
+            //Only drop the sync, in case a real frame starts inside this one
+            self.pending.drain(..2);
+            return Ok(UbxChunk::BadFrame);
+        }
+
+        let frame: Vec<u8> = self.pending.drain(..HEADER_LEN + len + 2).collect();
+        Ok(UbxChunk::Frame {
+            class: frame[2],
+            id: frame[3],
+            payload: frame[HEADER_LEN..HEADER_LEN + len].to_vec(),
+        })
+    }
+}
+
+fn le_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn le_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn le_i32(data: &[u8], at: usize) -> i32 {
+    le_u32(data, at) as i32
+}
+
+/// Decodes a NAV-PVT payload, or None if it is too short.
+pub fn decode_nav_pvt(payload: &[u8]) -> Option<UbxNavPvt> {
+    if payload.len() < 92 {
+        return None;
+    }
+
+    let valid = payload[11];
+    let flags = payload[21];
+
+    Some(UbxNavPvt {
+        itow: le_u32(payload, 0),
+        year: le_u16(payload, 4),
+        month: payload[6],
+        day: payload[7],
+        hour: payload[8],
+        minute: payload[9],
+        second: payload[10],
+        nano: le_i32(payload, 16),
+        valid_date: valid & 0x01 != 0,
+        valid_time: valid & 0x02 != 0,
+        fully_resolved: valid & 0x04 != 0,
+        time_accuracy: le_u32(payload, 12),
+        fix_type: payload[20],
+        gnss_fix_ok: flags & 0x01 != 0,
+        satellites: payload[23],
+        longitude: le_i32(payload, 24) as f64 * 1e-7,
+        latitude: le_i32(payload, 28) as f64 * 1e-7,
+        height: le_i32(payload, 32) as f64 / 1000.0,
+        height_msl: le_i32(payload, 36) as f64 / 1000.0,
+        horizontal_accuracy: le_u32(payload, 40) as f64 / 1000.0,
+        vertical_accuracy: le_u32(payload, 44) as f64 / 1000.0,
+        velocity_north: le_i32(payload, 48) as f64 / 1000.0,
+        velocity_east: le_i32(payload, 52) as f64 / 1000.0,
+        velocity_down: le_i32(payload, 56) as f64 / 1000.0,
+        ground_speed: le_i32(payload, 60) as f64 / 1000.0,
+        heading_motion: le_i32(payload, 64) as f64 * 1e-5,
+        speed_accuracy: le_u32(payload, 68) as f64 / 1000.0,
+        heading_accuracy: le_u32(payload, 72) as f64 * 1e-5,
+        pdop: le_u16(payload, 76) as f64 * 0.01,
+    })
+}
+
+/// The message currently waiting for its acknowledgement.
+struct Pending {
+    class: u8,
+    id: u8,
+    /// Set by the reader once an ACK or NAK arrives.
+    acknowledged: Option<bool>,
+}
+
+struct Shared {
+    pending: Mutex<Option<Pending>>,
+    /// Signalled when the pending message is acknowledged.
+    completed: Condvar,
+    message_callback: Mutex<Option<(CVoidSend, UbxMessageCallback)>>,
+    nav_pvt_callback: Mutex<Option<(CVoidSend, UbxNavPvtCallback)>>,
+    text_callback: Mutex<Option<(CVoidSend, UbxTextCallback)>>,
+    frames: AtomicU64,
+    bad_checksums: AtomicU64,
+}
+
+impl Shared {
+    fn handle_chunk(&self, chunk: UbxChunk) {
+        match chunk {
+            UbxChunk::Frame { class, id, payload } => {
+                self.frames.fetch_add(1, Ordering::Relaxed);
+                self.handle_frame(class, id, &payload);
+            }
+            UbxChunk::BadFrame => {
+                log::debug!("Dropping UBX frame with bad checksum");
+                self.bad_checksums.fetch_add(1, Ordering::Relaxed);
+            }
+            UbxChunk::Text(line) => self.handle_text(&line),
+        }
+    }
+
+    fn handle_frame(&self, class: u8, id: u8, payload: &[u8]) {
+        if class == CLASS_ACK && (id == ACK_ACK || id == ACK_NAK) && payload.len() >= 2 {
+            let mut pending = self.pending.lock();
+            if let Some(pending) = pending
+                .as_mut()
+                .filter(|p| p.acknowledged.is_none() && p.class == payload[0] && p.id == payload[1])
+            {
+                pending.acknowledged = Some(id == ACK_ACK);
+                self.completed.notify_all();
+            }
+        }
+
+        if class == CLASS_NAV && id == NAV_PVT {
+            let callback = *self.nav_pvt_callback.lock();
+            if let (Some((user_data, callback)), Some(pvt)) = (callback, decode_nav_pvt(payload)) {
+                unsafe {
+                    //Safe only if callback does not store a reference to the message, which it does not own.
+                    callback(user_data.0, &pvt);
+                }
+            }
+        }
+
+        let callback = *self.message_callback.lock();
+        if let Some((user_data, callback)) = callback {
+            unsafe {
+                //Safe only if callback does not store a reference to the payload, which it does not own.
+                callback(user_data.0, class, id, payload.as_ptr(), payload.len());
+            }
+        }
+    }
+
+    fn handle_text(&self, line: &[u8]) {
+        let line = line.trim_ascii_end();
+        if line.is_empty() {
+            return;
+        }
+
+        let callback = *self.text_callback.lock();
+        if let Some((user_data, callback)) = callback {
+            let c_str = match CString::new(line) {
+                Ok(c_str) => c_str,
+                Err(_) => {
+                    log::warn!("Dropping text line with internal null bytes");
+                    return;
+                }
+            };
+
+            unsafe {
+                //Safe only if callback does not store a reference to the string, which it does not own.
+                callback(user_data.0, c_str.as_ptr(), line.len());
+            }
+        }
+    }
+}
+
+/// A client for u-blox receivers that speak the UBX binary protocol alongside NMEA.
+///
+/// The client owns a reader thread that holds the read handle of its port for as long as the client
+/// lives, exactly like a [crate::SerialListener]. The thread separates UBX frames from the text around
+/// them, so both can share the port.
+pub struct UbxClient {
+    shared: Arc<Shared>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    /// Ensures only one message is waiting for its acknowledgement at a time.
+    send_lock: Mutex<()>,
+    /// Token used to kill the reader thread.
+    cts: CancellationTokenSource,
+}
+
+impl UbxClient {
+    /// Creates a client and starts its reader thread.
+    pub fn start(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    ) -> UbxClient {
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(None),
+            completed: Condvar::new(),
+            message_callback: Mutex::new(None),
+            nav_pvt_callback: Mutex::new(None),
+            text_callback: Mutex::new(None),
+            frames: AtomicU64::new(0),
+            bad_checksums: AtomicU64::new(0),
+        });
+        let cts = CancellationTokenSource::new();
+
+        let token = cts.token().clone();
+        let thread_shared = shared.clone();
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = reader.lock();
+
+        let thread_reader = reader.clone();
+        std::thread::spawn(move || {
+            log::debug!("Spawned UBX reader");
+            read_loop(&thread_shared, &thread_reader, &token);
+            log::debug!("exiting UBX reader thread")
+        });
+        //Thread detaches here
+
+        UbxClient {
+            shared,
+            write_handle,
+            send_lock: Mutex::new(()),
+            cts,
+        }
+    }
+
+    /// Sends a UBX message and waits up to timeout seconds for the receiver to acknowledge it.
+    pub fn send_ubx(
+        &self,
+        msg_class: u8,
+        msg_id: u8,
+        payload: &[u8],
+        timeout: f32,
+    ) -> UbxAckResult {
+        if payload.len() > u16::MAX as usize {
+            return UbxAckResult {
+                error: SerialError::Other,
+                acknowledged: false,
+            };
+        }
+
+        let _send = self.send_lock.lock();
+        let deadline = Instant::now().checked_add(timeout_from_secs(timeout));
+
+        *self.shared.pending.lock() = Some(Pending {
+            class: msg_class,
+            id: msg_id,
+            acknowledged: None,
+        });
+
+        let (error, _) = write_counted(
+            &mut **self.write_handle.lock(),
+            &ubx_frame(msg_class, msg_id, payload),
+        );
+
+        let mut pending = self.shared.pending.lock();
+        if error == SerialError::NoErr {
+            while pending.as_ref().is_some_and(|p| p.acknowledged.is_none()) {
+                match deadline {
+                    Some(deadline) => {
+                        if self
+                            .shared
+                            .completed
+                            .wait_until(&mut pending, deadline)
+                            .timed_out()
+                        {
+                            break;
+                        }
+                    }
+                    None => self.shared.completed.wait(&mut pending),
+                }
+            }
+        }
+
+        match pending.take().unwrap().acknowledged {
+            Some(acknowledged) => UbxAckResult {
+                error: SerialError::NoErr,
+                acknowledged,
+            },
+            None => UbxAckResult {
+                error: if error == SerialError::NoErr {
+                    SerialError::Timeout
+                } else {
+                    error
+                },
+                acknowledged: false,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> UbxStats {
+        UbxStats {
+            frames: self.shared.frames.load(Ordering::Relaxed),
+            bad_checksums: self.shared.bad_checksums.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets the callback that receives every valid frame, replacing any previous one.
+    pub fn set_message_callback(&self, callback: Option<(CVoidSend, UbxMessageCallback)>) {
+        *self.shared.message_callback.lock() = callback;
+    }
+
+    /// Sets the callback that receives decoded NAV-PVT messages, replacing any previous one.
+    pub fn set_nav_pvt_callback(&self, callback: Option<(CVoidSend, UbxNavPvtCallback)>) {
+        *self.shared.nav_pvt_callback.lock() = callback;
+    }
+
+    /// Sets the callback that receives lines of text between frames, replacing any previous one.
+    pub fn set_text_callback(&self, callback: Option<(CVoidSend, UbxTextCallback)>) {
+        *self.shared.text_callback.lock() = callback;
+    }
+
+    /// Stops the reader thread, releasing the read handle once its current read times out.
+    pub fn stop(&self) {
+        self.cts.cancel();
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this client
+    /// to the callback setter functions.
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut UbxClient {
+        self as *mut UbxClient
+    }
+}
+
+impl Drop for UbxClient {
+    fn drop(&mut self) {
+        self.cts.cancel() //The token will be kept alive by the thread, so this doesn't create a dangling pointer.
+    }
+}
+
+/// Body of the reader thread.
+fn read_loop(
+    shared: &Shared,
+    reader: &Mutex<BufReader<SerialPortReader>>,
+    token: &CancellationToken,
+) {
+    //Lock the reader while this client is alive
+    let mut reader = reader.lock();
+    let mut splitter = UbxSplitter::default();
+
+    while !token.is_canceled() {
+        match reader.fill_buf() {
+            Ok([]) => {
+                log::warn!("UBX reader hit end of file");
+                break;
+            }
+            Ok(buf) => {
+                splitter.push(buf);
+                let len = buf.len();
+                reader.consume(len);
+
+                while let Some(chunk) = splitter.next_chunk() {
+                    shared.handle_chunk(chunk);
+                }
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => {
+                log::warn!("UBX reader failed: {}", err);
+                break;
+            }
+        }
+    }
+}
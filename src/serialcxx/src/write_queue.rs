@@ -0,0 +1,271 @@
+//! Background writer backing [crate::Serial::write_async].
+
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use parking_lot::Condvar;
+use serialport::SerialPort;
+
+use crate::ffi::{SerialError, WritePriority};
+use crate::serial_ext::{io_error_to_serial, CVoidSend};
+use crate::Mutex;
+
+/// Number of messages that may be waiting at each priority before [WriteQueue::push] starts refusing them.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// Number of [WritePriority] levels.
+const PRIORITY_LEVELS: usize = 3;
+
+/// Completion callback for queued writes. Called on the writer thread with the user data passed
+/// alongside the write, the outcome, and the number of bytes that made it to the port.
+pub type WriteCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, error: SerialError, bytes_written: usize);
+
+/// A single message waiting to be written.
+struct WriteJob {
+    data: Vec<u8>,
+    callback: Option<(CVoidSend, WriteCallback)>,
+}
+
+impl WriteJob {
+    /// Invokes the completion callback, if any.
+    fn complete(&self, error: SerialError, bytes_written: usize) {
+        if let Some((user_data, callback)) = self.callback {
+            unsafe {
+                //Safe as long as C++ keeps user_data alive until the callback is called.
+                callback(user_data.0, error, bytes_written);
+            }
+        }
+    }
+}
+
+struct QueueState {
+    /// One FIFO per priority, indexed by [priority_index].
+    jobs: [VecDeque<WriteJob>; PRIORITY_LEVELS],
+    /// True while the worker is writing a job it has already popped.
+    in_flight: bool,
+    capacity: usize,
+    shutdown: bool,
+}
+
+impl QueueState {
+    /// Messages that have been accepted but not yet completed.
+    fn depth(&self) -> usize {
+        self.queued() + self.in_flight as usize
+    }
+
+    /// Messages waiting to be written, across all priorities.
+    fn queued(&self) -> usize {
+        self.jobs.iter().map(VecDeque::len).sum()
+    }
+
+    /// Takes the oldest message of the highest priority that has any waiting.
+    fn pop_next(&mut self) -> Option<WriteJob> {
+        self.jobs.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+}
+
+/// Maps a priority to its queue. Higher priorities get higher indices.
+fn priority_index(priority: WritePriority) -> usize {
+    match priority {
+        WritePriority::Low => 0,
+        WritePriority::High => 2,
+        _ => 1,
+    }
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    /// Signalled when a job is pushed, or on shutdown.
+    job_ready: Condvar,
+    /// Signalled whenever the queue becomes empty.
+    drained: Condvar,
+}
+
+/// A bounded queue of writes, serviced by a lazily spawned writer thread.
+///
+/// Messages are written whole, highest priority first, and in FIFO order within a priority. Each
+/// priority is bounded separately, so a backlog of bulk data never prevents urgent messages from
+/// being queued. The writer locks the same write handle as [crate::Serial::write], so queued and
+/// blocking writes never interleave within a message.
+pub struct WriteQueue {
+    shared: Arc<Shared>,
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WriteQueue {
+    pub fn new(port: Arc<Mutex<Box<dyn SerialPort>>>) -> Self {
+        WriteQueue {
+            shared: Arc::new(Shared {
+                state: Mutex::new(QueueState {
+                    jobs: Default::default(),
+                    in_flight: false,
+                    capacity: DEFAULT_QUEUE_CAPACITY,
+                    shutdown: false,
+                }),
+                job_ready: Condvar::new(),
+                drained: Condvar::new(),
+            }),
+            port,
+            worker: Mutex::new(None),
+        }
+    }
+
+    /// Queues data to be written, returning QueueFull without queuing if its priority is at capacity.
+    pub fn push(
+        &self,
+        data: Vec<u8>,
+        priority: WritePriority,
+        callback: Option<(CVoidSend, WriteCallback)>,
+    ) -> SerialError {
+        self.ensure_worker();
+
+        let mut state = self.shared.state.lock();
+        let capacity = state.capacity;
+        let jobs = &mut state.jobs[priority_index(priority)];
+        if jobs.len() >= capacity {
+            return SerialError::QueueFull;
+        }
+
+        jobs.push_back(WriteJob { data, callback });
+        self.shared.job_ready.notify_one();
+
+        SerialError::NoErr
+    }
+
+    /// Number of messages queued or currently being written.
+    pub fn depth(&self) -> usize {
+        self.shared.state.lock().depth()
+    }
+
+    /// Sets the number of messages that may wait at each priority. Messages already queued are kept.
+    pub fn set_capacity(&self, messages: usize) {
+        self.shared.state.lock().capacity = messages;
+    }
+
+    /// Blocks until every accepted message has completed, or until the deadline passes. Called from
+    /// a completion callback, this does not wait, since the queue cannot drain until it returns.
+    ///
+    /// Returns true if the queue drained.
+    pub fn flush(&self, deadline: Option<Instant>) -> bool {
+        let on_writer = self
+            .worker
+            .lock()
+            .as_ref()
+            .is_some_and(|worker| worker.thread().id() == std::thread::current().id());
+        //The message whose callback is running is still in flight
+        if on_writer {
+            return false;
+        }
+
+        let mut state = self.shared.state.lock();
+
+        while state.depth() > 0 {
+            match deadline {
+                Some(deadline) => {
+                    if self
+                        .shared
+                        .drained
+                        .wait_until(&mut state, deadline)
+                        .timed_out()
+                    {
+                        return state.depth() == 0;
+                    }
+                }
+                None => self.shared.drained.wait(&mut state),
+            }
+        }
+
+        true
+    }
+
+    /// Spawns the writer thread if it is not running yet.
+    fn ensure_worker(&self) {
+        let mut worker = self.worker.lock();
+        if worker.is_some() {
+            return;
+        }
+
+        let shared = self.shared.clone();
+        let port = self.port.clone();
+        *worker = Some(std::thread::spawn(move || {
+            log::debug!("Spawned writer");
+            write_loop(&shared, &port);
+            log::debug!("exiting writer thread")
+        }));
+    }
+}
+
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.job_ready.notify_all();
+
+        //Waits at most for the message currently being written
+        if let Some(worker) = self.worker.lock().take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Body of the writer thread. Pops jobs until shutdown, at which point any remaining jobs are
+/// completed as Interrupted.
+fn write_loop(shared: &Shared, port: &Mutex<Box<dyn SerialPort>>) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock();
+            while state.queued() == 0 && !state.shutdown {
+                shared.job_ready.wait(&mut state);
+            }
+
+            if state.shutdown {
+                let remaining: Vec<_> = state
+                    .jobs
+                    .iter_mut()
+                    .rev()
+                    .flat_map(|jobs| jobs.drain(..))
+                    .collect();
+                drop(state);
+
+                for job in remaining {
+                    job.complete(SerialError::Interrupted, 0);
+                }
+                shared.drained.notify_all();
+                return;
+            }
+
+            state.in_flight = true;
+            state.pop_next().unwrap()
+        };
+
+        let (error, bytes_written) = write_counted(&mut **port.lock(), &job.data);
+        job.complete(error, bytes_written);
+
+        let mut state = shared.state.lock();
+        state.in_flight = false;
+        if state.queued() == 0 {
+            shared.drained.notify_all();
+        }
+    }
+}
+
+/// Writes all of data like [std::io::Write::write_all], but reports how much was written on failure.
+pub fn write_counted(port: &mut dyn SerialPort, data: &[u8]) -> (SerialError, usize) {
+    let mut written = 0;
+
+    while written < data.len() {
+        match port.write(&data[written..]) {
+            Ok(0) => return (SerialError::Other, written),
+            Ok(n) => written += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return (io_error_to_serial(&err), written),
+        }
+    }
+
+    (SerialError::NoErr, written)
+}
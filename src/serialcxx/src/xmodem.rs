@@ -0,0 +1,693 @@
+//! XMODEM-CRC, XMODEM-1K and YMODEM batch file transfers, created with
+//! [crate::Serial::create_file_transfer].
+//!
+//! Each block is `[SOH or STX][seq][!seq][128 or 1024 bytes][CRC-16/XMODEM, high byte first]`, and is
+//! acknowledged with ACK or refused with NAK. The receiver starts a transfer by sending 'C'. YMODEM
+//! sends each file's name and size in block 0 before its data, and ends the batch with an empty
+//! block 0. Transfers run on their own thread, which holds the read handle until they finish.
+
+use std::ffi::c_void;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cancellation::{CancellationToken, CancellationTokenSource};
+use parking_lot::Condvar;
+use serialport::{Error, SerialPort};
+
+use crate::crc::sum8;
+use crate::ffi::{
+    ChecksumKind, SerialError, TransferConfig, TransferFile, TransferProgress, TransferProtocol,
+    TransferResult, TransferStatus,
+};
+use crate::serial::timeout_from_secs;
+use crate::serial_ext::{CVoidSend, DeadlineGuard, SerialPortReader};
+use crate::write_queue::write_counted;
+use crate::{Mutex, MutexGuard};
+
+/// Starts a 128 byte block.
+const SOH: u8 = 0x01;
+/// Starts a 1024 byte block.
+const STX: u8 = 0x02;
+/// Ends a file.
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+/// Two in a row cancel the transfer.
+const CAN: u8 = 0x18;
+/// Sent by the receiver to start a transfer checked with CRC-16.
+const CRC_REQUEST: u8 = b'C';
+/// Pads the last block of a file.
+const SUB: u8 = 0x1A;
+
+/// How often a blocked read checks for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The longest silence waited for when discarding input.
+const PURGE_SILENCE: Duration = Duration::from_secs(1);
+
+/// Callback for transfer progress. The progress is only valid until the callback returns.
+pub type ProgressCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, progress: *const TransferProgress);
+
+/// Why a transfer stopped early.
+enum Abort {
+    Cancelled,
+    RemoteCancelled,
+    TooManyRetries,
+    Protocol(&'static str),
+    Port(String),
+}
+
+type Step<T> = std::result::Result<T, Abort>;
+
+/// A block read by the receiver.
+enum Received {
+    Block(u8, Vec<u8>),
+    Eot,
+}
+
+enum Phase {
+    Idle,
+    Running,
+    Finished(TransferResult),
+}
+
+struct Shared {
+    phase: Mutex<Phase>,
+    /// Notified when the transfer finishes.
+    finished: Condvar,
+    /// Files to send, queued with [FileTransfer::add_file].
+    files: Mutex<Vec<TransferFile>>,
+    progress_callback: Mutex<Option<(CVoidSend, ProgressCallback)>>,
+}
+
+/// One side of a transfer, running on the thread that holds the read handle.
+struct Session<'a> {
+    reader: MutexGuard<'a, BufReader<SerialPortReader>>,
+    settings: &'a Mutex<Box<dyn SerialPort>>,
+    write_handle: &'a Mutex<Box<dyn SerialPort>>,
+    token: &'a CancellationToken,
+    shared: &'a Shared,
+    protocol: TransferProtocol,
+    timeout: Duration,
+    max_retries: u32,
+    /// Retries over the whole transfer, for progress reports.
+    retries: u32,
+}
+
+impl Session<'_> {
+    /// Reads one byte, returning None if none arrives within timeout.
+    fn read_byte(&mut self, timeout: Duration) -> Step<Option<u8>> {
+        //A deadline too far away to represent never passes
+        let deadline = Instant::now().checked_add(timeout);
+        let settings = self.settings;
+
+        loop {
+            if self.token.is_canceled() {
+                return Err(Abort::Cancelled);
+            }
+            if let Some(&byte) = self.reader.buffer().first() {
+                self.reader.consume(1);
+                return Ok(Some(byte));
+            }
+
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Ok(None);
+            }
+
+            //Wake up regularly, so cancellation does not wait for a long timeout
+            let poll = now + POLL_INTERVAL;
+            let guard = DeadlineGuard::new(settings, Some(deadline.map_or(poll, |d| d.min(poll))));
+            if !guard.arm() {
+                continue;
+            }
+            match self.reader.fill_buf() {
+                Ok([]) => return Err(Abort::Port("Port hit end of file.".to_string())),
+                Ok(_) => {}
+                Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+                Err(err) => return Err(Abort::Port(err.to_string())),
+            }
+        }
+    }
+
+    /// Fills buf, returning false if the sender goes quiet first.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Step<bool> {
+        for byte in buf.iter_mut() {
+            match self.read_byte(self.timeout)? {
+                Some(read) => *byte = read,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Discards input until the line goes quiet, so a retry starts on a block boundary and stale
+    /// replies are not mistaken for new ones. The silence is kept well under the timeout, so the
+    /// peer retrying does not keep the line busy forever.
+    fn purge(&mut self) -> Step<()> {
+        let silence = (self.timeout / 4).min(PURGE_SILENCE);
+        while self.read_byte(silence)?.is_some() {}
+        Ok(())
+    }
+
+    fn write(&self, data: &[u8]) -> Step<()> {
+        match write_counted(&mut **self.write_handle.lock(), data) {
+            (SerialError::NoErr, _) => Ok(()),
+            (error, _) => Err(Abort::Port(format!(
+                "Failed to write to the port: {:?}",
+                error.repr
+            ))),
+        }
+    }
+
+    /// Called after a CAN, cancelling the transfer if another follows. A lone CAN is line noise.
+    fn check_cancel(&mut self) -> Step<()> {
+        match self.read_byte(self.timeout.min(PURGE_SILENCE))? {
+            Some(CAN) => Err(Abort::RemoteCancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failed attempt, giving up once there have been more than max_retries.
+    fn retry(&mut self, attempts: &mut u32) -> Step<()> {
+        *attempts += 1;
+        if *attempts > self.max_retries {
+            return Err(Abort::TooManyRetries);
+        }
+        self.retries += 1;
+        Ok(())
+    }
+
+    fn report(&self, file_index: usize, name: &str, bytes_done: usize, bytes_total: usize) {
+        //Copied out, so the callback can replace itself without deadlocking
+        let callback = *self.shared.progress_callback.lock();
+        if let Some((user_data, callback)) = callback {
+            let progress = TransferProgress {
+                file_index: file_index as u32,
+                name: name.to_string(),
+                bytes_done: bytes_done as u64,
+                bytes_total: bytes_total as u64,
+                retries: self.retries,
+            };
+            unsafe {
+                //Safe only if callback does not store a reference to the progress, which it does not own.
+                callback(user_data.0, &progress);
+            }
+        }
+    }
+
+    /// Waits for the receiver to start, returning true if it asked for CRC-16 rather than an 8 bit sum.
+    fn await_start(&mut self) -> Step<bool> {
+        let mut attempts = 0;
+
+        loop {
+            match self.read_byte(self.timeout)? {
+                Some(CRC_REQUEST) => return Ok(true),
+                Some(NAK) => return Ok(false),
+                Some(CAN) => self.check_cancel()?,
+                Some(_) => {}
+                None => self.retry(&mut attempts)?,
+            }
+        }
+    }
+
+    /// Waits for an ACK or NAK, returning None if neither arrives in time.
+    fn await_reply(&mut self) -> Step<Option<u8>> {
+        loop {
+            match self.read_byte(self.timeout)? {
+                Some(reply @ (ACK | NAK)) => return Ok(Some(reply)),
+                Some(CAN) => self.check_cancel()?,
+                //Receivers may still be sending 'C' from before this block
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Sends a block of exactly 128 or 1024 bytes until it is acknowledged.
+    fn send_block(&mut self, seq: u8, data: &[u8], crc: bool) -> Step<()> {
+        let mut block = Vec::with_capacity(data.len() + 5);
+        block.push(if data.len() == 1024 { STX } else { SOH });
+        block.extend_from_slice(&[seq, !seq]);
+        block.extend_from_slice(data);
+        if crc {
+            let crc = ChecksumKind::Crc16Xmodem.compute(data) as u16;
+            block.extend_from_slice(&crc.to_be_bytes());
+        } else {
+            block.push(sum8(data));
+        }
+
+        let mut attempts = 0;
+        loop {
+            self.write(&block)?;
+            match self.await_reply()? {
+                Some(ACK) => return Ok(()),
+                _ => {
+                    self.retry(&mut attempts)?;
+                    self.purge()?;
+                }
+            }
+        }
+    }
+
+    /// Sends EOT until it is acknowledged.
+    fn send_eot(&mut self) -> Step<()> {
+        let mut attempts = 0;
+        let mut first = true;
+
+        loop {
+            self.write(&[EOT])?;
+            match self.await_reply()? {
+                Some(ACK) => return Ok(()),
+                //YMODEM receivers refuse the first EOT, to be sure it is not line noise
+                Some(NAK) if first => {}
+                _ => self.retry(&mut attempts)?,
+            }
+            first = false;
+        }
+    }
+
+    /// Sends the data blocks of a file, then EOT.
+    fn send_file(&mut self, file_index: usize, file: &TransferFile, crc: bool) -> Step<()> {
+        let data = file.data.as_slice();
+        let long_blocks = self.protocol != TransferProtocol::Xmodem;
+        let mut seq = 1u8;
+        let mut sent = 0;
+
+        while sent < data.len() {
+            //Short tails go in a 128 byte block, to save sending up to 1K of padding
+            let block_len = if long_blocks && data.len() - sent > 128 {
+                1024
+            } else {
+                128
+            };
+            let chunk = &data[sent..data.len().min(sent + block_len)];
+
+            let mut block = chunk.to_vec();
+            block.resize(block_len, SUB);
+            self.send_block(seq, &block, crc)?;
+
+            sent += chunk.len();
+            seq = seq.wrapping_add(1);
+            self.report(file_index, &file.name, sent, data.len());
+        }
+
+        self.send_eot()
+    }
+
+    fn send(&mut self, files: &[TransferFile]) -> Step<()> {
+        if self.protocol != TransferProtocol::Ymodem {
+            let crc = self.await_start()?;
+            return self.send_file(0, &files[0], crc);
+        }
+
+        for (file_index, file) in files.iter().enumerate() {
+            let crc = self.await_start()?;
+            self.send_block(0, &ymodem_header(file), crc)?;
+            //The receiver asks again before the data
+            let crc = self.await_start()?;
+            self.send_file(file_index, file, crc)?;
+        }
+
+        //An empty block 0 ends the batch
+        let crc = self.await_start()?;
+        self.send_block(0, &[0; 128], crc)
+    }
+
+    /// Receives the next block, sending request each time the sender is quiet for too long.
+    fn receive_block(&mut self, request: u8) -> Step<Received> {
+        let mut attempts = 0;
+
+        loop {
+            let block_len = match self.read_byte(self.timeout)? {
+                Some(SOH) => 128,
+                Some(STX) => 1024,
+                Some(EOT) => return Ok(Received::Eot),
+                Some(CAN) => {
+                    self.check_cancel()?;
+                    continue;
+                }
+                Some(_) => continue,
+                None => {
+                    self.retry(&mut attempts)?;
+                    self.write(&[request])?;
+                    continue;
+                }
+            };
+
+            //Sequence number, its complement, the data and its CRC
+            let mut body = vec![0; block_len + 4];
+            let complete = self.read_exact(&mut body)?;
+            let data = &body[2..2 + block_len];
+            let crc = u16::from_be_bytes([body[block_len + 2], body[block_len + 3]]);
+
+            if complete
+                && body[0] == !body[1]
+                && ChecksumKind::Crc16Xmodem.compute(data) as u16 == crc
+            {
+                return Ok(Received::Block(body[0], data.to_vec()));
+            }
+
+            self.retry(&mut attempts)?;
+            self.purge()?;
+            self.write(&[NAK])?;
+        }
+    }
+
+    /// Receives the data blocks of a file up to its EOT. The CRC request starting the file must
+    /// already have been sent.
+    fn receive_file(
+        &mut self,
+        file_index: usize,
+        name: &str,
+        size: Option<usize>,
+    ) -> Step<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut expected = 1u8;
+        let mut request = CRC_REQUEST;
+        let mut refused_eot = false;
+
+        loop {
+            match self.receive_block(request)? {
+                Received::Block(seq, block) if seq == expected => {
+                    data.extend_from_slice(&block);
+                    if let Some(size) = size {
+                        data.truncate(size);
+                    }
+                    expected = expected.wrapping_add(1);
+                    request = NAK;
+                    self.write(&[ACK])?;
+                    self.report(file_index, name, data.len(), size.unwrap_or(0));
+                }
+                //Sent again because our ACK was lost
+                Received::Block(seq, _) if seq == expected.wrapping_sub(1) => self.write(&[ACK])?,
+                Received::Block(..) => return Err(Abort::Protocol("Block out of sequence.")),
+                Received::Eot if self.protocol == TransferProtocol::Ymodem && !refused_eot => {
+                    refused_eot = true;
+                    self.write(&[NAK])?;
+                }
+                Received::Eot => {
+                    self.write(&[ACK])?;
+                    return Ok(data);
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, files: &mut Vec<TransferFile>) -> Step<()> {
+        if self.protocol != TransferProtocol::Ymodem {
+            self.write(&[CRC_REQUEST])?;
+            let data = self.receive_file(0, "", None)?;
+            files.push(TransferFile {
+                name: String::new(),
+                data,
+            });
+            return Ok(());
+        }
+
+        loop {
+            self.write(&[CRC_REQUEST])?;
+            let (name, size) = match self.receive_block(CRC_REQUEST)? {
+                Received::Block(0, header) => parse_ymodem_header(&header),
+                _ => return Err(Abort::Protocol("Expected a YMODEM header block.")),
+            };
+            self.write(&[ACK])?;
+
+            //An empty name ends the batch
+            if name.is_empty() {
+                return Ok(());
+            }
+
+            self.write(&[CRC_REQUEST])?;
+            let data = self.receive_file(files.len(), &name, size)?;
+            files.push(TransferFile { name, data });
+        }
+    }
+}
+
+/// Builds the YMODEM block 0 of a file: its name, a NUL, and its size in decimal.
+fn ymodem_header(file: &TransferFile) -> Vec<u8> {
+    let mut header = file.name.as_bytes().to_vec();
+    header.push(0);
+    header.extend_from_slice(file.data.len().to_string().as_bytes());
+    header.resize(if header.len() < 128 { 128 } else { 1024 }, 0);
+    header
+}
+
+/// Gets the name and size of a file from its YMODEM block 0. Senders may leave the size out.
+fn parse_ymodem_header(header: &[u8]) -> (String, Option<usize>) {
+    let mut fields = header.split(|&byte| byte == 0);
+    let name = String::from_utf8_lossy(fields.next().unwrap_or_default()).into_owned();
+    //The size may be followed by a modification time and mode, separated by spaces
+    let size = fields
+        .next()
+        .and_then(|field| std::str::from_utf8(field).ok())
+        .and_then(|field| field.split(' ').next())
+        .and_then(|size| size.parse().ok());
+
+    (name, size)
+}
+
+/// A file transfer over a port, running once on its own thread.
+pub struct FileTransfer {
+    shared: Arc<Shared>,
+    reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+    settings: Arc<Mutex<Box<dyn SerialPort>>>,
+    write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+    protocol: TransferProtocol,
+    timeout: Duration,
+    max_retries: u32,
+    /// Token used to cancel the transfer thread.
+    cts: CancellationTokenSource,
+}
+
+impl FileTransfer {
+    /// Creates an idle transfer, or throws if config is invalid.
+    pub fn new(
+        reader: Arc<Mutex<BufReader<SerialPortReader>>>,
+        settings: Arc<Mutex<Box<dyn SerialPort>>>,
+        write_handle: Arc<Mutex<Box<dyn SerialPort>>>,
+        config: &TransferConfig,
+    ) -> serialport::Result<FileTransfer> {
+        if ![
+            TransferProtocol::Xmodem,
+            TransferProtocol::Xmodem1k,
+            TransferProtocol::Ymodem,
+        ]
+        .contains(&config.protocol)
+        {
+            return Err(invalid("Unknown transfer protocol."));
+        }
+        if !config.timeout.is_finite() || config.timeout <= 0.0 {
+            return Err(invalid("Timeout must be positive and finite."));
+        }
+
+        Ok(FileTransfer {
+            shared: Arc::new(Shared {
+                phase: Mutex::new(Phase::Idle),
+                finished: Condvar::new(),
+                files: Mutex::new(Vec::new()),
+                progress_callback: Mutex::new(None),
+            }),
+            reader,
+            settings,
+            write_handle,
+            protocol: config.protocol,
+            timeout: timeout_from_secs(config.timeout),
+            max_retries: config.max_retries,
+            cts: CancellationTokenSource::new(),
+        })
+    }
+
+    /// Queues a file to send. Returns false if the transfer has already started.
+    pub fn add_file(&self, name: &str, data: &[u8]) -> bool {
+        if !matches!(*self.shared.phase.lock(), Phase::Idle) {
+            return false;
+        }
+
+        self.shared.files.lock().push(TransferFile {
+            name: name.to_string(),
+            data: data.to_vec(),
+        });
+        true
+    }
+
+    /// Starts sending the queued files, or throws if they cannot be sent.
+    pub fn start_send(&self) -> serialport::Result<()> {
+        {
+            let files = self.shared.files.lock();
+            if files.is_empty() {
+                return Err(invalid("No files to send."));
+            }
+            if self.protocol != TransferProtocol::Ymodem && files.len() > 1 {
+                return Err(invalid("XMODEM can only send one file."));
+            }
+            if self.protocol == TransferProtocol::Ymodem
+                && files.iter().any(|file| {
+                    file.name.is_empty()
+                        || file.name.contains('\0')
+                        || ymodem_header(file).len() > 1024
+                })
+            {
+                return Err(invalid(
+                    "YMODEM file names must be between 1 and 1000 bytes, without NULs.",
+                ));
+            }
+        }
+
+        self.start(true)
+    }
+
+    /// Starts receiving files.
+    pub fn start_receive(&self) -> serialport::Result<()> {
+        self.start(false)
+    }
+
+    fn start(&self, send: bool) -> serialport::Result<()> {
+        {
+            let mut phase = self.shared.phase.lock();
+            if !matches!(*phase, Phase::Idle) {
+                return Err(invalid("Transfer has already been started."));
+            }
+            *phase = Phase::Running;
+        }
+
+        let shared = self.shared.clone();
+        let reader = self.reader.clone();
+        let settings = self.settings.clone();
+        let write_handle = self.write_handle.clone();
+        let token = self.cts.token().clone();
+        let (protocol, timeout, max_retries) = (self.protocol, self.timeout, self.max_retries);
+
+        //Lock the mutex to prevent a race before this thread spawns
+        let _out_lock = self.reader.lock();
+
+        std::thread::spawn(move || {
+            log::debug!("Spawned file transfer thread");
+            let mut session = Session {
+                //Lock the reader for the whole transfer
+                reader: reader.lock(),
+                settings: &settings,
+                write_handle: &write_handle,
+                token: &token,
+                shared: &shared,
+                protocol,
+                timeout,
+                max_retries,
+                retries: 0,
+            };
+
+            let mut files = Vec::new();
+            let outcome = if send {
+                let to_send = shared.files.lock().clone();
+                session.send(&to_send)
+            } else {
+                session.receive(&mut files)
+            };
+            let result = finish(&session, outcome, files);
+            drop(session);
+
+            *shared.phase.lock() = Phase::Finished(result);
+            shared.finished.notify_all();
+            log::debug!("exiting file transfer thread")
+        });
+        //Thread detaches here
+
+        Ok(())
+    }
+
+    /// Cancels the transfer. The peer is told with CAN bytes.
+    pub fn cancel(&self) {
+        self.cts.cancel();
+    }
+
+    /// Waits up to timeout seconds for the transfer to finish.
+    pub fn wait(&self, timeout: f32) -> TransferResult {
+        let deadline = Instant::now().checked_add(timeout_from_secs(timeout));
+        let mut phase = self.shared.phase.lock();
+
+        while matches!(*phase, Phase::Running) {
+            let timed_out = match deadline {
+                Some(deadline) => self
+                    .shared
+                    .finished
+                    .wait_until(&mut phase, deadline)
+                    .timed_out(),
+                None => {
+                    self.shared.finished.wait(&mut phase);
+                    false
+                }
+            };
+            if timed_out {
+                break;
+            }
+        }
+
+        let status = match &*phase {
+            Phase::Finished(result) => return result.clone(),
+            Phase::Idle => TransferStatus::Idle,
+            Phase::Running => TransferStatus::Running,
+        };
+        TransferResult {
+            status,
+            files: Vec::new(),
+            message: String::new(),
+        }
+    }
+
+    /// Sets the callback that receives progress, replacing any previous one.
+    pub fn set_progress_callback(&self, callback: Option<(CVoidSend, ProgressCallback)>) {
+        *self.shared.progress_callback.lock() = callback;
+    }
+
+    /// Gets a pointer to self. Shim to avoid messing with rust::box. Use this to pass this transfer
+    /// to [crate::bindgenffi::set_transfer_progress_callback].
+    ///
+    /// Obviously dont free this pointer or things will blow up.
+    pub fn self_ptr(&mut self) -> *mut FileTransfer {
+        self as *mut FileTransfer
+    }
+}
+
+impl Drop for FileTransfer {
+    fn drop(&mut self) {
+        self.cancel() //Shared state is kept alive by the thread, so this doesn't create a dangling pointer.
+    }
+}
+
+fn invalid(desc: &str) -> Error {
+    Error::new(serialport::ErrorKind::InvalidInput, desc)
+}
+
+/// Turns the outcome of a session into its result, telling the peer if we gave up.
+fn finish(session: &Session, outcome: Step<()>, files: Vec<TransferFile>) -> TransferResult {
+    let (status, message) = match outcome {
+        Ok(()) => (TransferStatus::Done, String::new()),
+        Err(Abort::Cancelled) => (TransferStatus::Cancelled, "Cancelled.".to_string()),
+        Err(Abort::RemoteCancelled) => (
+            TransferStatus::RemoteCancelled,
+            "Cancelled by the peer.".to_string(),
+        ),
+        Err(Abort::TooManyRetries) => (
+            TransferStatus::TooManyRetries,
+            format!("Gave up after {} retries.", session.max_retries),
+        ),
+        Err(Abort::Protocol(desc)) => (TransferStatus::ProtocolError, desc.to_string()),
+        Err(Abort::Port(desc)) => (TransferStatus::PortErr, desc),
+    };
+
+    if matches!(
+        status,
+        TransferStatus::Cancelled | TransferStatus::TooManyRetries | TransferStatus::ProtocolError
+    ) && session.write(&[CAN; 3]).is_err()
+    {
+        log::warn!("File transfer failed to cancel the peer");
+    }
+
+    TransferResult {
+        status,
+        files,
+        message,
+    }
+}